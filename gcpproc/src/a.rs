@@ -1,22 +1,31 @@
-#[derive(Debug, Clone)]
-enum HeaderNameType {
+use crate::common::{CharNumber, LineNumber, Location};
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderNameType {
     HChar, // '<...>'
     QChar, // '"..."'
 }
 
-#[derive(Debug, Clone)]
-enum CharConstPrefix {
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum CharConstPrefix {
     None, // ''...''
     L,    // 'L'...''
     u,    // 'u'...''
     U,    // 'U'...''
+    u8,   // 'u8'...'', C++17 only.
 }
 
-#[derive(Debug, Clone)]
-enum StrLitPrefix {
-    None,    // '"..."'
-    Unicode, // 'u8"..."'
-    Wide,    // 'u"..."', 'U"..."', 'L"..."'
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum StrLitPrefix {
+    None, // '"..."'
+    u8,   // 'u8"..."'
+    u,    // 'u"..."'
+    U,    // 'U"..."'
+    L,    // 'L"..."'
 }
 
 // Section 6.4.7, C18 spec
@@ -27,8 +36,8 @@ enum StrLitPrefix {
 //
 // If QChar, cannot contain '"'. If it contains ''', '\', '//', or '/*',
 // undefined behaviour.
-#[derive(Debug, Clone)]
-struct HeaderName(HeaderNameType, String);
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderName(pub HeaderNameType, pub String);
 
 // Section 6.4.8, C18 spec
 //
@@ -40,20 +49,20 @@ struct HeaderName(HeaderNameType, String);
 // According to C++ spec, can also have `,` anywhere not at the start.
 //
 // Look, if starts with a dot or digit, it's a number to me!
-#[derive(Debug, Clone)]
-struct PPNumber(String);
+#[derive(Debug, Clone, PartialEq)]
+pub struct PPNumber(pub String);
 
 // Must start with non-digit. Can have any digit, non digit or '_' afterwards.
-#[derive(Debug, Clone)]
-struct Identifer(String);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identifer(pub String);
 
 // Any sequence, except ''', '\' and newline.
 //
 // May contain '\'', '\"', '\?', '\\', '\a', '\b', '\f', '\n', '\r',
 // '\t', '\v', '\[0-7]{1-3}', '\x[a-fA-F0-9]*', universal-character-name
 // ('\u[a-fA-F0-9]`, '\U[a-fA-F0-9]{2}').
-#[derive(Debug, Clone)]
-struct CharConst(CharConstPrefix, String);
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharConst(pub CharConstPrefix, pub String);
 
 // 'prefx? " char? "'
 //
@@ -64,13 +73,13 @@ struct CharConst(CharConstPrefix, String);
 // May contain '\'', '\"', '\?', '\\', '\a', '\b', '\f', '\n', '\r',
 // '\t', '\v', '\[0-7]{1-3}', '\x[a-fA-F0-9]*', universal-character-name
 // ('\u[a-fA-F0-9]`, '\U[a-fA-F0-9]{2}').
-#[derive(Debug, Clone)]
-struct StrLit(StrLitPrefix, String);
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrLit(pub StrLitPrefix, pub String);
 
 // Section 6.4.6, C18 spec,
 // with some exclusions lifted to PPToken.
-#[derive(Debug, Clone)]
-enum Punct {
+#[derive(Debug, Clone, PartialEq)]
+pub enum Punct {
     LSqrtBrk,  // '[', '<:'
     RSqrtBrk,  // ']', ':>'
     LCurlyBrk, // '{', '<%'
@@ -125,10 +134,16 @@ enum Punct {
     Hash,              // '#', '%:'
     DoubleHash,        // '##', '%:%:'
     Comma,             // ','
+
+    // C++ only.
+    DoubleColon, // '::'
+    DotStar,     // '.*'
+    PointerStar, // '->*'
+    Spaceship,   // '<=>', C++20 only.
 }
 
-#[derive(Debug, Clone)]
-enum PPToken {
+#[derive(Debug, Clone, PartialEq)]
+pub enum PPToken {
     NonPreProc(String),
     HeaderName(HeaderName),
     PPNumber(PPNumber),
@@ -136,22 +151,208 @@ enum PPToken {
     CharConst(CharConst),
     StrLit(StrLit),
     Punct(Punct),
+    // A punctuator spelt with its digraph, e.g., '<:' instead of '['.
+    Digraph(Punct),
+    // Any run of whitespace, other than newlines.
+    Space,
     NewLine,
 }
 
-#[derive(Debug, Clone)]
-struct Filename(String);
+// Where a token is in `Output.new_file`, as the line and char of its first
+// char. These are numbered like the second half of `Output.loc_mapping`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub nline: LineNumber,
+    pub nchar: CharNumber,
+    // In chars.
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedPPToken(pub PPToken, pub Span);
+
+impl Punct {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Punct::LSqrtBrk => "[",
+            Punct::RSqrtBrk => "]",
+            Punct::LCurlyBrk => "{",
+            Punct::RCurlyBrk => "}",
+            Punct::Dot => ".",
+            Punct::Pointer => "->",
+            Punct::Quest => "?",
+            Punct::Colon => ":",
+            Punct::SColon => ";",
+            Punct::DoubleAmp => "&&",
+            Punct::DoublePipe => "||",
+            Punct::DoubleAdd => "++",
+            Punct::DoubleSub => "--",
+            Punct::Eq => "=",
+            Punct::DoubleEq => "==",
+            Punct::Add => "+",
+            Punct::PlusEq => "+=",
+            Punct::Sub => "-",
+            Punct::MinusEq => "-=",
+            Punct::Mul => "*",
+            Punct::MulEq => "*=",
+            Punct::Div => "/",
+            Punct::DivEq => "/=",
+            Punct::Mod => "%",
+            Punct::ModEq => "%=",
+            Punct::LAngleBrk => "<",
+            Punct::LAngleBrkEq => "<=",
+            Punct::RAngleBrk => ">",
+            Punct::RAngleBrkEq => ">=",
+            Punct::Not => "!",
+            Punct::NotEq => "!=",
+            Punct::Pipe => "|",
+            Punct::PipeEq => "|=",
+            Punct::Comp => "~",
+            Punct::Amp => "&",
+            Punct::AmpEq => "&=",
+            Punct::Pow => "^",
+            Punct::PowEq => "^=",
+            Punct::LDoubleAngleBrk => "<<",
+            Punct::LDoubleAngleBrkEq => "<<=",
+            Punct::RDoubleAngleBrk => ">>",
+            Punct::RDoubleAngleBrkEq => ">>=",
+            Punct::TripleDot => "...",
+            Punct::LParen => "(",
+            Punct::RParen => ")",
+            Punct::Hash => "#",
+            Punct::DoubleHash => "##",
+            Punct::Comma => ",",
+            Punct::DoubleColon => "::",
+            Punct::DotStar => ".*",
+            Punct::PointerStar => "->*",
+            Punct::Spaceship => "<=>",
+        }
+    }
+
+    // Only the six punctuators in section 6.4.6p3 have digraphs.
+    pub fn as_digraph_str(&self) -> Option<&'static str> {
+        match self {
+            Punct::LSqrtBrk => Some("<:"),
+            Punct::RSqrtBrk => Some(":>"),
+            Punct::LCurlyBrk => Some("<%"),
+            Punct::RCurlyBrk => Some("%>"),
+            Punct::Hash => Some("%:"),
+            Punct::DoubleHash => Some("%:%:"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CharConstPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CharConstPrefix::None => "",
+            CharConstPrefix::L => "L",
+            CharConstPrefix::u => "u",
+            CharConstPrefix::U => "U",
+            CharConstPrefix::u8 => "u8",
+        })
+    }
+}
+
+impl fmt::Display for StrLitPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            StrLitPrefix::None => "",
+            StrLitPrefix::u8 => "u8",
+            StrLitPrefix::u => "u",
+            StrLitPrefix::U => "U",
+            StrLitPrefix::L => "L",
+        })
+    }
+}
+
+// Prints the token as it was spelt.
+impl fmt::Display for PPToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PPToken::NonPreProc(s) => f.write_str(s),
+            PPToken::HeaderName(HeaderName(HeaderNameType::HChar, s)) => {
+                write!(f, "<{}>", s)
+            }
+            PPToken::HeaderName(HeaderName(HeaderNameType::QChar, s)) => {
+                write!(f, "\"{}\"", s)
+            }
+            PPToken::PPNumber(PPNumber(s)) => f.write_str(s),
+            PPToken::Identifer(Identifer(s)) => f.write_str(s),
+            PPToken::CharConst(CharConst(p, s)) => write!(f, "{}'{}'", p, s),
+            PPToken::StrLit(StrLit(p, s)) => write!(f, "{}\"{}\"", p, s),
+            PPToken::Punct(p) => f.write_str(p.as_str()),
+            PPToken::Digraph(p) => {
+                f.write_str(p.as_digraph_str().unwrap_or_else(|| p.as_str()))
+            }
+            PPToken::Space => f.write_str(" "),
+            PPToken::NewLine => f.write_str("\n"),
+        }
+    }
+}
+
+impl PPToken {
+    // The punctuator, regardless of whether it was spelt with a digraph.
+    pub fn punct(&self) -> Option<&Punct> {
+        match self {
+            PPToken::Punct(p) | PPToken::Digraph(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    pub fn is_punct(&self, punct: &Punct) -> bool {
+        self.punct() == Some(punct)
+    }
+}
+
+impl Span {
+    // Maps the start of the span back to where it was in the original file.
+    pub fn location(
+        &self,
+        loc_mapping: &[(Location, Location)],
+    ) -> Option<Location> {
+        let this = (self.nline, self.nchar);
+        let i = loc_mapping.partition_point(|(_, o)| {
+            (o.nline.unwrap(), o.nchar.unwrap()) <= this
+        });
+        if i == 0 {
+            return None;
+        }
+
+        let (s, o) = &loc_mapping[i - 1];
+        let (snline, snchar) = (s.nline.unwrap(), s.nchar.unwrap());
+        let (online, onchar) = (o.nline.unwrap(), o.nchar.unwrap());
+        Some(if online == self.nline {
+            Location::new(
+                s.filename.clone(),
+                snline,
+                snchar + self.nchar - onchar,
+            )
+        } else {
+            Location::new(
+                s.filename.clone(),
+                snline + self.nline - online,
+                self.nchar,
+            )
+        })
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filename(pub String);
 
-#[derive(Debug, Clone)]
-enum DiagnosticType {
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticType {
     Warning,
     Error,
     Ignored,
     Fatal,
 }
 
-#[derive(Debug, Clone)]
-enum PragmaTokens {
+#[derive(Debug, Clone, PartialEq)]
+pub enum PragmaTokens {
     None,
     Once,
 
@@ -171,34 +372,81 @@ enum PragmaTokens {
     // '#pragma SystemHeader newline' or '#pragma clang system_header newline'
     SystemHeader,
 
+    // https://gcc.gnu.org/onlinedocs/cpp/Pragmas.html
+    //
+    // '#pragma GCC poison identifiers newline'
+    Poison(Vec<Identifer>),
+    // '#pragma GCC dependency "file" tokens newline'. The tokens, if any,
+    // are warned about too if the file is newer.
+    Dependency(HeaderName, Vec<PPToken>),
+
+    // Given to the handlers in `pragma`, or written to the output.
     Other(Vec<PPToken>),
 }
 
-#[derive(Debug, Clone)]
-enum MessageType {
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageType {
     Warning,
     Error,
     PragmaError,
     PragmaWarning,
 }
 
-#[derive(Debug, Clone)]
-enum IncludeType {
+#[derive(Debug, Clone, PartialEq)]
+pub enum IncludeType {
     Import,
     Include,
     IncludeNext,
 }
 
+impl IncludeType {
+    // The directive's name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            IncludeType::Import => "import",
+            IncludeType::Include => "include",
+            IncludeType::IncludeNext => "include_next",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdentType {
+    Ident,
+    Sccs,
+}
+
+impl IdentType {
+    // The directive's name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            IdentType::Ident => "ident",
+            IdentType::Sccs => "sccs",
+        }
+    }
+}
+
 // Cannot contain assignment, increment, decrement, function-call, or
 // comma operators unless when they are not evaluated.
 //
 // Needs to also handle defined, __has_attribute, and __has_cpp_attribute.
-#[derive(Debug, Clone)]
-struct ConstExpr(Vec<PPToken>);
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstExpr(pub Vec<SpannedPPToken>);
+
+// The last parameter of a function-like macro, which takes all the remaining
+// arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Variadic {
+    No,
+    // '...', referred to as '__VA_ARGS__'. C99 and C++11 onwards.
+    Anonymous,
+    // 'name...', a GNU extension.
+    Named(Identifer),
+}
 
-#[derive(Debug, Clone)]
-enum Token {
-    NonPreProc(String),
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    NonPreProc(Vec<SpannedPPToken>),
     NullDirective, // '# newline'
     NonStdDefined,
     // '#error error_message newline' and '#warning warning_message newline'.
@@ -207,22 +455,22 @@ enum Token {
     // and '#pragma GCC error error_message newline',  which will all be `Token::Msg(...)`, not
     // `Token::Pragma(...)`.
     //
-    // If we encounter an error, or a warning when -Werror is set, we should simply
+    // If we encounter an error, or a warning when -Werror is set, we simply
     // stop and run the preproccesor locally to get the error output, and cache
-    // the failure. If the preproccesor doesn't error as predicted, then we've
-    // encountered a bug in our impl!
+    // the failure, see `fallback`. If the preproccesor doesn't error as
+    // predicted, then we've encountered a bug in our impl!
     //
     // https://gcc.gnu.org/onlinedocs/cpp/Diagnostics.html#Diagnostics
     // https://gcc.gnu.org/onlinedocs/gcc/Diagnostic-Pragmas.html
     Msg(MessageType, Vec<PPToken>),
 
     // https://gcc.gnu.org/onlinedocs/cpp/Pragmas.html
-    Pragma(PragmaTokens), // '#pragma tokens newline', also, '_Pragma ("str") newline'
+    Pragma(PragmaTokens), // '#pragma tokens newline', also, '_Pragma ("str")'
 
     // https://gcc.gnu.org/onlinedocs/cpp/Line-Control.html#Line-Control
     // '#line lineno "filename"? newline' or '#line <tokens>`.
     // StrLitPrefix == None, if present.
-    Line(Option<Vec<PPToken>>, Option<u64>, Option<StrLit>),
+    Line(Option<Vec<SpannedPPToken>>, Option<u64>, Option<StrLit>),
 
     // https://gcc.gnu.org/onlinedocs/cpp/Include-Syntax.html
     // https://gcc.gnu.org/onlinedocs/gcc-3.1/cpp/Obsolete-once-only-headers.html
@@ -231,14 +479,18 @@ enum Token {
     //
     // Both '#import ...', '#include_next ...' and '#include ...'.
     Include(IncludeType, HeaderName),
+    // '#include tokens newline', where the tokens have to be macro expanded
+    // into a header name.
+    IncludeComputed(IncludeType, Vec<SpannedPPToken>),
 
     // https://gcc.gnu.org/onlinedocs/cpp/_005f_005fhas_005finclude.html#g_t_005f_005fhas_005finclude
     // https://clang.llvm.org/docs/LanguageExtensions.html#include-file-checking-macros
     HasInclude(IncludeType, HeaderName),
 
     // https://gcc.gnu.org/onlinedocs/cpp/Macros.html#Macros
+    // https://gcc.gnu.org/onlinedocs/cpp/Variadic-Macros.html
     Define(Identifer, Vec<PPToken>),
-    DefineFn(Identifer, Vec<Identifer>, Vec<PPToken>),
+    DefineFn(Identifer, Vec<Identifer>, Variadic, Vec<PPToken>),
     Undef(Identifer),
 
     // https://gcc.gnu.org/onlinedocs/cpp/Conditional-Syntax.html#Conditional-Syntax
@@ -250,5 +502,20 @@ enum Token {
     ElseIf(ConstExpr),
     EndIf,
 
+    // https://gcc.gnu.org/onlinedocs/cpp/Other-Directives.html
+    //
+    // '#ident "string" newline' or '#sccs "string" newline', both written to
+    // the output as '#ident'.
+    Ident(IdentType, StrLit),
+
+    // https://gcc.gnu.org/onlinedocs/cpp/Obsolete-Features.html
+    //
+    // '#assert predicate ( answer ) newline', and '#unassert predicate
+    // newline' or '#unassert predicate ( answer ) newline'. The answers are
+    // kept without whitespace.
+    Assert(Identifer, Vec<PPToken>),
+    Unassert(Identifer, Option<Vec<PPToken>>),
+
+    // '#name tokens newline', for any name we don't know.
     Other(String),
 }
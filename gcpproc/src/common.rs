@@ -1,6 +1,7 @@
-#![macro_escape]
 //! Common files for preprocessor.
 
+use crate::a::MessageType;
+
 /// All the parameters the preprocessor cares about.
 pub struct Params {
     // -std=<version>
@@ -10,11 +11,13 @@ pub struct Params {
     // -Wno-comment/-Wnocomments
     // wcomment: bool,
 
+    // -pedantic
+    pub pedantic: bool,
+
     // -Wtrigraphs
     // -Wno-trigraphs
     pub wtrigraphs: bool,
 
-    // TODO:
     // -I <dir>
     // -iquote <dir>
     // -isystem <dir>
@@ -22,15 +25,22 @@ pub struct Params {
     // -iprefix <prefix>
     // -iwithprefix <dir>
     // -iwithprefixbefore <dir>
+    // In the order given, as -iprefix only affects the options after it.
+    pub include_dirs: Vec<IncludeDir>,
     // -isysroot <dir>
+    pub isysroot: Option<String>,
     // -imultilib <dir>
+    pub imultilib: Option<String>,
+    // The compiler's standard system directories, searched after -isystem,
+    // without the sysroot. The flag is set for the directories -imultilib
+    // applies to, which are gcc's target specific C++ ones.
+    pub std_dirs: Vec<(String, bool)>,
 
     // -trigraphs
     pub trigraphs: bool,
-    // TODO: -Wundef
+    // -Wundef
     // -Wno-undef
-    // wundef: bool,
-
+    pub wundef: bool,
     // TODO: -Wexpansion-to-defined
     // -Wno-expansion-to-defined
     // wexpansion_to_defined: bool,
@@ -39,15 +49,31 @@ pub struct Params {
     // -Wno-unused-macros
     // wunused-macros: bool,
 
-    // TODO: -Wendif-labels
+    // -Wendif-labels
     // -Wno-endif-labels
-    // wno_endif_labels: bool,
+    pub wendif_labels: bool,
+
+    // -Wdeprecated
+    // -Wno-deprecated
+    pub wdeprecated: bool,
+
+    // -Wsystem-headers
+    pub wsystem_headers: bool,
+
+    // -Wunknown-pragmas
+    // -Wno-unknown-pragmas
+    pub wunknown_pragmas: bool,
 
-    // TODO: -D <name>/-D <name>=<definition>/-U <name>
-    // macro_defines: Vec<(String, Option<String>)>,
+    // -Werror
+    pub werror: bool,
+    // -Werror=<option>, the option without the '-W', e.g., "undef".
+    pub werror_options: Vec<String>,
 
-    // TODO: -undef
-    // undef: bool,
+    // -D <name>/-D <name>=<definition>/-U <name>
+    pub macro_defines: Vec<MacroDefine>,
+
+    // -undef
+    pub undef: bool,
 
     // TODO: -pthread
     // pthread: bool,
@@ -55,8 +81,8 @@ pub struct Params {
     // TODO: -fpreprocessed
     // fpreprocessed: bool,
 
-    // TODO: -fdirectives-only
-    // fdirectives_only: bool,
+    // -fdirectives-only
+    pub fdirectives_only: bool,
 
     // TODO: -fworking-directory
     // fworking_directory: bool,
@@ -66,15 +92,21 @@ pub struct Params {
     // TODO: -C/-CC
     // no_discard_comments: CommentDiscardMode,
 
-    // TODO: -P
-    // disable_linemarkers: bool,
+    // -P
+    pub disable_linemarkers: bool,
+
+    // -fhosted
+    // -ffreestanding
+    pub hosted: bool,
 
-    // TODO: -include <file>
-    // include: Vec<String>,
+    // -include <file>
+    pub include: Vec<String>,
 
-    // TODO: -imacros <file>
-    // imacros: Vec<String>,
+    // -imacros <file>
+    pub imacros: Vec<String>,
 
+    // -fms-extensions, for '__pragma'
+    pub ms_extensions: bool,
     // Do we want to support -M and others? I think not.
 }
 
@@ -83,6 +115,26 @@ impl Default for Params {
         Params {
             trigraphs: false,
             wtrigraphs: true,
+            pedantic: false,
+            wundef: false,
+            wendif_labels: true,
+            wdeprecated: true,
+            wsystem_headers: false,
+            wunknown_pragmas: false,
+            werror: false,
+            werror_options: vec![],
+            macro_defines: vec![],
+            undef: false,
+            include: vec![],
+            imacros: vec![],
+            ms_extensions: false,
+            fdirectives_only: false,
+            disable_linemarkers: false,
+            hosted: true,
+            include_dirs: vec![],
+            isysroot: None,
+            imultilib: None,
+            std_dirs: vec![],
             // NOTE: gcc's default for C is C11, and for C++ it's C++14.
             // Remember to decide between the two based on fileext.
             version: Version::C(CVersion::C11),
@@ -118,6 +170,26 @@ impl Location {
     }
 }
 
+// The options that add to the header search path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IncludeDir {
+    I(String),
+    IQuote(String),
+    ISystem(String),
+    IDirAfter(String),
+    IPrefix(String),
+    IWithPrefix(String),
+    IWithPrefixBefore(String),
+}
+
+// A '-D' or '-U'. They're carried out in the order they were given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroDefine {
+    // 'name', 'name=definition' or 'name(params)=definition'.
+    D(String),
+    U(String),
+}
+
 /// Describes a diagnostic message that we've encountered.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Issue {
@@ -156,6 +228,145 @@ pub enum IssueDesc {
     TrigraphPresent(char),
     TrigraphAndVersionConflict,
     FileEndMissingNewline,
+    // Directive name, e.g., "define".
+    MalformedDirective(String),
+    UnknownDirective(String),
+    ExtraTokensAfterDirective(String),
+    // The '#line' number, as written.
+    InvalidLineNumber(String),
+    // Bigger than the version allows, or zero, with -pedantic.
+    LineNumberOutOfRange(u64),
+    // Pragma name, e.g., "push_macro".
+    MalformedPragma(String),
+    // The operator, "_Pragma" or "__pragma", without its operand.
+    MalformedPragmaOperator(String),
+    // Macro name, and the location of the previous definition. The issue's
+    // own location is the new definition. Locations are boxed in here, to
+    // keep `Issue` small.
+    MacroRedefined(String, Option<Box<Location>>),
+    // Macro name, e.g., "__LINE__", for '#define' and '#undef'.
+    BuiltinMacroRedefined(String),
+    UndefiningBuiltinMacro(String),
+    // "defined", which can't be a macro.
+    InvalidMacroName(String),
+    // The parameter that's repeated.
+    DuplicateMacroParam(String),
+    // Macro name.
+    PasteAtEdgeOfMacro(String),
+    StringifyWithoutParam(String),
+    // The two tokens, as spelt.
+    InvalidPaste(String, String),
+    // Macro name, expected and given number of arguments.
+    WrongMacroArgCount(String, usize, usize),
+    UnterminatedArgumentList(String),
+    // The directive's name, between a macro's arguments, only with
+    // -pedantic.
+    DirectiveInMacroArgs(String),
+    // Identifier, e.g., "__VA_ARGS__", used where it isn't allowed.
+    MisplacedIdentifier(String),
+    // A standard feature, e.g., "__VA_OPT__", used in a version without it.
+    UnsupportedInVersion(String),
+    // Description of a GNU extension that was used, only with -pedantic.
+    GnuExtension(String),
+    // '#if' and '#elif' expressions.
+    DefinedWithoutIdentifier,
+    // What was expected, e.g., ")" or "expression".
+    MissingTokenInIf(String),
+    // The token, as spelt.
+    InvalidTokenInIf(String),
+    DivisionByZeroInIf,
+    IntegerOverflowInIf,
+    CommaInIf,
+    // -Wundef, the identifier.
+    UndefinedIdentifierInIf(String),
+    // The constant, as spelt.
+    InvalidConstantInIf(String),
+    IntegerTooLargeInIf(String),
+    LargeIntegerIsUnsigned(String),
+    MultiCharConstant(String),
+    // Conditional inclusion, the directive name, e.g., "else".
+    ConditionalWithoutIf(String),
+    // The location of the '#else'.
+    ConditionalAfterElse(String, Option<Box<Location>>),
+    // The name of the directive that started the conditional, e.g., "ifdef".
+    UnterminatedConditional(String),
+    // '#include', the header name, as written.
+    HeaderNotFound(String),
+    // The directive name, e.g., "include".
+    IncludeExpectsFilename(String),
+    EmptyFilename(String),
+    // The maximum depth.
+    IncludeNestedTooDeeply(usize),
+    // The path, and the error reading it.
+    CannotReadFile(String, String),
+    // A search path directory that isn't one.
+    NotADirectory(String),
+    IncludeNextInPrimaryFile,
+    // Description of a deprecated GNU extension, e.g., "#import".
+    DeprecatedGnuExtension(String),
+    PragmaOnceInMainFile,
+    SystemHeaderPragmaInMainFile,
+    // '#pragma GCC poison', the identifier.
+    PoisonedIdentifier(String),
+    PoisoningExistingMacro(String),
+    // '#pragma GCC dependency', the file that's newer, and the tokens after
+    // it, as spelt.
+    CurrentFileOlderThan(String, String),
+    // '#assert', '#unassert', and '#predicate ( answer )' in '#if'.
+    PredicateMustBeIdentifier,
+    MissingLParenAfterPredicate,
+    MissingRParenInAnswer,
+    EmptyAnswer,
+    // A pragma without a handler, its namespace and name.
+    UnknownPragma(String),
+    // Given by a pragma's handler.
+    FromPragmaHandler(String),
+    // The value of SOURCE_DATE_EPOCH.
+    InvalidSourceDateEpoch(String),
+    // '#error', '#warning', '#pragma GCC error' or '#pragma GCC warning',
+    // and the message.
+    Message(MessageType, String),
+    // We predicted preprocessing would fail, but the real preprocessor
+    // succeeded, so there's a bug in us. The file it was run on.
+    InternalBug(String),
+    // The program, and why it couldn't be run.
+    CannotRunPreprocessor(String, String),
+}
+
+impl IssueDesc {
+    // The option that controls the warning, for '#pragma GCC diagnostic' and
+    // -Werror=, as gcc names it.
+    pub fn option(&self) -> Option<&'static str> {
+        Some(match self {
+            IssueDesc::TrigraphPresent(_)
+            | IssueDesc::TrigraphPresentAndIgnored(_) => "-Wtrigraphs",
+            IssueDesc::UndefinedIdentifierInIf(_) => "-Wundef",
+            IssueDesc::ExtraTokensAfterDirective(d)
+                if d == "else" || d == "endif" =>
+            {
+                "-Wendif-labels"
+            }
+            IssueDesc::DeprecatedGnuExtension(_) => "-Wdeprecated",
+            IssueDesc::BuiltinMacroRedefined(_)
+            | IssueDesc::UndefiningBuiltinMacro(_) => {
+                "-Wbuiltin-macro-redefined"
+            }
+            IssueDesc::Message(MessageType::Warning, _)
+            | IssueDesc::Message(MessageType::PragmaWarning, _) => "-Wcpp",
+            IssueDesc::MultiCharConstant(_) => "-Wmultichar",
+            IssueDesc::GnuExtension(_)
+            | IssueDesc::CommaInIf
+            | IssueDesc::DirectiveInMacroArgs(_)
+            | IssueDesc::LineNumberOutOfRange(_) => "-Wpedantic",
+            IssueDesc::UnknownPragma(_) => "-Wunknown-pragmas",
+            _ => return None,
+        })
+    }
+
+    // Like gcc, '#warning's are still warned about in system headers.
+    pub fn in_system_headers(&self) -> bool {
+        matches!(self, IssueDesc::Message(MessageType::Warning, _))
+    }
 }
 
 /// Describes to current version of the C/++ standard in use.
@@ -175,7 +386,8 @@ pub enum CppVersion {
     Cpp11, // http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2012/n3337.pdf
     Cpp14, // http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2014/n4296.pdf
     Cpp17, // http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2017/n4687.pdf
-    Cpp20, // http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2019/n4835.pdf and up
+    Cpp20, // http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2019/n4835.pdf
+    Cpp23, // https://www.open-std.org/jtc1/sc22/wg21/docs/papers/2023/n4950.pdf and up
     Max,
 }
 
@@ -187,6 +399,7 @@ pub enum CVersion {
     C99, // http://www.open-std.org/jtc1/sc22/wg14/www/docs/n869/n869.pdf.gz
     C11, // http://www.open-std.org/jtc1/sc22/wg14/www/docs/n1570.pdf
     C18, // https://web.archive.org/web/20181230041359if_/http://www.open-std.org/jtc1/sc22/wg14/www/abq/c17_updated_proposed_fdis.pdf
+    C2x, // http://www.open-std.org/jtc1/sc22/wg14/www/docs/n2478.pdf and up
     Max,
 }
 
@@ -217,6 +430,7 @@ impl Version {
 }
 
 // Formated assert_eq
+#[allow(unused_macros)]
 macro_rules! fassert_eq {
     ($left:expr, $right:expr) => ({
         match (&$left, &$right) {
@@ -0,0 +1,157 @@
+//! Conditional inclusion, section 6.10.1, C11 spec.
+//!
+//! Keeps track of the nesting of '#if', '#ifdef', '#ifndef', '#elif',
+//! '#else' and '#endif', and of whether the current group is skipped.
+
+#[cfg(test)]
+mod tests;
+
+use crate::common::{Issue, IssueDesc, IssueType, Location, Params};
+
+#[derive(Debug, Clone)]
+struct Cond {
+    // The directive that started it, e.g., "ifdef", and where.
+    directive: String,
+    loc: Option<Location>,
+    // If the enclosing group is skipped, so are all of these groups.
+    was_skipping: bool,
+    // Whether one of the groups has been taken, or we've seen the '#else',
+    // so the groups after are skipped.
+    taken: bool,
+    seen_else: bool,
+    else_loc: Option<Location>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Conditionals {
+    stack: Vec<Cond>,
+    skipping: bool,
+}
+
+impl Conditionals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn skipping(&self) -> bool {
+        self.skipping
+    }
+
+    // How many conditionals are open.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    // Whether the directive called `name` has to be parsed while skipping.
+    //
+    // Only an '#elif' that might be taken needs to be, and, for
+    // -Wendif-labels, '#else' and '#endif' if the conditional isn't in a
+    // skipped group. The rest only matter for the nesting.
+    pub fn needs_parse(&self, name: &str, params: &Params) -> bool {
+        let top = match self.stack.last() {
+            Some(top) => top,
+            None => return false,
+        };
+        match name {
+            "elif" => !top.was_skipping && !top.taken,
+            "else" | "endif" => !top.was_skipping && params.wendif_labels,
+            _ => false,
+        }
+    }
+
+    // '#if', '#ifdef' and '#ifndef'. `cond` is only called if the group
+    // isn't in a skipped one.
+    pub fn open(
+        &mut self,
+        directive: &str,
+        loc: Option<Location>,
+        cond: impl FnOnce(&mut Vec<Issue>) -> bool,
+        issues: &mut Vec<Issue>,
+    ) {
+        let taken = !self.skipping && cond(issues);
+        self.stack.push(Cond {
+            directive: directive.to_string(),
+            loc,
+            was_skipping: self.skipping,
+            taken,
+            seen_else: false,
+            else_loc: None,
+        });
+        self.skipping = !taken;
+    }
+
+    // The conditional a '#elif', '#else' or '#endif' belongs to.
+    fn top(
+        &mut self,
+        directive: &str,
+        loc: &Option<Location>,
+        issues: &mut Vec<Issue>,
+    ) -> Option<&mut Cond> {
+        if self.stack.is_empty() {
+            issues.push(Issue::new(
+                loc.clone(),
+                IssueType::Error,
+                IssueDesc::ConditionalWithoutIf(directive.to_string()),
+            ));
+        }
+        let top = self.stack.last_mut()?;
+        if top.seen_else && directive != "endif" {
+            issues.push(Issue::new(
+                loc.clone(),
+                IssueType::Error,
+                IssueDesc::ConditionalAfterElse(
+                    directive.to_string(),
+                    top.else_loc.clone().map(Box::new),
+                ),
+            ));
+        }
+        Some(top)
+    }
+
+    // `cond` is only called if none of the groups before have been taken.
+    pub fn elif(
+        &mut self,
+        loc: Option<Location>,
+        cond: impl FnOnce(&mut Vec<Issue>) -> bool,
+        issues: &mut Vec<Issue>,
+    ) {
+        let top = match self.top("elif", &loc, issues) {
+            Some(top) => top,
+            None => return,
+        };
+        let taken = !top.was_skipping && !top.taken && cond(issues);
+        top.taken |= taken;
+        self.skipping = !taken;
+    }
+
+    pub fn else_(&mut self, loc: Option<Location>, issues: &mut Vec<Issue>) {
+        let top = match self.top("else", &loc, issues) {
+            Some(top) => top,
+            None => return,
+        };
+        let taken = !top.was_skipping && !top.taken;
+        top.taken = true;
+        top.seen_else = true;
+        top.else_loc = loc;
+        self.skipping = !taken;
+    }
+
+    pub fn endif(&mut self, loc: Option<Location>, issues: &mut Vec<Issue>) {
+        if let Some(top) = self.top("endif", &loc, issues) {
+            self.skipping = top.was_skipping;
+            self.stack.pop();
+        }
+    }
+
+    // At the end of the file, every conditional should have been closed.
+    pub fn finish(&mut self, issues: &mut Vec<Issue>) {
+        for cond in self.stack.drain(..) {
+            issues.push(Issue::new(
+                cond.loc,
+                IssueType::Error,
+                IssueDesc::UnterminatedConditional(cond.directive),
+            ));
+        }
+        self.skipping = false;
+    }
+}
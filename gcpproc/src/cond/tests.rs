@@ -0,0 +1,216 @@
+use super::*;
+use crate::driver::preprocess;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+// Without linemarkers, so only the text is compared.
+fn params() -> Params {
+    Params {
+        disable_linemarkers: true,
+        ..Params::default()
+    }
+}
+
+fn error(nline: usize, desc: IssueDesc) -> Issue {
+    Issue::new(Some(location!(nline, 1)), IssueType::Error, desc)
+}
+
+#[test]
+fn groups() {
+    let (text, issues) = preprocess(
+        "#define A\n\
+         #ifdef A\n\
+         a\n\
+         #endif\n\
+         #ifndef A\n\
+         not_a\n\
+         #else\n\
+         else_a\n\
+         #endif\n\
+         #if 0\n\
+         zero\n\
+         #elif defined A && !defined B\n\
+         elif\n\
+         #elif 1\n\
+         elif_1\n\
+         #else\n\
+         else\n\
+         #endif\n\
+         #if 1\n\
+         #if 0\n\
+         #else\n\
+         nested\n\
+         #endif\n\
+         #endif\n",
+        &FILENAME,
+        &params(),
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "a\nelse_a\nelif\nnested\n");
+}
+
+// Only the nesting of skipped groups matters, so the lines in them are never
+// parsed.
+#[test]
+fn skipped_groups() {
+    let (text, issues) = preprocess(
+        "#if 0\n\
+         #define 1\n\
+         #unknown\n\
+         #if (\n\
+         #elif 1 / 0\n\
+         #else garbage\n\
+         #endif garbage\n\
+         %:ifdef\n\
+         %:endif\n\
+         ## if\n\
+         #elif 1\n\
+         one\n\
+         #elif 1 / 0\n\
+         #else\n\
+         two\n\
+         #endif\n",
+        &FILENAME,
+        &params(),
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "one\n");
+}
+
+#[test]
+fn unbalanced() {
+    let (text, issues) = preprocess(
+        "#else\n\
+         #elif 1\n\
+         #endif\n\
+         #if 1\n\
+         a\n\
+         #else\n\
+         b\n\
+         #elif 1\n\
+         c\n\
+         #else\n\
+         d\n\
+         #endif\n\
+         #ifdef A\n\
+         #if 1\n",
+        &FILENAME,
+        &params(),
+    );
+    let else_loc = Some(location!(6, 1));
+    fassert_eq!(
+        issues,
+        vec![
+            error(1, IssueDesc::ConditionalWithoutIf("else".to_string())),
+            error(2, IssueDesc::ConditionalWithoutIf("elif".to_string())),
+            error(3, IssueDesc::ConditionalWithoutIf("endif".to_string())),
+            error(
+                8,
+                IssueDesc::ConditionalAfterElse(
+                    "elif".to_string(),
+                    else_loc.clone().map(Box::new)
+                ),
+            ),
+            error(
+                10,
+                IssueDesc::ConditionalAfterElse(
+                    "else".to_string(),
+                    else_loc.map(Box::new)
+                ),
+            ),
+            error(13, IssueDesc::UnterminatedConditional("ifdef".to_string())),
+            error(14, IssueDesc::UnterminatedConditional("if".to_string())),
+        ]
+    );
+    fassert_eq!(text, "a\n");
+}
+
+// Malformed conditionals still nest, with their groups skipped.
+#[test]
+fn malformed() {
+    let (text, issues) = preprocess(
+        "#ifdef\n\
+         a\n\
+         #else\n\
+         b\n\
+         #endif\n\
+         #if 1 / 0\n\
+         c\n\
+         #elif\n\
+         d\n\
+         #else\n\
+         e\n\
+         #endif\n",
+        &FILENAME,
+        &params(),
+    );
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(1, 2)),
+                IssueType::Error,
+                IssueDesc::MalformedDirective("ifdef".to_string()),
+            ),
+            Issue::new(
+                Some(location!(6, 7)),
+                IssueType::Error,
+                IssueDesc::DivisionByZeroInIf,
+            ),
+            Issue::new(
+                Some(location!(8, 2)),
+                IssueType::Error,
+                IssueDesc::MalformedDirective("elif".to_string()),
+            ),
+        ]
+    );
+    fassert_eq!(text, "b\ne\n");
+}
+
+#[test]
+fn endif_labels() {
+    let src = "#if 0\n\
+               #else A\n\
+               #if 0\n\
+               #else B\n\
+               #endif C\n\
+               #endif D\n\
+               #if 0\n\
+               #if 0\n\
+               #else E\n\
+               #endif F\n\
+               #endif\n";
+    let warning = |nline, nchar, name: &str| {
+        Issue::new(
+            Some(location!(nline, nchar)),
+            IssueType::Warning,
+            IssueDesc::ExtraTokensAfterDirective(name.to_string()),
+        )
+    };
+
+    let (_, issues) = preprocess(src, &FILENAME, &params());
+    fassert_eq!(
+        issues,
+        vec![
+            warning(2, 7, "else"),
+            warning(4, 7, "else"),
+            warning(5, 8, "endif"),
+            warning(6, 8, "endif"),
+        ]
+    );
+
+    let params = Params {
+        wendif_labels: false,
+        ..params()
+    };
+    let (_, issues) = preprocess(src, &FILENAME, &params);
+    fassert_eq!(issues, vec![]);
+}
@@ -0,0 +1,86 @@
+//! '#pragma GCC diagnostic', and -Werror.
+//!
+//! https://gcc.gnu.org/onlinedocs/gcc/Diagnostic-Pragmas.html
+//!
+//! Keeps the kind each option's warnings have been given by the pragmas, and
+//! the states saved by 'push'. Warnings are given their final `IssueType`
+//! from the state where they were found. The pragmas can only change what's
+//! already warned about: a warning that's off on the command line, e.g.,
+//! -Wundef, isn't turned on by them.
+//!
+//! https://gcc.gnu.org/onlinedocs/cpp/System-Headers.html
+//!
+//! Warnings in system headers are dropped, unless -Wsystem-headers is
+//! given, or they're one of the few gcc gives anyway.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::DiagnosticType;
+use crate::common::{Issue, IssueType, Params};
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    // The kinds given by the pragmas, by option, e.g., "-Wundef".
+    kinds: HashMap<String, DiagnosticType>,
+    // Saved by 'push', the last pushed last.
+    stack: Vec<HashMap<String, DiagnosticType>>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // '#pragma GCC diagnostic <kind> "<option>"'.
+    pub fn set(&mut self, option: &str, kind: DiagnosticType) {
+        self.kinds.insert(option.to_string(), kind);
+    }
+
+    pub fn push(&mut self) {
+        self.stack.push(self.kinds.clone());
+    }
+
+    // Like gcc, a 'pop' without a 'push' goes back to the command line's
+    // state.
+    pub fn pop(&mut self) {
+        self.kinds = self.stack.pop().unwrap_or_default();
+    }
+
+    // `issue`, as an error if it's a warning made one, or `None` if it's
+    // ignored, or in a system header. A pragma's kind wins over -Werror and
+    // -Werror=.
+    pub fn classify(
+        &self,
+        mut issue: Issue,
+        system: bool,
+        params: &Params,
+    ) -> Option<Issue> {
+        if issue.itype != IssueType::Warning {
+            return Some(issue);
+        }
+        if system && !params.wsystem_headers && !issue.desc.in_system_headers()
+        {
+            return None;
+        }
+        let option = issue.desc.option();
+        match option.and_then(|o| self.kinds.get(o)) {
+            Some(DiagnosticType::Ignored) => return None,
+            Some(DiagnosticType::Error) | Some(DiagnosticType::Fatal) => {
+                issue.itype = IssueType::Error
+            }
+            Some(DiagnosticType::Warning) => (),
+            None => {
+                let werror = option.is_some_and(|o| {
+                    params.werror_options.iter().any(|w| o[2..] == *w)
+                });
+                if params.werror || werror {
+                    issue.itype = IssueType::Error;
+                }
+            }
+        }
+        Some(issue)
+    }
+}
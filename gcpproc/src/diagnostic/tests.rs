@@ -0,0 +1,138 @@
+use super::*;
+use crate::common::{IssueDesc, Location};
+use crate::driver::preprocess;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+fn params() -> Params {
+    Params {
+        disable_linemarkers: true,
+        wundef: true,
+        ..Params::default()
+    }
+}
+
+fn undef(nline: usize, itype: IssueType) -> Issue {
+    Issue::new(
+        Some(location!(nline, 5)),
+        itype,
+        IssueDesc::UndefinedIdentifierInIf("X".to_string()),
+    )
+}
+
+#[test]
+fn classify() {
+    let mut diagnostics = Diagnostics::new();
+    let params = params();
+    let warning = undef(1, IssueType::Warning);
+    let error = undef(1, IssueType::Error);
+    fassert_eq!(
+        diagnostics.classify(warning.clone(), false, &params),
+        Some(warning.clone())
+    );
+
+    diagnostics.set("-Wundef", DiagnosticType::Error);
+    fassert_eq!(
+        diagnostics.classify(warning.clone(), false, &params),
+        Some(error.clone())
+    );
+    diagnostics.push();
+    diagnostics.set("-Wundef", DiagnosticType::Ignored);
+    fassert_eq!(diagnostics.classify(warning.clone(), false, &params), None);
+    // Errors stay errors.
+    fassert_eq!(
+        diagnostics.classify(error.clone(), false, &params),
+        Some(error.clone())
+    );
+    diagnostics.pop();
+    fassert_eq!(
+        diagnostics.classify(warning.clone(), false, &params),
+        Some(error.clone())
+    );
+    diagnostics.pop();
+    fassert_eq!(
+        diagnostics.classify(warning.clone(), false, &params),
+        Some(warning.clone())
+    );
+}
+
+#[test]
+fn werror() {
+    let mut diagnostics = Diagnostics::new();
+    let warning = undef(1, IssueType::Warning);
+    let error = undef(1, IssueType::Error);
+    let pragma_once = Issue::new(
+        Some(location!(1, 1)),
+        IssueType::Warning,
+        IssueDesc::PragmaOnceInMainFile,
+    );
+    let params = Params {
+        werror_options: vec!["undef".to_string()],
+        ..params()
+    };
+    fassert_eq!(
+        diagnostics.classify(warning.clone(), false, &params),
+        Some(error.clone())
+    );
+    fassert_eq!(
+        diagnostics.classify(pragma_once.clone(), false, &params),
+        Some(pragma_once.clone())
+    );
+
+    let params = Params {
+        werror: true,
+        werror_options: vec![],
+        ..params
+    };
+    fassert_eq!(
+        diagnostics.classify(pragma_once.clone(), false, &params),
+        Some(Issue {
+            itype: IssueType::Error,
+            ..pragma_once
+        })
+    );
+    diagnostics.set("-Wundef", DiagnosticType::Warning);
+    fassert_eq!(
+        diagnostics.classify(warning.clone(), false, &params),
+        Some(warning)
+    );
+}
+
+// Each warning is classified by the pragmas before it, wherever they are.
+#[test]
+fn pragmas() {
+    let (_, issues) = preprocess(
+        "#if X\n\
+         #endif\n\
+         #pragma GCC diagnostic push\n\
+         #pragma GCC diagnostic ignored \"-Wundef\"\n\
+         #if X\n\
+         #endif\n\
+         _Pragma(\"GCC diagnostic error \\\"-Wundef\\\"\")\n\
+         #if X\n\
+         #endif\n\
+         #pragma GCC diagnostic pop\n\
+         #if X\n\
+         #endif\n\
+         #pragma GCC diagnostic ignored \"-Wtrigraphs\"\n\
+         ??=\n",
+        &FILENAME,
+        &params(),
+    );
+    fassert_eq!(
+        issues,
+        vec![
+            undef(1, IssueType::Warning),
+            undef(8, IssueType::Error),
+            undef(11, IssueType::Warning),
+        ]
+    );
+}
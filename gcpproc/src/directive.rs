@@ -0,0 +1,659 @@
+//! Splits the tokens from the `Lexer` into logical lines, and turns each line
+//! into a `Token`.
+//!
+//! Directives are parsed by the grammar in `preproc.lalrpop`, with the help of
+//! the functions in here.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{
+    DiagnosticType, HeaderName, HeaderNameType, IdentType, Identifer,
+    MessageType, PPToken, PragmaTokens, Punct, Span, SpannedPPToken, StrLit,
+    StrLitPrefix, Token,
+};
+use crate::common::{Issue, IssueDesc, IssueType, Location, Params};
+use crate::lexer::Lexer;
+use crate::p1to3::Output;
+use crate::preproc::DirectiveParser;
+
+use lalrpop_util::ParseError;
+
+// The `loc_mapping` from `Output`.
+pub type LocMapping = [(Location, Location)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    // Directive names. Only the first identifier after the '#' can be one of
+    // these.
+    Define,
+    Undef,
+    Include,
+    IncludeNext,
+    Import,
+    If,
+    Ifdef,
+    Ifndef,
+    Elif,
+    Else,
+    Endif,
+    Line,
+    Pragma,
+    Error,
+    Warning,
+    // '#ident', as `Ident` is any identifier.
+    IdentKw,
+    Sccs,
+    Assert,
+    Unassert,
+
+    Ident,
+    Number,
+    HeaderName,
+    LParen,
+    // The '(' right after the name in a '#define', if there is no whitespace
+    // between them.
+    FnLParen,
+    RParen,
+    Comma,
+    Ellipsis,
+    Other,
+}
+
+// The tokens the grammar sees. Whitespace is dropped, but we remember if
+// there was any before each token.
+#[derive(Debug, Clone)]
+pub struct Tok(pub Kind, pub SpannedPPToken, pub bool);
+
+impl Tok {
+    pub fn name(&self) -> String {
+        (self.1).0.to_string()
+    }
+
+    pub fn ident(self) -> Identifer {
+        match self.1 {
+            SpannedPPToken(PPToken::Identifer(i), _) => i,
+            t => panic!("Expected identifier, got {:?}", t),
+        }
+    }
+
+    pub fn header_name(self) -> HeaderName {
+        match self.1 {
+            SpannedPPToken(PPToken::HeaderName(h), _) => h,
+            t => panic!("Expected header name, got {:?}", t),
+        }
+    }
+
+    fn location(&self, loc_mapping: &LocMapping) -> Option<Location> {
+        (self.1).1.location(loc_mapping)
+    }
+}
+
+fn directive_kind(name: &str) -> Option<Kind> {
+    Some(match name {
+        "define" => Kind::Define,
+        "undef" => Kind::Undef,
+        "include" => Kind::Include,
+        "include_next" => Kind::IncludeNext,
+        "import" => Kind::Import,
+        "if" => Kind::If,
+        "ifdef" => Kind::Ifdef,
+        "ifndef" => Kind::Ifndef,
+        "elif" => Kind::Elif,
+        "else" => Kind::Else,
+        "endif" => Kind::Endif,
+        "line" => Kind::Line,
+        "pragma" => Kind::Pragma,
+        "error" => Kind::Error,
+        "warning" => Kind::Warning,
+        "ident" => Kind::IdentKw,
+        "sccs" => Kind::Sccs,
+        "assert" => Kind::Assert,
+        "unassert" => Kind::Unassert,
+        _ => return None,
+    })
+}
+
+// Converts the tokens after the '#' into what the grammar wants.
+fn toks(line: &[SpannedPPToken]) -> Vec<Tok> {
+    let mut toks: Vec<Tok> = Vec::with_capacity(line.len());
+    let mut space = false;
+    for t in line {
+        let kind = match &t.0 {
+            PPToken::Space => {
+                space = true;
+                continue;
+            }
+            PPToken::Identifer(Identifer(i)) if toks.is_empty() => {
+                directive_kind(i).unwrap_or(Kind::Ident)
+            }
+            PPToken::Identifer(_) => Kind::Ident,
+            PPToken::PPNumber(_) => Kind::Number,
+            PPToken::HeaderName(_) => Kind::HeaderName,
+            PPToken::Punct(Punct::LParen) => match toks.as_slice() {
+                [Tok(Kind::Define, _, _), Tok(Kind::Ident, _, _)] if !space => {
+                    Kind::FnLParen
+                }
+                _ => Kind::LParen,
+            },
+            PPToken::Punct(Punct::RParen) => Kind::RParen,
+            PPToken::Punct(Punct::Comma) => Kind::Comma,
+            PPToken::Punct(Punct::TripleDot) => Kind::Ellipsis,
+            _ => Kind::Other,
+        };
+        toks.push(Tok(kind, t.clone(), space));
+        space = false;
+    }
+    toks
+}
+
+// A space token just before `span`.
+fn space_before(span: Span) -> SpannedPPToken {
+    SpannedPPToken(
+        PPToken::Space,
+        Span {
+            nline: span.nline,
+            nchar: span.nchar - 1,
+            len: 1,
+        },
+    )
+}
+
+pub fn spanned_tokens(toks: Vec<Tok>) -> Vec<SpannedPPToken> {
+    let mut ret = Vec::with_capacity(toks.len() * 2);
+    for Tok(_, t, space) in toks {
+        if space && !ret.is_empty() {
+            ret.push(space_before(t.1));
+        }
+        ret.push(t);
+    }
+    ret
+}
+
+pub fn pp_tokens(toks: Vec<Tok>) -> Vec<PPToken> {
+    spanned_tokens(toks)
+        .into_iter()
+        .map(|SpannedPPToken(t, _)| t)
+        .collect()
+}
+
+pub fn extra_tokens(
+    issues: &mut Vec<Issue>,
+    loc_mapping: &LocMapping,
+    directive: &Tok,
+    extra: &[Tok],
+) {
+    if let Some(t) = extra.first() {
+        issues.push(Issue::new(
+            t.location(loc_mapping),
+            IssueType::Warning,
+            IssueDesc::ExtraTokensAfterDirective(directive.name()),
+        ));
+    }
+}
+
+// A '#line' number, which is only ever written in decimal.
+pub fn line_number(n: &str) -> Option<u64> {
+    if n.bytes().all(|c| c.is_ascii_digit()) {
+        n.parse().ok()
+    } else {
+        None
+    }
+}
+
+// '#line digits "filename"?', or, if the first token isn't a number,
+// '#line tokens' to be macro expanded later.
+//
+// Also used for GNU linemarkers, '# digits "filename" flags', in which case
+// `directive` is the line number.
+pub fn line(
+    issues: &mut Vec<Issue>,
+    loc_mapping: &LocMapping,
+    directive: &Tok,
+    toks: Vec<Tok>,
+) -> Result<Token, ParseError<usize, Tok, Issue>> {
+    let linemarker = directive.0 == Kind::Number;
+    let nline = match &toks[0] {
+        Tok(_, SpannedPPToken(PPToken::PPNumber(n), _), _) => &n.0,
+        _ => return Ok(Token::Line(Some(spanned_tokens(toks)), None, None)),
+    };
+
+    let nline = line_number(nline).ok_or_else(|| ParseError::User {
+        error: Issue::new(
+            toks[0].location(loc_mapping),
+            IssueType::Error,
+            IssueDesc::InvalidLineNumber(toks[0].name()),
+        ),
+    })?;
+
+    let filename = match toks.get(1) {
+        None => None,
+        Some(Tok(_, SpannedPPToken(PPToken::StrLit(s), _), _))
+            if s.0 == StrLitPrefix::None =>
+        {
+            Some(s.clone())
+        }
+        Some(t) => {
+            return Err(ParseError::User {
+                error: Issue::new(
+                    t.location(loc_mapping),
+                    IssueType::Error,
+                    IssueDesc::MalformedDirective(directive.name()),
+                ),
+            })
+        }
+    };
+
+    // Linemarkers can have flags after the filename.
+    let extra = toks.get(2..).unwrap_or(&[]);
+    if !linemarker
+        || extra.iter().any(|t| match &(t.1).0 {
+            PPToken::PPNumber(n) => !["1", "2", "3", "4"].contains(&&*n.0),
+            _ => true,
+        })
+    {
+        extra_tokens(issues, loc_mapping, directive, extra);
+    }
+
+    Ok(Token::Line(None, Some(nline), filename))
+}
+
+// '#ident "string"' or '#sccs "string"'.
+pub fn ident(
+    issues: &mut Vec<Issue>,
+    loc_mapping: &LocMapping,
+    directive: &Tok,
+    itype: IdentType,
+    toks: Vec<Tok>,
+) -> Result<Token, ParseError<usize, Tok, Issue>> {
+    match toks.first() {
+        Some(Tok(_, SpannedPPToken(PPToken::StrLit(s), _), _))
+            if s.0 == StrLitPrefix::None =>
+        {
+            extra_tokens(issues, loc_mapping, directive, &toks[1..]);
+            Ok(Token::Ident(itype, s.clone()))
+        }
+        t => Err(ParseError::User {
+            error: Issue::new(
+                t.unwrap_or(directive).location(loc_mapping),
+                IssueType::Error,
+                IssueDesc::MalformedDirective(directive.name()),
+            ),
+        }),
+    }
+}
+
+// '#assert predicate ( answer )', or '#unassert predicate', where the
+// answer is optional. Like gcc, the answer ends at the first ')'.
+pub fn assertion(
+    issues: &mut Vec<Issue>,
+    loc_mapping: &LocMapping,
+    directive: &Tok,
+    toks: Vec<Tok>,
+) -> Result<Token, ParseError<usize, Tok, Issue>> {
+    let error = |t: Option<&Tok>, desc| ParseError::User {
+        error: Issue::new(
+            t.unwrap_or(directive).location(loc_mapping),
+            IssueType::Error,
+            desc,
+        ),
+    };
+    let pred = match toks.first() {
+        Some(t @ Tok(Kind::Ident, _, _)) => t.clone().ident(),
+        t => return Err(error(t, IssueDesc::PredicateMustBeIdentifier)),
+    };
+    let assert = directive.0 == Kind::Assert;
+    let answer = match toks.get(1) {
+        Some(Tok(Kind::LParen, _, _)) => {
+            let end = toks[2..]
+                .iter()
+                .position(|t| t.0 == Kind::RParen)
+                .ok_or_else(|| {
+                    error(toks.last(), IssueDesc::MissingRParenInAnswer)
+                })?;
+            if end == 0 {
+                return Err(error(toks.get(2), IssueDesc::EmptyAnswer));
+            }
+            let answer = toks[2..2 + end].iter().map(|t| (t.1).0.clone());
+            extra_tokens(issues, loc_mapping, directive, &toks[3 + end..]);
+            Some(answer.collect())
+        }
+        None if !assert => None,
+        t => return Err(error(t, IssueDesc::MissingLParenAfterPredicate)),
+    };
+    Ok(match answer {
+        Some(answer) if assert => Token::Assert(pred, answer),
+        answer => Token::Unassert(pred, answer),
+    })
+}
+
+// The header name from the macro expanded tokens of '#include tokens', and
+// the tokens after it.
+//
+// A string literal is used as is, without escape sequences processed. The
+// tokens between '<' and '>' are spelt one after the other, with whitespace
+// as a single space.
+pub fn computed_header_name(
+    toks: &[SpannedPPToken],
+) -> Option<(HeaderName, &[SpannedPPToken])> {
+    let start = toks.iter().position(|t| t.0 != PPToken::Space)?;
+    let rest = match &toks[start].0 {
+        PPToken::StrLit(StrLit(StrLitPrefix::None, s)) => {
+            let name = HeaderName(HeaderNameType::QChar, s.clone());
+            return Some((name, &toks[start + 1..]));
+        }
+        t if t.is_punct(&Punct::LAngleBrk) => &toks[start + 1..],
+        _ => return None,
+    };
+    let end = rest.iter().position(|t| t.0.is_punct(&Punct::RAngleBrk))?;
+    let name = rest[..end].iter().map(|t| t.0.to_string()).collect();
+    Some((HeaderName(HeaderNameType::HChar, name), &rest[end + 1..]))
+}
+
+fn pragma_str(toks: &[Tok]) -> Option<StrLit> {
+    match toks {
+        [Tok(_, SpannedPPToken(PPToken::StrLit(s), _), _)] => Some(s.clone()),
+        _ => None,
+    }
+}
+
+// 'name ( "string" )', as used by push_macro and pop_macro.
+fn pragma_macro_name(toks: &[Tok]) -> Option<Identifer> {
+    match toks {
+        [Tok(_, SpannedPPToken(PPToken::Punct(Punct::LParen), _), _), Tok(_, SpannedPPToken(PPToken::StrLit(s), _), _), Tok(_, SpannedPPToken(PPToken::Punct(Punct::RParen), _), _)]
+            if s.0 == StrLitPrefix::None =>
+        {
+            Some(Identifer(s.1.clone()))
+        }
+        _ => None,
+    }
+}
+
+// https://gcc.gnu.org/onlinedocs/cpp/Pragmas.html
+//
+// Pragmas we don't know about are left as `PragmaTokens::Other`, and
+// malformed ones we do are dropped.
+pub fn pragma(
+    issues: &mut Vec<Issue>,
+    loc_mapping: &LocMapping,
+    toks: Vec<Tok>,
+) -> Token {
+    let names: Vec<_> = toks
+        .iter()
+        .take(3)
+        .map(|t| match &(t.1).0 {
+            PPToken::Identifer(Identifer(i)) => i.as_str(),
+            _ => "",
+        })
+        .collect();
+
+    let malformed = |issues: &mut Vec<Issue>, name: &str| {
+        issues.push(Issue::new(
+            toks[0].location(loc_mapping),
+            IssueType::Warning,
+            IssueDesc::MalformedPragma(name.to_string()),
+        ));
+    };
+
+    let pragma = match names.as_slice() {
+        [] => PragmaTokens::None,
+        ["once", ..] => {
+            extra_tokens(issues, loc_mapping, &toks[0], &toks[1..]);
+            PragmaTokens::Once
+        }
+        [name @ "push_macro", ..] | [name @ "pop_macro", ..] => {
+            match pragma_macro_name(&toks[1..]) {
+                Some(i) if *name == "push_macro" => PragmaTokens::PushMacro(i),
+                Some(i) => PragmaTokens::PopMacro(i),
+                None => {
+                    malformed(issues, name);
+                    PragmaTokens::None
+                }
+            }
+        }
+        ["GCC", "poison", ..] => {
+            let idents: Option<Vec<_>> = toks[2..]
+                .iter()
+                .map(|t| match &(t.1).0 {
+                    PPToken::Identifer(i) => Some(i.clone()),
+                    _ => None,
+                })
+                .collect();
+            match idents {
+                Some(idents) => PragmaTokens::Poison(idents),
+                None => {
+                    malformed(issues, "poison");
+                    PragmaTokens::None
+                }
+            }
+        }
+        ["GCC", "dependency", ..] => {
+            let rest = spanned_tokens(toks[2..].to_vec());
+            match computed_header_name(&rest) {
+                Some((header, extra)) => PragmaTokens::Dependency(
+                    header,
+                    extra.iter().map(|t| t.0.clone()).collect(),
+                ),
+                None => {
+                    malformed(issues, "dependency");
+                    PragmaTokens::None
+                }
+            }
+        }
+        ["GCC", "system_header", ..] | ["clang", "system_header", ..] => {
+            extra_tokens(issues, loc_mapping, &toks[1], &toks[2..]);
+            PragmaTokens::SystemHeader
+        }
+        ["GCC", "warning", ..] | ["GCC", "error", ..] => {
+            let mtype = if names[1] == "warning" {
+                MessageType::PragmaWarning
+            } else {
+                MessageType::PragmaError
+            };
+            return Token::Msg(mtype, pp_tokens(toks[2..].to_vec()));
+        }
+        ["GCC", "diagnostic", kind] | ["clang", "diagnostic", kind] => {
+            let dtype = match *kind {
+                "push" => Some(PragmaTokens::DiagnosticPush),
+                "pop" => Some(PragmaTokens::DiagnosticPop),
+                _ => None,
+            };
+            let diag = match *kind {
+                "warning" => Some(DiagnosticType::Warning),
+                "error" => Some(DiagnosticType::Error),
+                "ignored" => Some(DiagnosticType::Ignored),
+                "fatal" => Some(DiagnosticType::Fatal),
+                _ => None,
+            };
+            match (dtype, diag, pragma_str(&toks[3..])) {
+                (Some(p), _, _) => {
+                    extra_tokens(issues, loc_mapping, &toks[2], &toks[3..]);
+                    p
+                }
+                (None, Some(d), Some(s)) => PragmaTokens::Diagnostic(d, s),
+                _ => {
+                    malformed(issues, "diagnostic");
+                    PragmaTokens::None
+                }
+            }
+        }
+        _ => PragmaTokens::Other(pp_tokens(toks)),
+    };
+    Token::Pragma(pragma)
+}
+
+// Turns one logical line, without the newline, into a `Token`. Directives we
+// can't make sense of become an `Issue`.
+pub fn parse_line(
+    line: &[SpannedPPToken],
+    loc_mapping: &LocMapping,
+    params: &Params,
+    issues: &mut Vec<Issue>,
+) -> Result<Token, Issue> {
+    let start = line.iter().position(|t| t.0 != PPToken::Space);
+    let hash = match start {
+        Some(i) if line[i].0.is_punct(&Punct::Hash) => i,
+        _ => return Ok(Token::NonPreProc(line.to_vec())),
+    };
+
+    let toks = toks(&line[hash + 1..]);
+    let name = toks.first().cloned();
+    let loc = |i: usize| match toks.get(i).or_else(|| toks.last()) {
+        Some(t) => t.location(loc_mapping),
+        None => line[hash].1.location(loc_mapping),
+    };
+    let desc = |name: &Option<Tok>| match name {
+        Some(t) if directive_kind(&t.name()).is_some() => {
+            IssueDesc::MalformedDirective(t.name())
+        }
+        Some(t) => IssueDesc::UnknownDirective(t.name()),
+        None => IssueDesc::MalformedDirective(String::new()),
+    };
+
+    DirectiveParser::new()
+        .parse(
+            issues,
+            loc_mapping,
+            params,
+            toks.iter()
+                .cloned()
+                .enumerate()
+                .map(|(i, t)| Ok((i, t, i + 1))),
+        )
+        .map_err(|e| match e {
+            ParseError::User { error } => error,
+            ParseError::UnrecognizedToken {
+                token: (i, _, _), ..
+            }
+            | ParseError::ExtraToken { token: (i, _, _) }
+            | ParseError::InvalidToken { location: i } => {
+                Issue::new(loc(i), IssueType::Error, desc(&name))
+            }
+            ParseError::UnrecognizedEOF { location, .. } => Issue::new(
+                loc(location.saturating_sub(1)),
+                IssueType::Error,
+                desc(&name),
+            ),
+        })
+}
+
+// Logical lines of tokens, without the newlines.
+pub struct Lines<'a> {
+    lexer: Lexer<'a>,
+}
+
+impl<'a> Lines<'a> {
+    pub fn new(lexer: Lexer<'a>) -> Self {
+        Lines { lexer }
+    }
+
+    // See `Lexer::peek_directive`. Blank lines are skipped first.
+    pub fn peek_directive(&mut self) -> Option<(&'a str, Span)> {
+        while self.lexer.at_blank_line() {
+            self.lexer.skip_line();
+        }
+        self.lexer.peek_directive()
+    }
+
+    // Skips the next line without tokenizing it. Returns false if there are
+    // no more lines.
+    pub fn skip_line(&mut self) -> bool {
+        self.lexer.skip_line()
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Vec<SpannedPPToken>;
+
+    fn next(&mut self) -> Option<Vec<SpannedPPToken>> {
+        let mut line = vec![];
+        loop {
+            match self.lexer.next() {
+                None if line.is_empty() => return None,
+                None | Some(SpannedPPToken(PPToken::NewLine, _)) => {
+                    return Some(line)
+                }
+                Some(t) => line.push(t),
+            }
+        }
+    }
+}
+
+// Parses the output of `preproc_phases_1_to_3` a line at a time, so that
+// the lines of skipped groups don't have to be tokenized.
+//
+// The `loc_mapping` is passed in with each line, as '#line' changes it.
+pub struct Parser<'a> {
+    lines: Lines<'a>,
+    params: &'a Params,
+    // The tokens of the line `next` last parsed.
+    line: Vec<SpannedPPToken>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(new_file: &'a str, params: &'a Params) -> Self {
+        Parser {
+            lines: Lines::new(Lexer::new(new_file, params)),
+            params,
+            line: vec![],
+        }
+    }
+
+    pub fn line(&self) -> &[SpannedPPToken] {
+        &self.line
+    }
+
+    pub fn peek_directive(&mut self) -> Option<(&'a str, Span)> {
+        self.lines.peek_directive()
+    }
+
+    pub fn skip_line(&mut self) -> bool {
+        self.lines.skip_line()
+    }
+
+    // Parses the next line that isn't blank, giving the `Span` of its start.
+    pub fn next(
+        &mut self,
+        loc_mapping: &LocMapping,
+        issues: &mut Vec<Issue>,
+    ) -> Option<(Result<Token, Issue>, Span)> {
+        loop {
+            let line = self.lines.next()?;
+            let span = match line.iter().find(|t| t.0 != PPToken::Space) {
+                Some(t) => t.1,
+                None => continue,
+            };
+            let token = parse_line(&line, loc_mapping, self.params, issues);
+            if let Ok(Token::Other(name)) = &token {
+                issues.push(Issue::new(
+                    span.location(loc_mapping),
+                    IssueType::Error,
+                    IssueDesc::UnknownDirective(name.clone()),
+                ));
+            }
+            self.line = line;
+            return Some((token, span));
+        }
+    }
+}
+
+// Parses every line in the output of `preproc_phases_1_to_3`, giving each
+// `Token` the `Span` of the start of its line.
+#[allow(dead_code)]
+pub fn parse(
+    output: &Output,
+    params: &Params,
+) -> (Vec<(Token, Span)>, Vec<Issue>) {
+    let mut issues = vec![];
+    let mut tokens = vec![];
+    let mut parser = Parser::new(&output.new_file, params);
+    while let Some((token, span)) =
+        parser.next(&output.loc_mapping, &mut issues)
+    {
+        match token {
+            Ok(t) => tokens.push((t, span)),
+            Err(issue) => issues.push(issue),
+        }
+    }
+    (tokens, issues)
+}
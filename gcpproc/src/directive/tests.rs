@@ -0,0 +1,540 @@
+use super::*;
+use crate::a::{
+    CharConst, CharConstPrefix, ConstExpr, HeaderNameType, IncludeType,
+    PPNumber, Variadic,
+};
+use crate::common::{Location, Params};
+use crate::p1to3::preproc_phases_1_to_3;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+fn parse_src(src: &str) -> (Vec<Token>, Vec<Issue>) {
+    let params = Params::default();
+    let output = preproc_phases_1_to_3(src, &FILENAME, &params);
+    let (tokens, issues) = parse(&output, &params);
+    (tokens.into_iter().map(|(t, _)| t).collect(), issues)
+}
+
+// Drops the spans, which are tested in `lexer`.
+fn strip(tokens: &[SpannedPPToken]) -> Vec<PPToken> {
+    tokens
+        .iter()
+        .map(|SpannedPPToken(t, _)| t.clone())
+        .collect()
+}
+
+fn ident(s: &str) -> Identifer {
+    Identifer(s.to_string())
+}
+
+fn tident(s: &str) -> PPToken {
+    PPToken::Identifer(ident(s))
+}
+
+fn tnum(s: &str) -> PPToken {
+    PPToken::PPNumber(PPNumber(s.to_string()))
+}
+
+fn strlit(s: &str) -> StrLit {
+    StrLit(StrLitPrefix::None, s.to_string())
+}
+
+#[test]
+fn defines() {
+    let (tokens, issues) = parse_src(
+        "#define a\n\
+         #  define b 1 +  2\n\
+         #define c(x, y) x ## y\n\
+         #define d (x)\n\
+         #define e() #e\n\
+         #undef a\n",
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        tokens,
+        vec![
+            Token::Define(ident("a"), vec![]),
+            Token::Define(
+                ident("b"),
+                vec![
+                    tnum("1"),
+                    PPToken::Space,
+                    PPToken::Punct(Punct::Add),
+                    PPToken::Space,
+                    tnum("2"),
+                ]
+            ),
+            Token::DefineFn(
+                ident("c"),
+                vec![ident("x"), ident("y")],
+                Variadic::No,
+                vec![
+                    tident("x"),
+                    PPToken::Space,
+                    PPToken::Punct(Punct::DoubleHash),
+                    PPToken::Space,
+                    tident("y"),
+                ]
+            ),
+            Token::Define(
+                ident("d"),
+                vec![
+                    PPToken::Punct(Punct::LParen),
+                    tident("x"),
+                    PPToken::Punct(Punct::RParen),
+                ]
+            ),
+            Token::DefineFn(
+                ident("e"),
+                vec![],
+                Variadic::No,
+                vec![PPToken::Punct(Punct::Hash), tident("e")]
+            ),
+            Token::Undef(ident("a")),
+        ]
+    );
+}
+
+#[test]
+fn variadic_defines() {
+    let (tokens, issues) = parse_src(
+        "#define a(...) __VA_ARGS__\n\
+         #define b(x, ...) x\n\
+         #define c(x, args...) args\n\
+         #define d(args ...)\n",
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        tokens,
+        vec![
+            Token::DefineFn(
+                ident("a"),
+                vec![],
+                Variadic::Anonymous,
+                vec![tident("__VA_ARGS__")]
+            ),
+            Token::DefineFn(
+                ident("b"),
+                vec![ident("x")],
+                Variadic::Anonymous,
+                vec![tident("x")]
+            ),
+            Token::DefineFn(
+                ident("c"),
+                vec![ident("x")],
+                Variadic::Named(ident("args")),
+                vec![tident("args")]
+            ),
+            Token::DefineFn(
+                ident("d"),
+                vec![],
+                Variadic::Named(ident("args")),
+                vec![]
+            ),
+        ]
+    );
+
+    let (_, issues) = parse_src("#define e(..., x)\n#define f(x...,)\n");
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(1, 14)),
+                IssueType::Error,
+                IssueDesc::MalformedDirective("define".to_string()),
+            ),
+            Issue::new(
+                Some(location!(2, 15)),
+                IssueType::Error,
+                IssueDesc::MalformedDirective("define".to_string()),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn conditionals() {
+    let (tokens, issues) =
+        parse_src("#ifdef a\n#ifndef b\n#if c + 1\n#elif 'd'\n#else\n#endif\n");
+    fassert_eq!(issues, vec![]);
+    let tokens: Vec<_> = tokens
+        .into_iter()
+        .map(|t| match t {
+            Token::If(ConstExpr(e)) => (Token::EndIf, strip(&e)),
+            Token::ElseIf(ConstExpr(e)) => (Token::Else, strip(&e)),
+            t => (t, vec![]),
+        })
+        .collect();
+    fassert_eq!(
+        tokens,
+        vec![
+            (Token::Ifdef(ident("a")), vec![]),
+            (Token::Ifndef(ident("b")), vec![]),
+            (
+                Token::EndIf,
+                vec![
+                    tident("c"),
+                    PPToken::Space,
+                    PPToken::Punct(Punct::Add),
+                    PPToken::Space,
+                    tnum("1"),
+                ]
+            ),
+            (
+                Token::Else,
+                vec![PPToken::CharConst(CharConst(
+                    CharConstPrefix::None,
+                    String::from("d")
+                ))]
+            ),
+            (Token::Else, vec![]),
+            (Token::EndIf, vec![]),
+        ]
+    );
+}
+
+#[test]
+fn extra_tokens() {
+    let (tokens, issues) =
+        parse_src("#ifdef a b\n#else c\n#endif d\n#undef e f\n");
+    fassert_eq!(
+        tokens,
+        vec![
+            Token::Ifdef(ident("a")),
+            Token::Else,
+            Token::EndIf,
+            Token::Undef(ident("e")),
+        ]
+    );
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(1, 10)),
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective(String::from("ifdef"))
+            ),
+            Issue::new(
+                Some(location!(2, 7)),
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective(String::from("else"))
+            ),
+            Issue::new(
+                Some(location!(3, 8)),
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective(String::from("endif"))
+            ),
+            Issue::new(
+                Some(location!(4, 10)),
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective(String::from("undef"))
+            ),
+        ]
+    );
+}
+
+#[test]
+fn includes() {
+    let (tokens, issues) = parse_src(
+        "#include <a.h>\n\
+         #include_next \"b.h\"\n\
+         #import <c.h>\n\
+         #include D\n",
+    );
+    fassert_eq!(issues, vec![]);
+    let tokens: Vec<_> = tokens
+        .into_iter()
+        .map(|t| match t {
+            Token::IncludeComputed(it, toks) => (Some(it), None, strip(&toks)),
+            Token::Include(it, h) => (Some(it), Some(h), vec![]),
+            _ => (None, None, vec![]),
+        })
+        .collect();
+    fassert_eq!(
+        tokens,
+        vec![
+            (
+                Some(IncludeType::Include),
+                Some(HeaderName(HeaderNameType::HChar, String::from("a.h"))),
+                vec![]
+            ),
+            (
+                Some(IncludeType::IncludeNext),
+                Some(HeaderName(HeaderNameType::QChar, String::from("b.h"))),
+                vec![]
+            ),
+            (
+                Some(IncludeType::Import),
+                Some(HeaderName(HeaderNameType::HChar, String::from("c.h"))),
+                vec![]
+            ),
+            (Some(IncludeType::Include), None, vec![tident("D")]),
+        ]
+    );
+}
+
+#[test]
+fn lines() {
+    let (tokens, issues) = parse_src(
+        "#line 10\n\
+         #line 20 \"a.c\"\n\
+         # 30 \"b.c\" 1 3\n\
+         #line A B\n\
+         #line 0x10\n\
+         #line 40 \"c.c\" 2\n",
+    );
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(5, 7)),
+                IssueType::Error,
+                IssueDesc::InvalidLineNumber(String::from("0x10"))
+            ),
+            Issue::new(
+                Some(location!(6, 16)),
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective(String::from("line"))
+            ),
+        ]
+    );
+    let tokens: Vec<_> = tokens
+        .into_iter()
+        .map(|t| match t {
+            Token::Line(toks, n, f) => (toks.map(|t| strip(&t)), n, f),
+            t => panic!("Expected line, got {:?}", t),
+        })
+        .collect();
+    fassert_eq!(
+        tokens,
+        vec![
+            (None, Some(10), None),
+            (None, Some(20), Some(strlit("a.c"))),
+            (None, Some(30), Some(strlit("b.c"))),
+            (
+                Some(vec![tident("A"), PPToken::Space, tident("B")]),
+                None,
+                None
+            ),
+            (None, Some(40), Some(strlit("c.c"))),
+        ]
+    );
+}
+
+#[test]
+fn pragmas() {
+    let (tokens, issues) = parse_src(
+        "#pragma\n\
+         #pragma once\n\
+         #pragma push_macro(\"a\")\n\
+         #pragma pop_macro(\"a\")\n\
+         #pragma GCC diagnostic push\n\
+         #pragma clang diagnostic ignored \"-Wundef\"\n\
+         #pragma GCC diagnostic pop\n\
+         #pragma GCC system_header\n\
+         #pragma GCC warning \"b\"\n\
+         #pragma GCC error \"c\"\n\
+         #pragma omp parallel\n\
+         #pragma push_macro(a)\n",
+    );
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            Some(location!(12, 9)),
+            IssueType::Warning,
+            IssueDesc::MalformedPragma(String::from("push_macro"))
+        )]
+    );
+    fassert_eq!(
+        tokens,
+        vec![
+            Token::Pragma(PragmaTokens::None),
+            Token::Pragma(PragmaTokens::Once),
+            Token::Pragma(PragmaTokens::PushMacro(ident("a"))),
+            Token::Pragma(PragmaTokens::PopMacro(ident("a"))),
+            Token::Pragma(PragmaTokens::DiagnosticPush),
+            Token::Pragma(PragmaTokens::Diagnostic(
+                DiagnosticType::Ignored,
+                strlit("-Wundef")
+            )),
+            Token::Pragma(PragmaTokens::DiagnosticPop),
+            Token::Pragma(PragmaTokens::SystemHeader),
+            Token::Msg(
+                MessageType::PragmaWarning,
+                vec![PPToken::StrLit(strlit("b"))]
+            ),
+            Token::Msg(
+                MessageType::PragmaError,
+                vec![PPToken::StrLit(strlit("c"))]
+            ),
+            Token::Pragma(PragmaTokens::Other(vec![
+                tident("omp"),
+                PPToken::Space,
+                tident("parallel"),
+            ])),
+            Token::Pragma(PragmaTokens::None),
+        ]
+    );
+}
+
+#[test]
+fn idents_and_assertions() {
+    let (tokens, issues) = parse_src(
+        "#ident \"a\"\n\
+         #sccs \"b\" c\n\
+         #ident c\n\
+         #assert m(x86 - 64)\n\
+         #unassert m\n\
+         #unassert m (a) b\n\
+         #assert m\n\
+         #assert 1(a)\n\
+         #assert m()\n\
+         #assert m(a\n",
+    );
+    let issue = |nline, nchar, itype, desc| {
+        Issue::new(Some(location!(nline, nchar)), itype, desc)
+    };
+    fassert_eq!(
+        issues,
+        vec![
+            issue(
+                2,
+                11,
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective("sccs".to_string())
+            ),
+            issue(
+                3,
+                8,
+                IssueType::Error,
+                IssueDesc::MalformedDirective("ident".to_string())
+            ),
+            issue(
+                6,
+                17,
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective("unassert".to_string())
+            ),
+            issue(
+                7,
+                2,
+                IssueType::Error,
+                IssueDesc::MissingLParenAfterPredicate
+            ),
+            issue(8, 9, IssueType::Error, IssueDesc::PredicateMustBeIdentifier),
+            issue(9, 11, IssueType::Error, IssueDesc::EmptyAnswer),
+            issue(10, 11, IssueType::Error, IssueDesc::MissingRParenInAnswer),
+        ]
+    );
+    fassert_eq!(
+        tokens,
+        vec![
+            Token::Ident(IdentType::Ident, strlit("a")),
+            Token::Ident(IdentType::Sccs, strlit("b")),
+            Token::Assert(
+                ident("m"),
+                vec![tident("x86"), PPToken::Punct(Punct::Sub), tnum("64")]
+            ),
+            Token::Unassert(ident("m"), None),
+            Token::Unassert(ident("m"), Some(vec![tident("a")])),
+        ]
+    );
+}
+
+#[test]
+fn messages_and_others() {
+    let (tokens, issues) = parse_src(
+        "#error a b\n\
+         #warning c\n\
+         #\n\
+         #foo bar\n\
+         int d;\n",
+    );
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            Some(location!(4, 1)),
+            IssueType::Error,
+            IssueDesc::UnknownDirective(String::from("foo"))
+        )]
+    );
+    let tokens: Vec<_> = tokens
+        .into_iter()
+        .map(|t| match t {
+            Token::NonPreProc(toks) => (None, strip(&toks)),
+            t => (Some(t), vec![]),
+        })
+        .collect();
+    fassert_eq!(
+        tokens,
+        vec![
+            (
+                Some(Token::Msg(
+                    MessageType::Error,
+                    vec![tident("a"), PPToken::Space, tident("b")]
+                )),
+                vec![]
+            ),
+            (
+                Some(Token::Msg(MessageType::Warning, vec![tident("c")])),
+                vec![]
+            ),
+            (Some(Token::NullDirective), vec![]),
+            (Some(Token::Other(String::from("foo"))), vec![]),
+            (
+                None,
+                vec![
+                    tident("int"),
+                    PPToken::Space,
+                    tident("d"),
+                    PPToken::Punct(Punct::SColon),
+                ]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn malformed() {
+    let (tokens, issues) = parse_src(
+        "#define 3\n\
+         #define f(a,) a\n\
+         #ifdef\n\
+         #if\n\
+         #include\n\
+         # \"x\"\n\
+         #define g(a b) a\n",
+    );
+    fassert_eq!(tokens, vec![]);
+    let malformed = |name: &str, nline, nchar| {
+        Issue::new(
+            Some(location!(nline, nchar)),
+            IssueType::Error,
+            IssueDesc::MalformedDirective(name.to_string()),
+        )
+    };
+    fassert_eq!(
+        issues,
+        vec![
+            malformed("define", 1, 9),
+            malformed("define", 2, 13),
+            malformed("ifdef", 3, 2),
+            malformed("if", 4, 2),
+            malformed("include", 5, 2),
+            Issue::new(
+                Some(location!(6, 3)),
+                IssueType::Error,
+                IssueDesc::UnknownDirective(String::from("\"x\""))
+            ),
+            malformed("define", 7, 13),
+        ]
+    );
+}
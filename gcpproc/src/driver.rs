@@ -0,0 +1,1485 @@
+//! Runs the translation phases over a file, carrying out the directives and
+//! macro expanding the lines of text between them.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{
+    HeaderName, HeaderNameType, Identifer, IncludeType, MessageType, PPToken,
+    PragmaTokens, Punct, Span, SpannedPPToken, StrLit, StrLitPrefix, Token,
+};
+use crate::common::{
+    CVersion, CppVersion, Issue, IssueDesc, IssueType, LineNumber, Location,
+    MacroDefine, Params,
+};
+use crate::cond::Conditionals;
+use crate::diagnostic::Diagnostics;
+use crate::directive::{self, LocMapping};
+use crate::eval;
+use crate::expand::{self, Expander, HToken};
+use crate::lexer::Lexer;
+use crate::macros::{self, MacroTable};
+use crate::p1to3::{self, Output};
+use crate::pragma::{self, PragmaAction, PragmaRegistry};
+use crate::search::{Found, Resolution, SearchPath};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Like gcc's default -fmax-include-depth.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+// Where the '-D's and '-U's are said to be.
+pub const COMMAND_LINE: &str = "<command-line>";
+
+// The largest SOURCE_DATE_EPOCH, the end of the year 9999.
+const MAX_SOURCE_DATE_EPOCH: u64 = 253402300799;
+
+// '__TIMESTAMP__' when the file's modification time isn't known.
+const UNKNOWN_TIMESTAMP: &str = "??? ??? ?? ??:??:?? ????";
+
+// The seconds since the epoch for '__DATE__' and '__TIME__'. For
+// reproducible builds, that's SOURCE_DATE_EPOCH, if it's set.
+fn build_time(epoch: Option<String>, issues: &mut Vec<Issue>) -> u64 {
+    if let Some(epoch) = epoch {
+        match epoch.parse() {
+            Ok(secs) if secs <= MAX_SOURCE_DATE_EPOCH => return secs,
+            _ => issues.push(Issue::new(
+                None,
+                IssueType::Error,
+                IssueDesc::InvalidSourceDateEpoch(epoch),
+            )),
+        }
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn timestamp(path: &Path) -> String {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(UNKNOWN_TIMESTAMP.to_string(), |d| {
+            macros::date_time(d.as_secs()).2
+        })
+}
+
+// Identifies a file however it was named, so hard and symbolic links to it
+// are the same file: by device and inode on unix, and by canonical path
+// elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FileId {
+    #[cfg(unix)]
+    Inode(u64, u64),
+    #[cfg(not(unix))]
+    Path(std::path::PathBuf),
+}
+
+impl FileId {
+    #[cfg(unix)]
+    fn new(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let m = fs::metadata(path).ok()?;
+        Some(FileId::Inode(m.dev(), m.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn new(path: &Path) -> Option<Self> {
+        fs::canonicalize(path).ok().map(FileId::Path)
+    }
+}
+
+// Like gcc's, whether a file is a system header, and if so, whether it's
+// also implicitly 'extern "C"' in C++. None of our search directories are
+// marked as C++ aware, so the headers found in system directories are.
+// '#pragma GCC system_header' makes the rest of a file one that isn't.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum SystemHeader {
+    No,
+    Yes,
+    ExternC,
+}
+
+impl SystemHeader {
+    // The linemarker flags for it.
+    fn flags(self) -> &'static str {
+        match self {
+            SystemHeader::No => "",
+            SystemHeader::Yes => " 3",
+            SystemHeader::ExternC => " 3 4",
+        }
+    }
+}
+
+// A file being preprocessed.
+struct File {
+    name: String,
+    // Where it was found, or `None` for the main file.
+    found: Option<Found>,
+    id: Option<FileId>,
+    system: SystemHeader,
+}
+
+// Text that's still to be written.
+#[derive(Default)]
+struct Text {
+    // The start of a line that a function-like macro call is open on,
+    // already expanded.
+    start: Vec<SpannedPPToken>,
+    // The tokens still to be expanded, from the open call on.
+    toks: Vec<HToken>,
+}
+
+// The multiple-include optimisation: whether everything in the file so far
+// is in a '#ifndef X' ... '#endif' include guard. If it is, the file isn't
+// reopened while X is defined.
+#[derive(Debug, Clone, PartialEq)]
+enum Guard {
+    Start,
+    Open(Identifer),
+    Closed(Identifer),
+    None,
+}
+
+impl Guard {
+    // After a line of text, or the directive called `name`, which was
+    // '#ifndef X' if `ifndef` is X. There were `depth` conditionals open
+    // before it, and `depth_after` after.
+    fn line(
+        &mut self,
+        name: Option<&str>,
+        ifndef: Option<&Identifer>,
+        depth: usize,
+        depth_after: usize,
+    ) {
+        *self = match self {
+            Guard::Start => match ifndef {
+                Some(m) => Guard::Open(m.clone()),
+                None => Guard::None,
+            },
+            // The whole file has to be in the one group.
+            Guard::Open(_)
+                if depth == 1
+                    && matches!(name, Some("elif") | Some("else")) =>
+            {
+                Guard::None
+            }
+            Guard::Open(m) if depth_after == 0 => Guard::Closed(m.clone()),
+            Guard::Closed(_) => Guard::None,
+            _ => return,
+        }
+    }
+}
+
+pub struct Preprocessor<'a> {
+    params: &'a Params,
+    pub macros: MacroTable,
+    pub issues: Vec<Issue>,
+    pub output: String,
+    pub search: SearchPath,
+    // Every '#include' we've looked for, in order.
+    pub resolutions: Vec<Resolution>,
+    // The files being preprocessed, the main file first.
+    files: Vec<File>,
+    // The files we've entered, the ones that are only ever entered once,
+    // e.g., by '#import' or '#pragma once', and the guard macros of those
+    // with include guards.
+    entered: HashSet<FileId>,
+    once_only: HashSet<FileId>,
+    guards: HashMap<FileId, Identifer>,
+    // How many times a file wasn't reopened because of the above.
+    pub skipped_rereads: usize,
+    // The file and line the next line of output is from, for the
+    // linemarkers.
+    printed: Option<(String, LineNumber)>,
+    // The diagnostic pragmas' state, and how many of `issues` have been
+    // classified by it.
+    diagnostics: Diagnostics,
+    classified: usize,
+    // Whether we stopped at an '#error', or a '#warning' made an error.
+    pub failed: bool,
+    // By '#pragma GCC poison'.
+    poisoned: HashSet<Identifer>,
+    // The handlers of the other pragmas.
+    pub pragmas: PragmaRegistry,
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(params: &'a Params) -> Self {
+        let mut issues = vec![];
+        let search = SearchPath::new(params, &mut issues);
+        let mut macros = MacroTable::new();
+        macros.predefine(params);
+        let epoch = std::env::var("SOURCE_DATE_EPOCH").ok();
+        let (date, time, _) = macros::date_time(build_time(epoch, &mut issues));
+        macros.builtins.date = date;
+        macros.builtins.time = time;
+        let mut pp = Preprocessor {
+            params,
+            macros,
+            issues,
+            output: String::new(),
+            search,
+            resolutions: vec![],
+            files: vec![],
+            entered: HashSet::new(),
+            once_only: HashSet::new(),
+            guards: HashMap::new(),
+            skipped_rereads: 0,
+            printed: None,
+            diagnostics: Diagnostics::new(),
+            classified: 0,
+            failed: false,
+            poisoned: HashSet::new(),
+            pragmas: PragmaRegistry::new(),
+        };
+        pp.command_line();
+        pp
+    }
+
+    // Carries out the '-D's and '-U's, as if they were the directives on
+    // each line of a file. Like gcc, '-D name' defines it as 1, and a
+    // definition ends at a newline.
+    fn command_line(&mut self) {
+        let src: String = self
+            .params
+            .macro_defines
+            .iter()
+            .map(|d| match d {
+                MacroDefine::D(d) => match d.find('=') {
+                    Some(i) => {
+                        let def = d[i + 1..].lines().next().unwrap_or("");
+                        format!("#define {} {}\n", &d[..i], def)
+                    }
+                    None => format!("#define {} 1\n", d),
+                },
+                MacroDefine::U(name) => format!("#undef {}\n", name),
+            })
+            .collect();
+        let output =
+            p1to3::preproc_phases_1_to_3(&src, COMMAND_LINE, self.params);
+        self.issues.extend(output.issues.iter().cloned());
+        let loc_mapping = &output.loc_mapping;
+        let mut parser = directive::Parser::new(&output.new_file, self.params);
+        while let Some((token, span)) =
+            parser.next(loc_mapping, &mut self.issues)
+        {
+            match token {
+                Ok(token) => {
+                    let loc = span.location(loc_mapping);
+                    let issues = &mut self.issues;
+                    self.macros.handle_token(&token, loc, self.params, issues);
+                }
+                Err(issue) => self.issues.push(issue),
+            }
+        }
+    }
+
+    pub fn run(&mut self, src: &str, filename: &str) {
+        let id = FileId::new(Path::new(filename));
+        self.macros.builtins.base_file = filename.to_string();
+        self.run_found(src, filename, None, id);
+        self.classify();
+    }
+
+    // Gives the issues found since the last diagnostic pragma, or since we
+    // last entered or left a system header, their final `IssueType`, and
+    // drops the ignored ones.
+    fn classify(&mut self) {
+        let new = self.issues.split_off(self.classified);
+        let (diagnostics, params) = (&self.diagnostics, self.params);
+        let system = self
+            .files
+            .last()
+            .is_some_and(|f| f.system != SystemHeader::No);
+        self.issues.extend(
+            new.into_iter()
+                .filter_map(|i| diagnostics.classify(i, system, params)),
+        );
+        self.classified = self.issues.len();
+    }
+
+    // Like gcc, a file is a system header if it's found in a system
+    // directory, or included by one.
+    fn system_header(&self, found: &Option<Found>) -> SystemHeader {
+        let includer = self.files.last().map_or(SystemHeader::No, |f| f.system);
+        let dir = match found.as_ref().and_then(|f| f.dir) {
+            Some(dir) if self.search.is_system(dir) => SystemHeader::ExternC,
+            _ => SystemHeader::No,
+        };
+        if includer > dir {
+            includer
+        } else {
+            dir
+        }
+    }
+
+    fn run_found(
+        &mut self,
+        src: &str,
+        filename: &str,
+        found: Option<Found>,
+        id: Option<FileId>,
+    ) {
+        if let Some(id) = &id {
+            self.entered.insert(id.clone());
+        }
+        self.classify();
+        let system = self.system_header(&found);
+        self.files.push(File {
+            name: filename.to_string(),
+            found,
+            id: id.clone(),
+            system,
+        });
+        // The main file's linemarker has no flag, and included files' have
+        // 1, for entering them.
+        let flag = if self.files.len() > 1 { " 1" } else { "" };
+        self.linemarker(filename, 1, flag);
+
+        let builtins = &mut self.macros.builtins;
+        let old_timestamp = std::mem::replace(
+            &mut builtins.timestamp,
+            timestamp(Path::new(filename)),
+        );
+        builtins.include_level = self.files.len() - 1;
+        if self.files.len() == 1 {
+            self.command_line_includes();
+        }
+        let guard = self.run_file(src, filename);
+        self.classify();
+        self.files.pop();
+        let builtins = &mut self.macros.builtins;
+        builtins.timestamp = old_timestamp;
+        builtins.include_level = self.files.len().saturating_sub(1);
+        if let (Guard::Closed(m), Some(id)) = (guard, id) {
+            self.guards.insert(id, m);
+        }
+    }
+
+    // The '-imacros' files, and then the '-include' ones, are included before
+    // the main file's first line, as if from the lines after the '-D's and
+    // '-U's. Being included from '<command-line>', they're looked for in the
+    // working directory first. Only the macros '-imacros' files define are
+    // kept, not their text.
+    fn command_line_includes(&mut self) {
+        let params = self.params;
+        let files = params.imacros.iter().map(|f| (f, true));
+        let files = files.chain(params.include.iter().map(|f| (f, false)));
+        for (i, (name, imacros)) in files.enumerate() {
+            if self.failed {
+                return;
+            }
+            let nline = params.macro_defines.len() + i + 1;
+            let loc = Location::new(COMMAND_LINE.to_string(), nline, 1);
+            let header = HeaderName(HeaderNameType::QChar, name.clone());
+            let output_len = self.output.len();
+            let printed = self.printed.clone();
+            self.include_from(
+                IncludeType::Include,
+                header,
+                Some(loc),
+                COMMAND_LINE.to_string(),
+                None,
+            );
+            if imacros {
+                self.output.truncate(output_len);
+                self.printed = printed;
+            }
+        }
+    }
+
+    fn run_file(&mut self, src: &str, filename: &str) -> Guard {
+        let Output {
+            new_file,
+            issues,
+            mut loc_mapping,
+        } = p1to3::preproc_phases_1_to_3(src, filename, self.params);
+        // Phases 1 to 3's issues are held back until the directive after
+        // them, so the diagnostic pragmas before them apply. They're where
+        // they were before any '#line'.
+        let original = loc_mapping.clone();
+        let mut held = issues;
+        held.sort_by_key(|i| {
+            std::cmp::Reverse(i.loc.as_ref().and_then(|l| l.nline))
+        });
+        let mut parser = directive::Parser::new(&new_file, self.params);
+        let mut conds = Conditionals::new();
+        let mut guard = Guard::Start;
+
+        // Consecutive lines of text are expanded together, as a function-like
+        // macro's arguments can span lines. Like gcc, they can also have
+        // directives between them.
+        let mut text = Text::default();
+        while !self.failed {
+            let peeked = parser.peek_directive();
+            let name = peeked.map(|(name, _)| name);
+            if conds.skipping()
+                && !name.is_some_and(|n| conds.needs_parse(n, self.params))
+            {
+                if let Some((name, span)) = peeked {
+                    let loc = span.location(&loc_mapping);
+                    let depth = conds.depth();
+                    self.skipped_directive(&mut conds, name, loc);
+                    guard.line(Some(name), None, depth, conds.depth());
+                }
+                if !parser.skip_line() {
+                    break;
+                }
+                continue;
+            }
+
+            let (token, span) =
+                match parser.next(&loc_mapping, &mut self.issues) {
+                    Some(ts) => ts,
+                    None => break,
+                };
+            let depth = conds.depth();
+            // With -fdirectives-only, the text isn't looked at.
+            let checked = match &token {
+                Ok(Token::Pragma(PragmaTokens::Poison(_))) => false,
+                Ok(Token::NonPreProc(_)) => !self.params.fdirectives_only,
+                _ => true,
+            };
+            if checked {
+                self.check_poisoned(&loc_mapping, parser.line());
+            }
+            if let Ok(Token::NonPreProc(line)) = token {
+                guard.line(None, None, depth, depth);
+                if !text.toks.is_empty() {
+                    text.toks.push(HToken::new(SpannedPPToken(
+                        PPToken::NewLine,
+                        span,
+                    )));
+                }
+                // Phases 1 to 3 drop indentation, but can leave a space at
+                // the start of a file.
+                text.toks.extend(
+                    line.into_iter()
+                        .skip_while(|t| t.0 == PPToken::Space)
+                        .map(HToken::new),
+                );
+                continue;
+            }
+
+            self.text(&loc_mapping, &mut text, true);
+            self.release(&mut held, span.location(&original));
+            let loc = span.location(&loc_mapping);
+            if !text.toks.is_empty() && self.params.pedantic {
+                self.issues.push(Issue::new(
+                    loc.clone(),
+                    IssueType::Warning,
+                    IssueDesc::DirectiveInMacroArgs(
+                        name.unwrap_or("").to_string(),
+                    ),
+                ));
+            }
+            match token {
+                Ok(token) => {
+                    let ifndef = match &token {
+                        Token::Ifndef(m) => Some(m.clone()),
+                        _ => None,
+                    };
+                    // With -fdirectives-only, the definitions are kept for
+                    // when the output is preprocessed again, like gcc's -dD.
+                    let definition = matches!(
+                        token,
+                        Token::Define(..)
+                            | Token::DefineFn(..)
+                            | Token::Undef(_)
+                    );
+                    if definition && self.params.fdirectives_only {
+                        let line = parser.line();
+                        let start =
+                            line.iter().position(|t| t.0 != PPToken::Space);
+                        self.write_line(
+                            loc.clone(),
+                            &line[start.unwrap_or(0)..],
+                        );
+                    }
+                    self.directive(
+                        &mut loc_mapping,
+                        &mut conds,
+                        token,
+                        span,
+                        loc,
+                    );
+                    guard.line(name, ifndef.as_ref(), depth, conds.depth());
+                }
+                Err(issue) => {
+                    self.issues.push(issue);
+                    // Malformed conditionals still nest, with their groups
+                    // skipped.
+                    self.skipped_directive(&mut conds, name.unwrap_or(""), loc);
+                    guard.line(name, None, depth, conds.depth());
+                }
+            }
+        }
+        self.text(&loc_mapping, &mut text, false);
+        self.issues.extend(held.into_iter().rev());
+        if self.failed {
+            return Guard::None;
+        }
+        if conds.depth() != 0 {
+            guard = Guard::None;
+        }
+        conds.finish(&mut self.issues);
+        guard
+    }
+
+    // Moves the issues in `held` from before `loc` to `issues`. `held` is
+    // sorted by line, the last first.
+    fn release(&mut self, held: &mut Vec<Issue>, loc: Option<Location>) {
+        let nline = loc.and_then(|l| l.nline);
+        while let Some(issue) = held.pop() {
+            if nline.is_some_and(|n| {
+                issue.loc.as_ref().and_then(|l| l.nline) >= Some(n)
+            }) {
+                held.push(issue);
+                break;
+            }
+            self.issues.push(issue);
+        }
+    }
+
+    // Like gcc, poisoned identifiers are only errors where they're written,
+    // not where they come from a macro.
+    fn check_poisoned(
+        &mut self,
+        loc_mapping: &LocMapping,
+        line: &[SpannedPPToken],
+    ) {
+        if self.poisoned.is_empty() {
+            return;
+        }
+        for t in line {
+            if let PPToken::Identifer(i) = &t.0 {
+                if self.poisoned.contains(i) {
+                    self.issues.push(Issue::new(
+                        t.1.location(loc_mapping),
+                        IssueType::Error,
+                        IssueDesc::PoisonedIdentifier(i.0.clone()),
+                    ));
+                }
+            }
+        }
+    }
+
+    // A conditional directive whose condition isn't looked at.
+    fn skipped_directive(
+        &mut self,
+        conds: &mut Conditionals,
+        name: &str,
+        loc: Option<Location>,
+    ) {
+        let issues = &mut self.issues;
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                conds.open(name, loc, |_| false, issues)
+            }
+            "elif" => conds.elif(loc, |_| false, issues),
+            "else" => conds.else_(loc, issues),
+            "endif" => conds.endif(loc, issues),
+            _ => (),
+        }
+    }
+
+    // With `more`, there may be more text after a directive, so a call
+    // that's still open is left in `text`, with the start of its line.
+    fn text(&mut self, loc_mapping: &LocMapping, text: &mut Text, more: bool) {
+        let mut toks = std::mem::take(&mut text.start);
+        let first = toks.first().map(|t| t.1);
+        let mut loc = match first.or_else(|| text.toks.first().map(|t| t.span))
+        {
+            Some(span) => span.location(loc_mapping),
+            None => return,
+        };
+        let rest = std::mem::take(&mut text.toks);
+        // -fdirectives-only leaves the text as it is.
+        if self.params.fdirectives_only {
+            toks.extend(rest.into_iter().map(HToken::spanned));
+        } else {
+            let (expanded, open) = self.expand_text(loc_mapping, rest, more);
+            toks.extend(expanded);
+            text.toks = open;
+        }
+
+        // The newlines that are left are where the lines of text started,
+        // unless they were in a macro's arguments.
+        let mut start = 0;
+        for (i, t) in toks.iter().enumerate() {
+            if t.0 == PPToken::NewLine {
+                self.write_line(loc, &toks[start..i]);
+                loc = t.1.location(loc_mapping);
+                start = i + 1;
+            }
+        }
+        if text.toks.is_empty() {
+            self.write_line(loc, &toks[start..]);
+        } else {
+            text.start = toks.split_off(start);
+        }
+    }
+
+    // Macro expands `text`, carrying out the '_Pragma ( string-literal )'s,
+    // and with -fms-extensions the '__pragma ( tokens )'s, as they're come
+    // to. That way they can come from macros, and affect the text after them.
+    // They're dropped from the text.
+    //
+    // With `more`, a function-like macro call that isn't closed in `text` is
+    // given back unexpanded, from its name on.
+    fn expand_text(
+        &mut self,
+        loc_mapping: &LocMapping,
+        mut text: Vec<HToken>,
+        more: bool,
+    ) -> (Vec<SpannedPPToken>, Vec<HToken>) {
+        let mut ret = vec![];
+        loop {
+            let (toks, mut rest) = Expander::new(
+                &self.macros,
+                self.params,
+                loc_mapping,
+                &mut self.issues,
+            )
+            .expand_until_pragma(text, more);
+            ret.extend(toks.into_iter().map(HToken::spanned));
+            let (name, loc) = match rest.first() {
+                Some(op)
+                    if expand::is_pragma_operator(&op.tok, self.params) =>
+                {
+                    (op.tok.to_string(), op.span.location(loc_mapping))
+                }
+                _ => return (ret, rest),
+            };
+            match pragma_operand(&name, &rest[1..], self.params) {
+                Some((src, len)) => {
+                    let span = rest[0].span;
+                    rest.drain(..1 + len);
+                    // Like gcc, what's written of it is on a line of its own.
+                    if let Some(toks) = self.pragma_operator(&src, loc) {
+                        if ret.last().is_some_and(|t| t.0 != PPToken::NewLine) {
+                            ret.push(SpannedPPToken(PPToken::NewLine, span));
+                        }
+                        ret.extend(directive_line("pragma", toks, span));
+                        ret.push(SpannedPPToken(PPToken::NewLine, span));
+                    }
+                }
+                None => {
+                    self.issues.push(Issue::new(
+                        loc,
+                        IssueType::Error,
+                        IssueDesc::MalformedPragmaOperator(name),
+                    ));
+                    ret.push(rest.remove(0).spanned());
+                }
+            }
+            text = rest;
+        }
+    }
+
+    // Carries out the pragma `src`, found at `loc`, as if it were the
+    // tokens of a '#pragma', giving the tokens to write after 'pragma', if
+    // any.
+    fn pragma_operator(
+        &mut self,
+        src: &str,
+        loc: Option<Location>,
+    ) -> Option<Vec<PPToken>> {
+        let directive = format!("#pragma {}", src);
+        let line: Vec<_> = Lexer::new(&directive, self.params).collect();
+        let mut issues = vec![];
+        let token = directive::parse_line(&line, &[], self.params, &mut issues);
+        let ret = match token {
+            Ok(Token::Pragma(pragma)) => self.pragma(&pragma, loc.clone()),
+            Ok(Token::Msg(mtype, toks)) => {
+                self.message(&mtype, &toks, loc.clone());
+                None
+            }
+            Ok(_) => None,
+            Err(issue) => {
+                issues.push(issue);
+                None
+            }
+        };
+        // Its issues are said to be where the operator is.
+        self.issues.extend(issues.into_iter().map(|mut issue| {
+            issue.loc = loc.clone();
+            issue
+        }));
+        ret
+    }
+
+    // Writes a line of output from `loc`. Like gcc, up to 8 blank lines are
+    // written to get to it, and a linemarker if it's further.
+    fn write_line(&mut self, loc: Option<Location>, toks: &[SpannedPPToken]) {
+        if let (false, Some(loc)) = (self.params.disable_linemarkers, loc) {
+            let nline = loc.nline.unwrap();
+            match &self.printed {
+                Some((filename, printed))
+                    if *filename == loc.filename
+                        && (*printed..=*printed + 8).contains(&nline) =>
+                {
+                    for _ in *printed..nline {
+                        self.output.push('\n');
+                    }
+                    self.printed = Some((loc.filename, nline));
+                }
+                _ => self.linemarker(&loc.filename, nline, ""),
+            }
+        }
+        self.output.push_str(&expand::spell(toks, self.params));
+        self.output.push('\n');
+        if let Some((_, printed)) = &mut self.printed {
+            *printed += 1;
+        }
+    }
+
+    // '# nline "filename" flags', unless they're disabled by -P. The flags
+    // are `flag`, for entering or leaving a file, then the current file's
+    // system header flags.
+    //
+    // Only the locations they give each line are meant to match cpp's. It
+    // writes some more that change none of them, e.g., after a '#warning' in
+    // a system header, or on returning from one, and we don't.
+    fn linemarker(&mut self, filename: &str, nline: LineNumber, flag: &str) {
+        if self.params.disable_linemarkers {
+            return;
+        }
+        let system = self.files.last().map_or("", |f| f.system.flags());
+        self.output.push_str(&format!(
+            "# {} \"{}\"{}{}\n",
+            nline,
+            expand::escape(filename),
+            flag,
+            system
+        ));
+        self.printed = Some((filename.to_string(), nline));
+    }
+
+    // The directive `token`, which starts at `span`.
+    fn directive(
+        &mut self,
+        loc_mapping: &mut Vec<(Location, Location)>,
+        conds: &mut Conditionals,
+        token: Token,
+        span: Span,
+        loc: Option<Location>,
+    ) {
+        let (macros, params) = (&self.macros, self.params);
+        let issues = &mut self.issues;
+        let defined = |name| eval::is_defined(macros, name, params);
+        let eval = |expr, issues: &mut Vec<Issue>| {
+            eval::eval(expr, macros, params, loc_mapping, issues)
+                .unwrap_or_else(|issue| {
+                    // The condition is false if it can't be evaluated.
+                    issues.push(issue);
+                    false
+                })
+        };
+        match &token {
+            Token::Ifdef(name) => {
+                conds.open("ifdef", loc, |_| defined(name), issues)
+            }
+            Token::Ifndef(name) => {
+                conds.open("ifndef", loc, |_| !defined(name), issues)
+            }
+            Token::If(expr) => {
+                conds.open("if", loc, |issues| eval(expr, issues), issues)
+            }
+            Token::ElseIf(expr) => {
+                conds.elif(loc, |issues| eval(expr, issues), issues)
+            }
+            Token::Else => conds.else_(loc, issues),
+            Token::EndIf => conds.endif(loc, issues),
+            Token::Include(itype, header) => {
+                self.include(itype.clone(), header.clone(), loc)
+            }
+            Token::Pragma(pragma) => {
+                if let Some(toks) = self.pragma(pragma, loc.clone()) {
+                    self.write_line(loc, &directive_line("pragma", toks, span));
+                }
+            }
+            Token::Ident(itype, s) => {
+                self.extension_warning(itype.name(), false, &loc);
+                let toks = vec![PPToken::StrLit(s.clone())];
+                self.write_line(loc, &directive_line("ident", toks, span));
+            }
+            Token::Assert(..) | Token::Unassert(..) => {
+                let name = match token {
+                    Token::Assert(..) => "assert",
+                    _ => "unassert",
+                };
+                self.extension_warning(name, true, &loc);
+                self.macros
+                    .handle_token(&token, loc, params, &mut self.issues);
+            }
+            Token::Msg(mtype, toks) => self.message(mtype, toks, loc),
+            Token::IncludeComputed(itype, toks) => self.include_computed(
+                loc_mapping,
+                itype.clone(),
+                toks.clone(),
+                loc,
+            ),
+            Token::Line(None, Some(nline), filename) => {
+                let filename = filename.as_ref().map(|f| unescape(&f.1));
+                self.line(loc_mapping, span, *nline, filename, loc)
+            }
+            Token::Line(Some(toks), _, _) => {
+                self.line_computed(loc_mapping, span, toks.clone(), loc)
+            }
+            _ => {
+                self.macros.handle_token(&token, loc, params, issues);
+            }
+        }
+    }
+
+    fn include_computed(
+        &mut self,
+        loc_mapping: &LocMapping,
+        itype: IncludeType,
+        toks: Vec<SpannedPPToken>,
+        loc: Option<Location>,
+    ) {
+        let toks = Expander::new(
+            &self.macros,
+            self.params,
+            loc_mapping,
+            &mut self.issues,
+        )
+        .expand(toks);
+        match directive::computed_header_name(&toks) {
+            Some((header, extra)) => {
+                if extra.iter().any(|t| t.0 != PPToken::Space) {
+                    self.issues.push(Issue::new(
+                        loc.clone(),
+                        IssueType::Warning,
+                        IssueDesc::ExtraTokensAfterDirective(
+                            itype.name().to_string(),
+                        ),
+                    ));
+                }
+                self.include(itype, header, loc)
+            }
+            None => self.issues.push(Issue::new(
+                loc,
+                IssueType::Error,
+                IssueDesc::IncludeExpectsFilename(itype.name().to_string()),
+            )),
+        }
+    }
+
+    // '#line tokens', which have to be macro expanded into '#line digits
+    // "filename"?'.
+    fn line_computed(
+        &mut self,
+        loc_mapping: &mut Vec<(Location, Location)>,
+        span: Span,
+        toks: Vec<SpannedPPToken>,
+        loc: Option<Location>,
+    ) {
+        let toks = Expander::new(
+            &self.macros,
+            self.params,
+            loc_mapping,
+            &mut self.issues,
+        )
+        .expand(toks);
+        let toks: Vec<_> =
+            toks.iter().filter(|t| t.0 != PPToken::Space).collect();
+        let error = |t: &SpannedPPToken, desc| {
+            Issue::new(t.1.location(loc_mapping), IssueType::Error, desc)
+        };
+
+        let nline = match toks.first() {
+            Some(t) => match &t.0 {
+                PPToken::PPNumber(n) => match directive::line_number(&n.0) {
+                    Some(nline) => nline,
+                    None => {
+                        let desc = IssueDesc::InvalidLineNumber(n.0.clone());
+                        self.issues.push(error(t, desc));
+                        return;
+                    }
+                },
+                _ => {
+                    let desc = IssueDesc::InvalidLineNumber(t.0.to_string());
+                    self.issues.push(error(t, desc));
+                    return;
+                }
+            },
+            None => {
+                self.issues.push(Issue::new(
+                    loc,
+                    IssueType::Error,
+                    IssueDesc::MalformedDirective("line".to_string()),
+                ));
+                return;
+            }
+        };
+        let filename = match toks.get(1) {
+            None => None,
+            Some(SpannedPPToken(PPToken::StrLit(s), _))
+                if s.0 == StrLitPrefix::None =>
+            {
+                Some(unescape(&s.1))
+            }
+            Some(t) => {
+                let desc = IssueDesc::MalformedDirective("line".to_string());
+                self.issues.push(error(t, desc));
+                return;
+            }
+        };
+        if let Some(t) = toks.get(2) {
+            self.issues.push(Issue::new(
+                t.1.location(loc_mapping),
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective("line".to_string()),
+            ));
+        }
+        self.line(loc_mapping, span, nline, filename, loc)
+    }
+
+    // '#line', at `span`: the line after it is line `nline` of `filename`,
+    // or of the current file.
+    fn line(
+        &mut self,
+        loc_mapping: &mut Vec<(Location, Location)>,
+        span: Span,
+        nline: u64,
+        filename: Option<String>,
+        loc: Option<Location>,
+    ) {
+        // C90 and C++98 only allow up to 32767.
+        let max =
+            if self.params.version.ver_ge(CVersion::C99, CppVersion::Cpp11) {
+                2147483647
+            } else {
+                32767
+            };
+        if nline > max {
+            self.issues.push(Issue::new(
+                loc,
+                IssueType::Error,
+                IssueDesc::LineNumberOutOfRange(nline),
+            ));
+            return;
+        }
+        if nline == 0 && self.params.pedantic {
+            self.issues.push(Issue::new(
+                loc,
+                IssueType::Warning,
+                IssueDesc::LineNumberOutOfRange(nline),
+            ));
+        }
+        set_line(loc_mapping, span.nline + 1, nline as LineNumber, filename);
+    }
+
+    // '#pragma', '_Pragma' or '__pragma'.
+    // Gives the tokens to write after 'pragma', if any.
+    fn pragma(
+        &mut self,
+        pragma: &PragmaTokens,
+        loc: Option<Location>,
+    ) -> Option<Vec<PPToken>> {
+        match pragma {
+            PragmaTokens::Once => self.pragma_once(loc),
+            PragmaTokens::PushMacro(name) => self.macros.push_macro(name),
+            PragmaTokens::PopMacro(name) => self.macros.pop_macro(name),
+            PragmaTokens::Diagnostic(kind, option) => {
+                self.classify();
+                self.diagnostics.set(&option.1, kind.clone());
+            }
+            PragmaTokens::DiagnosticPush => self.diagnostics.push(),
+            PragmaTokens::DiagnosticPop => {
+                self.classify();
+                self.diagnostics.pop();
+            }
+            PragmaTokens::SystemHeader => self.pragma_system_header(loc),
+            PragmaTokens::Poison(idents) => self.poison(idents, loc),
+            PragmaTokens::Dependency(header, extra) => {
+                self.dependency(header, extra, loc)
+            }
+            PragmaTokens::Other(toks) => return self.pragma_other(toks, loc),
+            PragmaTokens::None => (),
+        }
+        None
+    }
+
+    // A pragma for the compiler, or one of the handlers.
+    fn pragma_other(
+        &mut self,
+        toks: &[PPToken],
+        loc: Option<Location>,
+    ) -> Option<Vec<PPToken>> {
+        match self.pragmas.handle(toks, loc.as_ref(), &mut self.issues) {
+            Some(PragmaAction::Consume) => None,
+            Some(PragmaAction::Rewrite(toks)) => Some(toks),
+            Some(PragmaAction::PassThrough) => Some(toks.to_vec()),
+            None => {
+                if self.params.wunknown_pragmas {
+                    let (namespace, name) = pragma::names(toks);
+                    let names: Vec<_> =
+                        namespace.into_iter().chain(name).collect();
+                    self.issues.push(Issue::new(
+                        loc,
+                        IssueType::Warning,
+                        IssueDesc::UnknownPragma(names.join(" ")),
+                    ));
+                }
+                Some(toks.to_vec())
+            }
+        }
+    }
+
+    fn pragma_once(&mut self, loc: Option<Location>) {
+        if self.files.len() <= 1 {
+            self.issues.push(Issue::new(
+                loc,
+                IssueType::Warning,
+                IssueDesc::PragmaOnceInMainFile,
+            ));
+        }
+        if let Some(id) = self.files.last().and_then(|f| f.id.clone()) {
+            self.once_only.insert(id);
+        }
+    }
+
+    // '#error', '#warning' and their pragmas. Preprocessing stops if it's an
+    // error, or a warning made one, for `fallback` to take over.
+    fn message(
+        &mut self,
+        mtype: &MessageType,
+        toks: &[PPToken],
+        loc: Option<Location>,
+    ) {
+        let (name, itype) = match mtype {
+            MessageType::Error => ("#error", IssueType::Error),
+            MessageType::Warning => ("#warning", IssueType::Warning),
+            MessageType::PragmaError => ("error", IssueType::Error),
+            MessageType::PragmaWarning => ("warning", IssueType::Warning),
+        };
+        let text = match mtype {
+            MessageType::Error | MessageType::Warning => {
+                toks.iter().map(|t| t.to_string()).collect()
+            }
+            _ => match pragma_message(toks) {
+                Some(text) => text,
+                None => {
+                    self.issues.push(Issue::new(
+                        loc,
+                        IssueType::Warning,
+                        IssueDesc::MalformedPragma(name.to_string()),
+                    ));
+                    return;
+                }
+            },
+        };
+        // '#warning' is standard from C2x and C++23.
+        if *mtype == MessageType::Warning
+            && self.params.pedantic
+            && self.params.version.ver_ls(CVersion::C2x, CppVersion::Cpp23)
+        {
+            self.issues.push(Issue::new(
+                loc.clone(),
+                IssueType::Warning,
+                IssueDesc::GnuExtension(name.to_string()),
+            ));
+        }
+        self.issues.push(Issue::new(
+            loc,
+            itype,
+            IssueDesc::Message(mtype.clone(), text),
+        ));
+        self.classify();
+        self.failed |= self.issues.last().is_some_and(|i| {
+            i.itype == IssueType::Error
+                && matches!(i.desc, IssueDesc::Message(..))
+        });
+    }
+
+    // Poisoned macros are undefined.
+    fn poison(&mut self, idents: &[Identifer], loc: Option<Location>) {
+        for i in idents {
+            if self.macros.undef(i).is_some() {
+                self.issues.push(Issue::new(
+                    loc.clone(),
+                    IssueType::Warning,
+                    IssueDesc::PoisoningExistingMacro(i.0.clone()),
+                ));
+            }
+            self.poisoned.insert(i.clone());
+        }
+    }
+
+    // Warns if the file `header`, looked for like an '#include', is newer
+    // than the current file.
+    fn dependency(
+        &mut self,
+        header: &HeaderName,
+        extra: &[PPToken],
+        loc: Option<Location>,
+    ) {
+        let current = match self.files.last() {
+            Some(f) => f.name.clone(),
+            None => return,
+        };
+        let found = match self.search.find(header, &current) {
+            Some(found) => found,
+            None => {
+                self.issues.push(Issue::new(
+                    loc,
+                    IssueType::Warning,
+                    IssueDesc::HeaderNotFound(header.1.clone()),
+                ));
+                return;
+            }
+        };
+        let modified =
+            |path: &Path| fs::metadata(path).and_then(|m| m.modified());
+        if let (Ok(dep), Ok(current)) =
+            (modified(&found.path), modified(Path::new(&current)))
+        {
+            if dep > current {
+                let extra: Vec<_> = extra
+                    .iter()
+                    .skip_while(|t| **t == PPToken::Space)
+                    .map(|t| t.to_string())
+                    .collect();
+                self.issues.push(Issue::new(
+                    loc,
+                    IssueType::Warning,
+                    IssueDesc::CurrentFileOlderThan(
+                        found.path.display().to_string(),
+                        extra.concat(),
+                    ),
+                ));
+            }
+        }
+    }
+
+    // The rest of the file is a system header, and the linemarker after the
+    // pragma says so.
+    fn pragma_system_header(&mut self, loc: Option<Location>) {
+        if self.files.len() <= 1 {
+            self.issues.push(Issue::new(
+                loc,
+                IssueType::Warning,
+                IssueDesc::SystemHeaderPragmaInMainFile,
+            ));
+            return;
+        }
+        self.classify();
+        if let Some(f) = self.files.last_mut() {
+            f.system = SystemHeader::Yes;
+        }
+        if let Some(loc) = loc {
+            self.linemarker(&loc.filename, loc.nline.unwrap() + 1, "");
+        }
+    }
+
+    // The GNU extension directive `name`. Like gcc, if it's deprecated, e.g.,
+    // '#import', -pedantic's warning is given instead of -Wdeprecated's.
+    fn extension_warning(
+        &mut self,
+        name: &str,
+        deprecated: bool,
+        loc: &Option<Location>,
+    ) {
+        let name = format!("#{}", name);
+        let desc = if self.params.pedantic {
+            IssueDesc::GnuExtension(name)
+        } else if deprecated && self.params.wdeprecated {
+            IssueDesc::DeprecatedGnuExtension(name)
+        } else {
+            return;
+        };
+        self.issues
+            .push(Issue::new(loc.clone(), IssueType::Warning, desc));
+    }
+
+    fn include(
+        &mut self,
+        itype: IncludeType,
+        header: HeaderName,
+        loc: Option<Location>,
+    ) {
+        // '#include_next' and '#import' are GNU extensions.
+        if itype != IncludeType::Include {
+            let import = itype == IncludeType::Import;
+            self.extension_warning(itype.name(), import, &loc);
+        }
+        if header.1.is_empty() {
+            self.issues.push(Issue::new(
+                loc,
+                IssueType::Error,
+                IssueDesc::EmptyFilename(itype.name().to_string()),
+            ));
+            return;
+        }
+        if self.files.len() >= MAX_INCLUDE_DEPTH {
+            self.issues.push(Issue::new(
+                loc,
+                IssueType::Error,
+                IssueDesc::IncludeNestedTooDeeply(MAX_INCLUDE_DEPTH),
+            ));
+            return;
+        }
+
+        let (includer, includer_found) = match self.files.last() {
+            Some(f) => (f.name.clone(), f.found.clone()),
+            None => (String::new(), None),
+        };
+        self.include_from(itype, header, loc, includer, includer_found);
+    }
+
+    // Includes `header` as if from `includer`, which was found at
+    // `includer_found`.
+    fn include_from(
+        &mut self,
+        itype: IncludeType,
+        header: HeaderName,
+        loc: Option<Location>,
+        includer: String,
+        includer_found: Option<Found>,
+    ) {
+        let found = match (&itype, includer_found) {
+            (IncludeType::IncludeNext, Some(f)) => {
+                self.search.find_next(&header, f.dir)
+            }
+            (IncludeType::IncludeNext, None) => {
+                // Like gcc, it's an '#include' in the main file.
+                self.issues.push(Issue::new(
+                    loc.clone(),
+                    IssueType::Warning,
+                    IssueDesc::IncludeNextInPrimaryFile,
+                ));
+                self.search.find(&header, &includer)
+            }
+            _ => self.search.find(&header, &includer),
+        };
+        self.resolutions.push(Resolution {
+            itype: itype.clone(),
+            header: header.clone(),
+            includer,
+            found: found.clone(),
+        });
+        let found = match found {
+            Some(found) => found,
+            None => {
+                self.issues.push(Issue::new(
+                    loc,
+                    IssueType::Error,
+                    IssueDesc::HeaderNotFound(header.1),
+                ));
+                return;
+            }
+        };
+
+        let id = FileId::new(&found.path);
+        if let Some(id) = &id {
+            let guarded = self
+                .guards
+                .get(id)
+                .is_some_and(|m| self.macros.is_defined(m));
+            if guarded
+                || self.once_only.contains(id)
+                || (itype == IncludeType::Import && self.entered.contains(id))
+            {
+                self.skipped_rereads += 1;
+                return;
+            }
+            if itype == IncludeType::Import {
+                self.once_only.insert(id.clone());
+            }
+        }
+
+        let filename = found.path.display().to_string();
+        match fs::read(&found.path) {
+            Ok(src) => {
+                let src = String::from_utf8_lossy(&src);
+                self.run_found(&src, &filename, Some(found), id);
+                // Back to the line after the '#include', with flag 2 for
+                // leaving the file.
+                if let Some(loc) = loc {
+                    self.linemarker(
+                        &loc.filename,
+                        loc.nline.unwrap() + 1,
+                        " 2",
+                    );
+                }
+            }
+            Err(e) => self.issues.push(Issue::new(
+                loc,
+                IssueType::Error,
+                IssueDesc::CannotReadFile(filename, e.to_string()),
+            )),
+        }
+    }
+}
+
+// The pragma in the operand of the operator `name`, and how many tokens the
+// operand is. '_Pragma's is a string literal, which is destringised, and
+// '__pragma's is any balanced tokens. Newlines in it are whitespace.
+fn pragma_operand(
+    name: &str,
+    toks: &[HToken],
+    params: &Params,
+) -> Option<(String, usize)> {
+    let is_space =
+        |t: &HToken| t.tok == PPToken::Space || t.tok == PPToken::NewLine;
+    let lparen = toks.iter().position(|t| !is_space(t))?;
+    if !toks[lparen].tok.is_punct(&Punct::LParen) {
+        return None;
+    }
+    let mut depth = 0;
+    let rparen = lparen
+        + 1
+        + toks[lparen + 1..].iter().position(|t| {
+            if t.tok.is_punct(&Punct::LParen) {
+                depth += 1;
+            } else if t.tok.is_punct(&Punct::RParen) {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            false
+        })?;
+    let operand: Vec<_> = toks[lparen + 1..rparen]
+        .iter()
+        .filter(|t| t.tok != PPToken::NewLine)
+        .map(|t| SpannedPPToken(t.tok.clone(), t.span))
+        .collect();
+    if name == "__pragma" {
+        return Some((expand::spell(&operand, params), rparen + 1));
+    }
+    match operand
+        .iter()
+        .filter(|t| t.0 != PPToken::Space)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [SpannedPPToken(PPToken::StrLit(StrLit(prefix, s)), _)]
+            if *prefix == StrLitPrefix::None || *prefix == StrLitPrefix::L =>
+        {
+            Some((destringise(s), rparen + 1))
+        }
+        _ => None,
+    }
+}
+
+// The message of '#pragma GCC error' or '#pragma GCC warning', a string
+// literal, which can be in parentheses.
+fn pragma_message(toks: &[PPToken]) -> Option<String> {
+    let toks: Vec<_> = toks.iter().filter(|t| **t != PPToken::Space).collect();
+    let s = match toks.as_slice() {
+        [PPToken::StrLit(s)] => s,
+        [l, PPToken::StrLit(s), r]
+            if l.is_punct(&Punct::LParen) && r.is_punct(&Punct::RParen) =>
+        {
+            s
+        }
+        _ => return None,
+    };
+    Some(s.1.clone())
+}
+
+// The line '#name toks', said to be at `span`.
+fn directive_line(
+    name: &str,
+    toks: Vec<PPToken>,
+    span: Span,
+) -> Vec<SpannedPPToken> {
+    let directive = [
+        PPToken::Punct(Punct::Hash),
+        PPToken::Identifer(Identifer(name.to_string())),
+        PPToken::Space,
+    ];
+    directive
+        .iter()
+        .cloned()
+        .chain(toks)
+        .map(|t| SpannedPPToken(t, span))
+        .collect()
+}
+
+// '\"' becomes '"' and '\\' becomes '\', as '_Pragma' wants.
+fn destringise(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(e @ ('\\' | '"'))) => {
+                ret.push(e);
+                chars.next();
+            }
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+// A '#line' filename has its simple escape sequences processed, so that
+// '"a\\b.c"' is 'a\b.c'.
+fn unescape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(e @ ('\\' | '"' | '\'' | '?'))) => {
+                ret.push(e);
+                chars.next();
+            }
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+// Maps line `oline` of phases 1 to 3's output, and the lines after it, to
+// line `nline` of `filename` onwards.
+fn set_line(
+    loc_mapping: &mut Vec<(Location, Location)>,
+    oline: LineNumber,
+    nline: LineNumber,
+    filename: Option<String>,
+) {
+    let start = Span {
+        nline: oline,
+        nchar: 1,
+        len: 0,
+    };
+    let old = match start.location(loc_mapping) {
+        Some(old) => old,
+        None => return,
+    };
+    let (old_nline, old_nchar) = (old.nline.unwrap(), old.nchar.unwrap());
+    let filename = filename.unwrap_or(old.filename);
+
+    let i = loc_mapping.partition_point(|(_, o)| {
+        (o.nline.unwrap(), o.nchar.unwrap()) <= (oline, 1)
+    });
+    for (s, _) in &mut loc_mapping[i..] {
+        s.nline = s.nline.map(|l| l + nline - old_nline);
+        s.filename = filename.clone();
+    }
+    let o = Location::new(filename.clone(), oline, 1);
+    let s = Location::new(filename, nline, old_nchar);
+    loc_mapping.insert(i, (s, o));
+}
+
+// Preprocesses `src`, giving the text and every issue found.
+#[allow(dead_code)]
+pub fn preprocess(
+    src: &str,
+    filename: &str,
+    params: &Params,
+) -> (String, Vec<Issue>) {
+    let mut pp = Preprocessor::new(params);
+    pp.run(src, filename);
+    (pp.output, pp.issues)
+}
@@ -0,0 +1,652 @@
+use super::*;
+use crate::common::{CppVersion, Version};
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+// Without linemarkers, so only the text is compared.
+fn params() -> Params {
+    Params {
+        disable_linemarkers: true,
+        ..Params::default()
+    }
+}
+
+#[test]
+fn text_between_directives() {
+    let (text, issues) = preprocess(
+        "a\n\
+         #define a 1\n\
+         a\n\
+         a\n\
+         #undef a\n\
+         a\n",
+        &FILENAME,
+        &params(),
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "a\n1\n1\na\n");
+}
+
+// A directive between a function-like macro's name and its '(' means it
+// isn't called.
+#[test]
+fn call_across_directive() {
+    let (text, issues) = preprocess(
+        "#define f(x) x\n\
+         f\n\
+         #define g\n\
+         (1)\n",
+        &FILENAME,
+        &params(),
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "f\n(1)\n");
+}
+
+// But like gcc, directives can be between its arguments, and are only
+// warned about with -pedantic.
+#[test]
+fn directive_in_args() {
+    let src = "#define f(x) [x]\n\
+               a f(1\n\
+               #ifdef X\n\
+               2\n\
+               #else\n\
+               3\n\
+               #endif\n\
+               #define Y 4\n\
+               Y) b\n\
+               Y\n";
+    let (text, issues) = preprocess(src, &FILENAME, &params());
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "a [1 3 4] b\n4\n");
+
+    let pedantic = Params {
+        pedantic: true,
+        ..params()
+    };
+    let (text, issues) = preprocess(src, &FILENAME, &pedantic);
+    let warning = |nline, name: &str| {
+        Issue::new(
+            Some(Location::new(FILENAME.clone(), nline, 1)),
+            IssueType::Warning,
+            IssueDesc::DirectiveInMacroArgs(name.to_string()),
+        )
+    };
+    fassert_eq!(
+        issues,
+        vec![
+            warning(3, "ifdef"),
+            warning(5, "else"),
+            warning(7, "endif"),
+            warning(8, "define"),
+        ]
+    );
+    fassert_eq!(text, "a [1 3 4] b\n4\n");
+}
+
+// Like gcc, '__has_attribute' and, in C++, '__has_cpp_attribute' are
+// defined, so that their support can be checked for.
+#[test]
+fn attribute_operators_defined() {
+    let src = "#ifdef __has_attribute\n\
+               a\n\
+               #endif\n\
+               #ifndef __has_cpp_attribute\n\
+               b\n\
+               #endif\n\
+               #if defined(__has_attribute) && __has_attribute(noreturn)\n\
+               c\n\
+               #endif\n";
+    let (text, issues) = preprocess(src, &FILENAME, &params());
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "a\nb\nc\n");
+
+    let cpp = Params {
+        version: Version::Cpp(CppVersion::Cpp17),
+        ..params()
+    };
+    let (text, issues) = preprocess(src, &FILENAME, &cpp);
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "a\nc\n");
+}
+
+// '#line' changes where later lines are said to be.
+#[test]
+fn line() {
+    let (text, issues) = preprocess(
+        "#define N 20\n\
+         #define F \"g.y\"\n\
+         #line 10 \"parse.y\"\n\
+         #bogus\n\
+         #line 100\n\
+         #bogus\n\
+         #line N F\n\
+         #bogus\n\
+         #line 2147483648\n\
+         #line 0x10\n\
+         #line\n\
+         #bogus\n\
+         text\n",
+        &FILENAME,
+        &params(),
+    );
+    let issue = |filename: &str, nline, nchar, itype, desc| {
+        Issue::new(
+            Some(Location::new(filename.to_string(), nline, nchar)),
+            itype,
+            desc,
+        )
+    };
+    let unknown = || IssueDesc::UnknownDirective("bogus".to_string());
+    fassert_eq!(
+        issues,
+        vec![
+            issue("parse.y", 10, 1, IssueType::Error, unknown()),
+            issue("parse.y", 100, 1, IssueType::Error, unknown()),
+            issue("g.y", 20, 1, IssueType::Error, unknown()),
+            issue(
+                "g.y",
+                21,
+                1,
+                IssueType::Error,
+                IssueDesc::LineNumberOutOfRange(2147483648),
+            ),
+            issue(
+                "g.y",
+                22,
+                7,
+                IssueType::Error,
+                IssueDesc::InvalidLineNumber("0x10".to_string()),
+            ),
+            issue(
+                "g.y",
+                23,
+                2,
+                IssueType::Error,
+                IssueDesc::MalformedDirective("line".to_string()),
+            ),
+            issue("g.y", 24, 1, IssueType::Error, unknown()),
+        ]
+    );
+    fassert_eq!(text, "text\n");
+}
+
+#[test]
+fn line_range() {
+    let src = "#line 32768\n#line 0\n";
+    let params = Params {
+        version: Version::C(CVersion::C89),
+        pedantic: true,
+        ..Params::default()
+    };
+    let (_, issues) = preprocess(src, &FILENAME, &params);
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(Location::new(FILENAME.clone(), 1, 1)),
+                IssueType::Error,
+                IssueDesc::LineNumberOutOfRange(32768),
+            ),
+            Issue::new(
+                Some(Location::new(FILENAME.clone(), 2, 1)),
+                IssueType::Warning,
+                IssueDesc::LineNumberOutOfRange(0),
+            ),
+        ]
+    );
+
+    let (_, issues) = preprocess(src, &FILENAME, &Params::default());
+    fassert_eq!(issues, vec![]);
+}
+
+#[test]
+fn predefined_macros() {
+    let src = "__STDC__ __STDC_VERSION__ __STDC_HOSTED__ __cplusplus\n";
+    let (text, issues) = preprocess(src, &FILENAME, &params());
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "1 201112L 1 __cplusplus\n");
+
+    let params = Params {
+        version: Version::Cpp(CppVersion::Cpp17),
+        hosted: false,
+        ..params()
+    };
+    let (text, _) = preprocess(src, &FILENAME, &params);
+    fassert_eq!(text, "1 __STDC_VERSION__ 0 201703L\n");
+}
+
+#[test]
+fn dynamic_macros() {
+    let params = params();
+    let mut pp = Preprocessor::new(&params);
+    pp.macros.builtins.date = "Sep  9 2001".to_string();
+    pp.macros.builtins.time = "01:46:40".to_string();
+    pp.run(
+        "__FILE__ __LINE__\n\
+         #line 10 \"x\\\\y.c\"\n\
+         __FILE__ __LINE__ __BASE_FILE__ __INCLUDE_LEVEL__\n\
+         __COUNTER__ __COUNTER__\n\
+         #if __COUNTER__ == 2 && defined __LINE__\n\
+         two\n\
+         #endif\n\
+         #define L __LINE__\n\
+         L\n\
+         __DATE__ __TIME__ __TIMESTAMP__\n",
+        &FILENAME,
+    );
+    fassert_eq!(pp.issues, vec![]);
+    fassert_eq!(
+        pp.output,
+        "\"filename\" 1\n\
+         \"x\\\\y.c\" 10 \"filename\" 0\n\
+         0 1\n\
+         two\n\
+         16\n\
+         \"Sep  9 2001\" \"01:46:40\" \"??? ??? ?? ??:??:?? ????\"\n"
+    );
+}
+
+#[test]
+fn source_date_epoch() {
+    let mut issues = vec![];
+    fassert_eq!(
+        build_time(Some("1000000000".to_string()), &mut issues),
+        1000000000
+    );
+    fassert_eq!(issues, vec![]);
+    for epoch in &["-1", "253402300800", "x"] {
+        build_time(Some(epoch.to_string()), &mut issues);
+    }
+    let invalid = |epoch: &str| {
+        Issue::new(
+            None,
+            IssueType::Error,
+            IssueDesc::InvalidSourceDateEpoch(epoch.to_string()),
+        )
+    };
+    fassert_eq!(
+        issues,
+        vec![invalid("-1"), invalid("253402300800"), invalid("x")]
+    );
+}
+
+// '-D's and '-U's are carried out in order, on the lines of '<command-line>'.
+#[test]
+fn command_line() {
+    let params = Params {
+        macro_defines: vec![
+            MacroDefine::D("A".to_string()),
+            MacroDefine::D("B=2".to_string()),
+            MacroDefine::D("f(x)=[x]".to_string()),
+            MacroDefine::U("A".to_string()),
+            MacroDefine::D("C=3\n4".to_string()),
+            MacroDefine::D("B=5".to_string()),
+        ],
+        ..params()
+    };
+    let (text, issues) =
+        preprocess("A B f(1) C\n#define C 6\n", &FILENAME, &params);
+    let command_line =
+        |nline| Some(Location::new(COMMAND_LINE.to_string(), nline, 1));
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                command_line(6),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "B".to_string(),
+                    command_line(2).map(Box::new)
+                ),
+            ),
+            Issue::new(
+                Some(Location::new(FILENAME.clone(), 2, 1)),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "C".to_string(),
+                    command_line(5).map(Box::new)
+                ),
+            ),
+        ]
+    );
+    fassert_eq!(text, "A 5 [1] 3\n");
+}
+
+// '-undef' keeps only the standard predefined macros.
+#[test]
+fn undef() {
+    let params = Params {
+        undef: true,
+        ..params()
+    };
+    let (text, issues) = preprocess(
+        "__STDC__ __LINE__ __COUNTER__ __INCLUDE_LEVEL__\n",
+        &FILENAME,
+        &params,
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "1 1 __COUNTER__ __INCLUDE_LEVEL__\n");
+}
+
+// '_Pragma's are carried out like '#pragma's, even from macros, and dropped
+// from the text.
+#[test]
+fn pragma_operator() {
+    let src = "#define P(x) _Pragma(#x)\n\
+               a _Pragma(\"once\") b\n\
+               P(push_macro) c\n\
+               _Pragma(L\"pop_macro(\\\"x\\\")\") d _Pragma(\n\
+               \"once\")\n\
+               _Pragma(1) e __pragma(once)\n";
+    let (text, issues) = preprocess(src, &FILENAME, &params());
+    let issue = |nline, nchar, itype, desc| {
+        Issue::new(
+            Some(Location::new(FILENAME.clone(), nline, nchar)),
+            itype,
+            desc,
+        )
+    };
+    fassert_eq!(
+        issues,
+        vec![
+            issue(2, 3, IssueType::Warning, IssueDesc::PragmaOnceInMainFile),
+            issue(
+                3,
+                1,
+                IssueType::Warning,
+                IssueDesc::MalformedPragma("push_macro".to_string()),
+            ),
+            issue(4, 32, IssueType::Warning, IssueDesc::PragmaOnceInMainFile),
+            issue(
+                6,
+                1,
+                IssueType::Error,
+                IssueDesc::MalformedPragmaOperator("_Pragma".to_string()),
+            ),
+        ]
+    );
+    fassert_eq!(text, "a b\n c\n d \n_Pragma(1) e __pragma(once)\n");
+
+    let params = Params {
+        ms_extensions: true,
+        ..params()
+    };
+    let (text, issues) = preprocess("a __pragma(once) b\n", &FILENAME, &params);
+    fassert_eq!(
+        issues,
+        vec![issue(
+            1,
+            3,
+            IssueType::Warning,
+            IssueDesc::PragmaOnceInMainFile
+        )]
+    );
+    fassert_eq!(text, "a b\n");
+}
+
+#[test]
+fn push_and_pop_macro() {
+    let (text, issues) = preprocess(
+        "#define X 1\n\
+         #pragma push_macro(\"X\")\n\
+         #undef X\n\
+         #pragma push_macro(\"X\")\n\
+         #define X 2\n\
+         X\n\
+         #pragma pop_macro(\"X\")\n\
+         X\n\
+         _Pragma(\"pop_macro(\\\"X\\\")\") X\n\
+         #pragma pop_macro(\"X\")\n\
+         X\n\
+         #pragma pop_macro(\"Y\")\n\
+         #define POP _Pragma(\"pop_macro(\\\"X\\\")\") X\n\
+         #pragma push_macro(\"X\")\n\
+         #undef X\n\
+         #define X 3\n\
+         X POP\n",
+        &FILENAME,
+        &params(),
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "2\nX\n 1\n1\n3 1\n");
+}
+
+// Macros defined before the pragma can still use the identifiers.
+#[test]
+fn poison() {
+    let (text, issues) = preprocess(
+        "#define X 1\n\
+         #define Y X\n\
+         #pragma GCC poison X Z\n\
+         Y X\n\
+         #ifdef Z\n\
+         #endif\n\
+         #pragma GCC poison 1\n",
+        &FILENAME,
+        &params(),
+    );
+    let error = |nline, nchar, name: &str| {
+        Issue::new(
+            Some(Location::new(FILENAME.clone(), nline, nchar)),
+            IssueType::Error,
+            IssueDesc::PoisonedIdentifier(name.to_string()),
+        )
+    };
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(Location::new(FILENAME.clone(), 3, 1)),
+                IssueType::Warning,
+                IssueDesc::PoisoningExistingMacro("X".to_string()),
+            ),
+            error(4, 3, "X"),
+            error(5, 8, "Z"),
+            Issue::new(
+                Some(Location::new(FILENAME.clone(), 7, 9)),
+                IssueType::Warning,
+                IssueDesc::MalformedPragma("poison".to_string()),
+            ),
+        ]
+    );
+    fassert_eq!(text, "X X\n");
+}
+
+// Predicates are apart from the macros.
+#[test]
+fn assertions() {
+    let no_deprecated = Params {
+        wdeprecated: false,
+        ..params()
+    };
+    let (text, issues) = preprocess(
+        "#ident \"v1\"\n\
+         #assert machine(x86)\n\
+         #assert machine(arm)\n\
+         #define machine 0\n\
+         #if #machine(x86) && #machine && !#machine(mips) && !machine\n\
+         a\n\
+         #endif\n\
+         #unassert machine(x86)\n\
+         #if #machine(x86) || !#machine (arm)\n\
+         b\n\
+         #endif\n\
+         #unassert machine\n\
+         #if #machine\n\
+         c\n\
+         #endif\n\
+         #if #cpu(\n\
+         #endif\n",
+        &FILENAME,
+        &no_deprecated,
+    );
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            Some(Location::new(FILENAME.clone(), 16, 5)),
+            IssueType::Error,
+            IssueDesc::MissingRParenInAnswer,
+        )]
+    );
+    fassert_eq!(text, "#ident \"v1\"\na\n");
+
+    // They're all GNU extensions, and '#assert', '#unassert' and assertions
+    // are deprecated.
+    let src = "#sccs \"v1\"\n\
+               #assert machine(x86)\n\
+               #if #machine\n\
+               #endif\n\
+               #unassert machine\n";
+    let warning = |nline, nchar, desc| {
+        Issue::new(
+            Some(Location::new(FILENAME.clone(), nline, nchar)),
+            IssueType::Warning,
+            desc,
+        )
+    };
+    let (_, issues) = preprocess(src, &FILENAME, &params());
+    fassert_eq!(
+        issues,
+        vec![
+            warning(
+                2,
+                1,
+                IssueDesc::DeprecatedGnuExtension("#assert".to_string())
+            ),
+            warning(
+                3,
+                5,
+                IssueDesc::DeprecatedGnuExtension("assertions".to_string())
+            ),
+            warning(
+                5,
+                1,
+                IssueDesc::DeprecatedGnuExtension("#unassert".to_string())
+            ),
+        ]
+    );
+    let params = Params {
+        pedantic: true,
+        ..params()
+    };
+    let (_, issues) = preprocess(src, &FILENAME, &params);
+    fassert_eq!(
+        issues,
+        vec![
+            warning(1, 1, IssueDesc::GnuExtension("#sccs".to_string())),
+            warning(2, 1, IssueDesc::GnuExtension("#assert".to_string())),
+            warning(3, 5, IssueDesc::GnuExtension("assertions".to_string())),
+            warning(5, 1, IssueDesc::GnuExtension("#unassert".to_string())),
+        ]
+    );
+}
+
+// The directives are carried out, but the text is left as it is.
+#[test]
+fn directives_only() {
+    let params = Params {
+        fdirectives_only: true,
+        ..params()
+    };
+    let (text, issues) = preprocess(
+        "#define X 1\n\
+         #define F(a, b)  a + b\n\
+         X F(1, 2) __LINE__\n\
+         #if X\n\
+         yes\n\
+         #endif\n\
+         #undef X\n\
+         X _Pragma(\"once\")\n",
+        &FILENAME,
+        &params,
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        text,
+        "#define X 1\n\
+         #define F(a, b) a + b\n\
+         X F(1, 2) __LINE__\n\
+         yes\n\
+         #undef X\n\
+         X _Pragma(\"once\")\n"
+    );
+}
+
+// Preprocessing stops at an error, but not at a warning.
+#[test]
+fn messages() {
+    let src = "#warning  a  \"b\"\n\
+               #pragma GCC warning (\"c\")\n\
+               #pragma GCC warning c\n\
+               #if 1\n\
+               #error d\n\
+               text\n";
+    let params = params();
+    let mut pp = Preprocessor::new(&params);
+    pp.run(src, &FILENAME);
+    let issue = |nline, itype, desc| {
+        Issue::new(Some(Location::new(FILENAME.clone(), nline, 1)), itype, desc)
+    };
+    let message =
+        |mtype, text: &str| IssueDesc::Message(mtype, text.to_string());
+    fassert_eq!(
+        pp.issues,
+        vec![
+            issue(
+                1,
+                IssueType::Warning,
+                message(MessageType::Warning, "a \"b\"")
+            ),
+            issue(
+                2,
+                IssueType::Warning,
+                message(MessageType::PragmaWarning, "c")
+            ),
+            issue(
+                3,
+                IssueType::Warning,
+                IssueDesc::MalformedPragma("warning".to_string())
+            ),
+            issue(5, IssueType::Error, message(MessageType::Error, "d")),
+        ]
+    );
+    fassert_eq!(pp.output, "");
+    assert!(pp.failed);
+
+    let params = Params {
+        werror: true,
+        ..params
+    };
+    let mut pp = Preprocessor::new(&params);
+    pp.run("#warning a\ntext\n", &FILENAME);
+    assert!(pp.failed);
+
+    // '#warning' is an extension before C2x and C++23.
+    for (version, extension) in &[
+        (Version::C(CVersion::C18), true),
+        (Version::C(CVersion::C2x), false),
+        (Version::Cpp(CppVersion::Cpp20), true),
+        (Version::Cpp(CppVersion::Cpp23), false),
+    ] {
+        let params = Params {
+            version: *version,
+            pedantic: true,
+            ..Params::default()
+        };
+        let mut pp = Preprocessor::new(&params);
+        pp.run("#warning a\n", &FILENAME);
+        fassert_eq!(
+            pp.issues
+                .iter()
+                .any(|i| i.desc
+                    == IssueDesc::GnuExtension("#warning".to_string())),
+            *extension,
+            "{:?}",
+            version
+        );
+    }
+}
@@ -0,0 +1,877 @@
+//! Evaluates the constant expressions of '#if' and '#elif', section 6.10.1,
+//! C11 spec.
+//!
+//! All arithmetic is done in `intmax_t` or `uintmax_t`, which are 64 bits
+//! wide for every target we care about.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{
+    CharConst, CharConstPrefix, ConstExpr, Identifer, PPNumber, PPToken, Punct,
+    Span, SpannedPPToken,
+};
+use crate::common::{
+    CVersion, CppVersion, Issue, IssueDesc, IssueType, Params, Version,
+};
+use crate::directive::LocMapping;
+use crate::expand::Expander;
+use crate::macros::MacroTable;
+
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Signed(i64),
+    Unsigned(u64),
+}
+
+impl Value {
+    fn is_true(self) -> bool {
+        self != Value::Signed(0) && self != Value::Unsigned(0)
+    }
+
+    fn from_bool(b: bool) -> Self {
+        Value::Signed(b as i64)
+    }
+
+    fn as_u64(self) -> u64 {
+        match self {
+            Value::Signed(v) => v as u64,
+            Value::Unsigned(v) => v,
+        }
+    }
+
+    // Section 6.3.1.8, C11 spec
+    //
+    // As both are the same width, if either is unsigned, both are.
+    fn convert(self, other: Self) -> (Self, Self) {
+        match (self, other) {
+            (Value::Signed(_), Value::Signed(_)) => (self, other),
+            _ => (
+                Value::Unsigned(self.as_u64()),
+                Value::Unsigned(other.as_u64()),
+            ),
+        }
+    }
+}
+
+// GNU attributes gcc knows about, for '__has_attribute'.
+const GNU_ATTRIBUTES: &[&str] = &[
+    "access",
+    "alias",
+    "aligned",
+    "alloc_align",
+    "alloc_size",
+    "always_inline",
+    "artificial",
+    "cleanup",
+    "cold",
+    "common",
+    "const",
+    "constructor",
+    "copy",
+    "deprecated",
+    "designated_init",
+    "destructor",
+    "error",
+    "externally_visible",
+    "fallthrough",
+    "flatten",
+    "format",
+    "format_arg",
+    "gnu_inline",
+    "hot",
+    "ifunc",
+    "leaf",
+    "malloc",
+    "may_alias",
+    "mode",
+    "no_instrument_function",
+    "no_reorder",
+    "no_sanitize",
+    "no_sanitize_address",
+    "no_split_stack",
+    "no_stack_limit",
+    "noclone",
+    "nocommon",
+    "noinit",
+    "noinline",
+    "noipa",
+    "nonnull",
+    "nonstring",
+    "noplt",
+    "noreturn",
+    "nothrow",
+    "optimize",
+    "packed",
+    "patchable_function_entry",
+    "pure",
+    "returns_nonnull",
+    "returns_twice",
+    "scalar_storage_order",
+    "section",
+    "sentinel",
+    "simd",
+    "target",
+    "target_clones",
+    "tls_model",
+    "transparent_union",
+    "unavailable",
+    "unused",
+    "used",
+    "vector_size",
+    "visibility",
+    "warn_if_not_aligned",
+    "warn_unused_result",
+    "warning",
+    "weak",
+    "weakref",
+];
+
+// Standard C++ attributes, and the value of '__has_cpp_attribute' for each.
+const CPP_ATTRIBUTES: &[(&str, CppVersion, i64)] = &[
+    ("carries_dependency", CppVersion::Cpp11, 200_809),
+    ("deprecated", CppVersion::Cpp14, 201_309),
+    ("fallthrough", CppVersion::Cpp17, 201_603),
+    ("likely", CppVersion::Cpp20, 201_803),
+    ("maybe_unused", CppVersion::Cpp17, 201_603),
+    ("no_unique_address", CppVersion::Cpp20, 201_803),
+    ("nodiscard", CppVersion::Cpp17, 201_907),
+    ("noreturn", CppVersion::Cpp11, 200_809),
+    ("unlikely", CppVersion::Cpp20, 201_803),
+];
+
+// 'name' and '__name__' are the same attribute.
+fn attribute_name(name: &str) -> &str {
+    if name.len() > 4 && name.starts_with("__") && name.ends_with("__") {
+        &name[2..name.len() - 2]
+    } else {
+        name
+    }
+}
+
+// The value of '__has_attribute' or '__has_cpp_attribute' for
+// '[scope::]name'.
+fn attribute_value(scope: Option<&str>, name: &str, params: &Params) -> i64 {
+    let name = attribute_name(name);
+    match scope.map(attribute_name) {
+        Some("gnu") => GNU_ATTRIBUTES.contains(&name) as i64,
+        Some(_) => 0,
+        None => {
+            let std = CPP_ATTRIBUTES.iter().find(|(a, v, _)| {
+                *a == name && params.version.ver_ge(CVersion::Max, *v)
+            });
+            match std {
+                Some((_, _, value)) => *value,
+                None => GNU_ATTRIBUTES.contains(&name) as i64,
+            }
+        }
+    }
+}
+
+// Whether `name` is '__has_attribute', or in C++ '__has_cpp_attribute'.
+fn is_attribute_operator(name: &str, params: &Params) -> bool {
+    let cpp = matches!(params.version, Version::Cpp(_));
+    name == "__has_attribute" || (cpp && name == "__has_cpp_attribute")
+}
+
+// Whether 'defined name' is true. Like gcc, the operators that are only
+// allowed in '#if' are defined, so their support can be checked for.
+pub fn is_defined(
+    macros: &MacroTable,
+    name: &Identifer,
+    params: &Params,
+) -> bool {
+    macros.is_defined(name) || is_attribute_operator(&name.0, params)
+}
+
+fn ident(tok: &PPToken) -> Option<&str> {
+    match tok {
+        PPToken::Identifer(Identifer(i)) => Some(i),
+        _ => None,
+    }
+}
+
+struct Evaluator<'a> {
+    macros: &'a MacroTable,
+    params: &'a Params,
+    loc_mapping: &'a LocMapping,
+    issues: &'a mut Vec<Issue>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn issue(&self, span: Span, itype: IssueType, desc: IssueDesc) -> Issue {
+        Issue::new(span.location(self.loc_mapping), itype, desc)
+    }
+
+    fn warn(&mut self, span: Span, desc: IssueDesc) {
+        let issue = self.issue(span, IssueType::Warning, desc);
+        self.issues.push(issue);
+    }
+
+    fn error(&self, span: Span, desc: IssueDesc) -> Issue {
+        self.issue(span, IssueType::Error, desc)
+    }
+
+    // Like gcc, -pedantic's warning is given instead of -Wdeprecated's.
+    fn assertion_warning(&mut self, span: Span) {
+        let name = "assertions".to_string();
+        if self.params.pedantic {
+            self.warn(span, IssueDesc::GnuExtension(name));
+        } else if self.params.wdeprecated {
+            self.warn(span, IssueDesc::DeprecatedGnuExtension(name));
+        }
+    }
+
+    // Replaces 'defined name', 'defined ( name )', '__has_attribute ( attr )'
+    // and '__has_cpp_attribute ( attr )', and the GNU assertions '#predicate'
+    // and '#predicate ( answer )', with their values, so that the operands
+    // are not macro expanded.
+    fn replace_operators(
+        &mut self,
+        toks: Vec<SpannedPPToken>,
+    ) -> Result<Vec<SpannedPPToken>, Issue> {
+        let mut ret = Vec::with_capacity(toks.len());
+        let mut toks = toks
+            .into_iter()
+            .filter(|t| t.0 != PPToken::Space)
+            .peekable();
+        while let Some(t) = toks.next() {
+            let span = t.1;
+            let value = match ident(&t.0) {
+                Some("defined") => {
+                    let name = match toks.next() {
+                        Some(SpannedPPToken(
+                            PPToken::Punct(Punct::LParen),
+                            _,
+                        )) => {
+                            let name = toks.next();
+                            match toks.next() {
+                                Some(t) if t.0.is_punct(&Punct::RParen) => name,
+                                _ => None,
+                            }
+                        }
+                        name => name,
+                    };
+                    match name {
+                        Some(SpannedPPToken(PPToken::Identifer(i), _)) => {
+                            is_defined(self.macros, &i, self.params) as i64
+                        }
+                        _ => {
+                            return Err(self.error(
+                                span,
+                                IssueDesc::DefinedWithoutIdentifier,
+                            ))
+                        }
+                    }
+                }
+                Some(op) if is_attribute_operator(op, self.params) => {
+                    let mut attr = vec![];
+                    let lparen = toks.next();
+                    if lparen
+                        .as_ref()
+                        .is_some_and(|t| t.0.is_punct(&Punct::LParen))
+                    {
+                        for t in toks.by_ref() {
+                            if t.0.is_punct(&Punct::RParen) {
+                                break;
+                            }
+                            attr.push(t.0);
+                        }
+                    }
+                    match attr.as_slice() {
+                        [PPToken::Identifer(Identifer(name))] => {
+                            attribute_value(None, name, self.params)
+                        }
+                        // '::' is only a single token in C++.
+                        [PPToken::Identifer(Identifer(scope)), PPToken::Punct(Punct::DoubleColon), PPToken::Identifer(Identifer(name))]
+                        | [PPToken::Identifer(Identifer(scope)), PPToken::Punct(Punct::Colon), PPToken::Punct(Punct::Colon), PPToken::Identifer(Identifer(name))] => {
+                            attribute_value(Some(scope), name, self.params)
+                        }
+                        _ => {
+                            return Err(self.error(
+                                span,
+                                IssueDesc::MissingTokenInIf(
+                                    "attribute name".to_string(),
+                                ),
+                            ))
+                        }
+                    }
+                }
+                None if t.0.is_punct(&Punct::Hash) => {
+                    let pred = match toks.next() {
+                        Some(SpannedPPToken(PPToken::Identifer(i), _)) => i,
+                        _ => {
+                            return Err(self.error(
+                                span,
+                                IssueDesc::PredicateMustBeIdentifier,
+                            ))
+                        }
+                    };
+                    // Like '#assert', the answer ends at the first ')'.
+                    let mut answer = None;
+                    if toks.next_if(|t| t.0.is_punct(&Punct::LParen)).is_some()
+                    {
+                        let mut a = vec![];
+                        loop {
+                            match toks.next() {
+                                Some(t) if t.0.is_punct(&Punct::RParen) => {
+                                    break
+                                }
+                                Some(t) => a.push(t.0),
+                                None => {
+                                    return Err(self.error(
+                                        span,
+                                        IssueDesc::MissingRParenInAnswer,
+                                    ))
+                                }
+                            }
+                        }
+                        if a.is_empty() {
+                            return Err(
+                                self.error(span, IssueDesc::EmptyAnswer)
+                            );
+                        }
+                        answer = Some(a);
+                    }
+                    self.assertion_warning(span);
+                    self.macros.is_asserted(&pred, answer.as_deref()) as i64
+                }
+                _ => {
+                    ret.push(t);
+                    continue;
+                }
+            };
+            ret.push(SpannedPPToken(
+                PPToken::PPNumber(PPNumber(value.to_string())),
+                span,
+            ));
+        }
+        Ok(ret)
+    }
+}
+
+// Evaluates the constant expression of an '#if' or '#elif'. Warnings are
+// added to `issues`, and the first error is returned.
+pub fn eval(
+    expr: &ConstExpr,
+    macros: &MacroTable,
+    params: &Params,
+    loc_mapping: &LocMapping,
+    issues: &mut Vec<Issue>,
+) -> Result<bool, Issue> {
+    let mut ev = Evaluator {
+        macros,
+        params,
+        loc_mapping,
+        issues,
+    };
+    let toks = ev.replace_operators(expr.0.clone())?;
+    let toks = Expander::new(macros, params, loc_mapping, ev.issues)
+        .in_if()
+        .expand(toks);
+    // Macros that expand to 'defined' are undefined behaviour, but gcc
+    // evaluates them anyway, without expanding the operand.
+    let toks = ev.replace_operators(toks)?;
+
+    // '#if' always has tokens, but they can expand to nothing.
+    let end = match (toks.last(), expr.0.first()) {
+        (Some(t), _) => t.1,
+        (None, span) => {
+            return Err(Issue::new(
+                span.and_then(|t| t.1.location(loc_mapping)),
+                IssueType::Error,
+                IssueDesc::MissingTokenInIf("expression".to_string()),
+            ));
+        }
+    };
+    let mut parser = Parser {
+        ev,
+        toks,
+        pos: 0,
+        end,
+    };
+    let value = parser.comma(true)?;
+    match parser.toks.get(parser.pos) {
+        Some(t) => Err(parser
+            .ev
+            .error(t.1, IssueDesc::InvalidTokenInIf(t.0.to_string()))),
+        None => Ok(value.is_true()),
+    }
+}
+
+struct Parser<'a> {
+    ev: Evaluator<'a>,
+    toks: Vec<SpannedPPToken>,
+    pos: usize,
+    // The span of the last token, for errors at the end of the expression.
+    end: Span,
+}
+
+// Binary operators, by increasing precedence.
+fn precedence(punct: &Punct) -> Option<u8> {
+    Some(match punct {
+        Punct::DoublePipe => 1,
+        Punct::DoubleAmp => 2,
+        Punct::Pipe => 3,
+        Punct::Pow => 4,
+        Punct::Amp => 5,
+        Punct::DoubleEq | Punct::NotEq => 6,
+        Punct::LAngleBrk
+        | Punct::RAngleBrk
+        | Punct::LAngleBrkEq
+        | Punct::RAngleBrkEq => 7,
+        Punct::LDoubleAngleBrk | Punct::RDoubleAngleBrk => 8,
+        Punct::Add | Punct::Sub => 9,
+        Punct::Mul | Punct::Div | Punct::Mod => 10,
+        _ => return None,
+    })
+}
+
+impl<'a> Parser<'a> {
+    fn peek_punct(&self) -> Option<Punct> {
+        self.toks.get(self.pos).and_then(|t| t.0.punct().cloned())
+    }
+
+    fn span(&self) -> Span {
+        self.toks.get(self.pos).map_or(self.end, |t| t.1)
+    }
+
+    fn expect(&mut self, punct: Punct) -> Result<(), Issue> {
+        if self.peek_punct() == Some(punct.clone()) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.ev.error(
+                self.span(),
+                IssueDesc::MissingTokenInIf(punct.as_str().to_string()),
+            ))
+        }
+    }
+
+    // The comma operator is only allowed where it isn't evaluated, but gcc
+    // only complains with -pedantic.
+    fn comma(&mut self, eval: bool) -> Result<Value, Issue> {
+        let mut value = self.conditional(eval)?;
+        while self.peek_punct() == Some(Punct::Comma) {
+            let span = self.span();
+            self.pos += 1;
+            if eval && self.ev.params.pedantic {
+                self.ev.warn(span, IssueDesc::CommaInIf);
+            }
+            value = self.conditional(eval)?;
+        }
+        Ok(value)
+    }
+
+    fn conditional(&mut self, eval: bool) -> Result<Value, Issue> {
+        let cond = self.binary(1, eval)?;
+        if self.peek_punct() != Some(Punct::Quest) {
+            return Ok(cond);
+        }
+        self.pos += 1;
+        let a = self.comma(eval && cond.is_true())?;
+        self.expect(Punct::Colon)?;
+        let b = self.conditional(eval && !cond.is_true())?;
+        let (a, b) = a.convert(b);
+        Ok(if cond.is_true() { a } else { b })
+    }
+
+    fn binary(&mut self, min_prec: u8, eval: bool) -> Result<Value, Issue> {
+        let mut lhs = self.unary(eval)?;
+        while let Some(op) = self.peek_punct() {
+            let prec = match precedence(&op) {
+                Some(prec) if prec >= min_prec => prec,
+                _ => break,
+            };
+            let span = self.span();
+            self.pos += 1;
+
+            let rhs_eval = match op {
+                Punct::DoubleAmp => eval && lhs.is_true(),
+                Punct::DoublePipe => eval && !lhs.is_true(),
+                _ => eval,
+            };
+            let rhs = self.binary(prec + 1, rhs_eval)?;
+            lhs = self.apply(&op, lhs, rhs, eval, span)?;
+        }
+        Ok(lhs)
+    }
+
+    fn overflow(&mut self, eval: bool, span: Span) {
+        if eval {
+            self.ev.warn(span, IssueDesc::IntegerOverflowInIf);
+        }
+    }
+
+    fn apply(
+        &mut self,
+        op: &Punct,
+        lhs: Value,
+        rhs: Value,
+        eval: bool,
+        span: Span,
+    ) -> Result<Value, Issue> {
+        use Value::{Signed, Unsigned};
+
+        match op {
+            Punct::DoubleAmp => {
+                return Ok(Value::from_bool(lhs.is_true() && rhs.is_true()))
+            }
+            Punct::DoublePipe => {
+                return Ok(Value::from_bool(lhs.is_true() || rhs.is_true()))
+            }
+            // The result has the type of the left operand.
+            Punct::LDoubleAngleBrk | Punct::RDoubleAngleBrk => {
+                let n = match rhs {
+                    Signed(n) => i128::from(n),
+                    Unsigned(n) => i128::from(n),
+                };
+                let n = if *op == Punct::LDoubleAngleBrk { n } else { -n };
+                return Ok(self.shift(lhs, n, eval, span));
+            }
+            _ => (),
+        }
+
+        let (lhs, rhs) = lhs.convert(rhs);
+        let zero = rhs == Signed(0) || rhs == Unsigned(0);
+        if zero && (*op == Punct::Div || *op == Punct::Mod) {
+            if eval {
+                return Err(self.ev.error(span, IssueDesc::DivisionByZeroInIf));
+            }
+            return Ok(lhs);
+        }
+
+        Ok(match (lhs, rhs) {
+            (Signed(a), Signed(b)) => {
+                let checked = match op {
+                    Punct::Mul => a.checked_mul(b),
+                    Punct::Div => a.checked_div(b),
+                    Punct::Mod => a.checked_rem(b),
+                    Punct::Add => a.checked_add(b),
+                    Punct::Sub => a.checked_sub(b),
+                    Punct::Amp => Some(a & b),
+                    Punct::Pow => Some(a ^ b),
+                    Punct::Pipe => Some(a | b),
+                    _ => return Ok(compare(op, a, b)),
+                };
+                match checked {
+                    Some(v) => Signed(v),
+                    None => {
+                        self.overflow(eval, span);
+                        Signed(match op {
+                            Punct::Mul => a.wrapping_mul(b),
+                            Punct::Div => a.wrapping_div(b),
+                            Punct::Mod => a.wrapping_rem(b),
+                            Punct::Add => a.wrapping_add(b),
+                            _ => a.wrapping_sub(b),
+                        })
+                    }
+                }
+            }
+            (a, b) => {
+                let (a, b) = (a.as_u64(), b.as_u64());
+                Unsigned(match op {
+                    Punct::Mul => a.wrapping_mul(b),
+                    Punct::Div => a / b,
+                    Punct::Mod => a % b,
+                    Punct::Add => a.wrapping_add(b),
+                    Punct::Sub => a.wrapping_sub(b),
+                    Punct::Amp => a & b,
+                    Punct::Pow => a ^ b,
+                    Punct::Pipe => a | b,
+                    _ => return Ok(compare(op, a, b)),
+                })
+            }
+        })
+    }
+
+    // Shifts left by `n`, or right if `n` is negative, like gcc does.
+    fn shift(&mut self, lhs: Value, n: i128, eval: bool, span: Span) -> Value {
+        match lhs {
+            Value::Signed(a) => {
+                if n >= 0 {
+                    let v = if n >= 64 { 0 } else { i128::from(a) << n };
+                    if n >= 64 && a != 0 || i64::try_from(v).is_err() {
+                        self.overflow(eval, span);
+                    }
+                    Value::Signed(v as i64)
+                } else if n <= -64 {
+                    Value::Signed(if a < 0 { -1 } else { 0 })
+                } else {
+                    Value::Signed(a >> -n)
+                }
+            }
+            Value::Unsigned(a) => Value::Unsigned(match n {
+                n if n >= 64 || n <= -64 => 0,
+                n if n >= 0 => a << n,
+                n => a >> -n,
+            }),
+        }
+    }
+
+    fn unary(&mut self, eval: bool) -> Result<Value, Issue> {
+        let t = match self.toks.get(self.pos) {
+            Some(t) => t.clone(),
+            None => {
+                return Err(self.ev.error(
+                    self.end,
+                    IssueDesc::MissingTokenInIf("expression".to_string()),
+                ))
+            }
+        };
+        self.pos += 1;
+
+        Ok(match &t.0 {
+            PPToken::Punct(Punct::LParen) => {
+                let value = self.comma(eval)?;
+                self.expect(Punct::RParen)?;
+                value
+            }
+            PPToken::Punct(Punct::Add) => self.unary(eval)?,
+            PPToken::Punct(Punct::Sub) => match self.unary(eval)? {
+                Value::Signed(v) => Value::Signed(match v.checked_neg() {
+                    Some(v) => v,
+                    None => {
+                        self.overflow(eval, t.1);
+                        v
+                    }
+                }),
+                Value::Unsigned(v) => Value::Unsigned(v.wrapping_neg()),
+            },
+            PPToken::Punct(Punct::Comp) => match self.unary(eval)? {
+                Value::Signed(v) => Value::Signed(!v),
+                Value::Unsigned(v) => Value::Unsigned(!v),
+            },
+            PPToken::Punct(Punct::Not) => {
+                Value::from_bool(!self.unary(eval)?.is_true())
+            }
+            PPToken::PPNumber(PPNumber(n)) => {
+                let (value, warning) = parse_number(n, self.ev.params)
+                    .map_err(|desc| self.ev.error(t.1, desc))?;
+                if let Some(desc) = warning {
+                    self.ev.warn(t.1, desc);
+                }
+                value
+            }
+            PPToken::CharConst(c) => {
+                let (value, warning) = parse_char_const(c)
+                    .map_err(|desc| self.ev.error(t.1, desc))?;
+                if let Some(desc) = warning {
+                    self.ev.warn(t.1, desc);
+                }
+                value
+            }
+            PPToken::Identifer(Identifer(i)) => {
+                let cpp = matches!(self.ev.params.version, Version::Cpp(_));
+                match i.as_str() {
+                    "true" if cpp => Value::Signed(1),
+                    "false" if cpp => Value::Signed(0),
+                    _ => {
+                        // Like gcc, only where it's evaluated.
+                        if eval && self.ev.params.wundef {
+                            self.ev.warn(
+                                t.1,
+                                IssueDesc::UndefinedIdentifierInIf(i.clone()),
+                            );
+                        }
+                        Value::Signed(0)
+                    }
+                }
+            }
+            tok => {
+                return Err(self
+                    .ev
+                    .error(t.1, IssueDesc::InvalidTokenInIf(tok.to_string())))
+            }
+        })
+    }
+}
+
+fn compare<T: PartialOrd>(op: &Punct, a: T, b: T) -> Value {
+    Value::from_bool(match op {
+        Punct::DoubleEq => a == b,
+        Punct::NotEq => a != b,
+        Punct::LAngleBrk => a < b,
+        Punct::RAngleBrk => a > b,
+        Punct::LAngleBrkEq => a <= b,
+        _ => a >= b,
+    })
+}
+
+// Section 6.4.4.1, C11 spec
+//
+// Returns the value, and possibly a warning.
+pub fn parse_number(
+    n: &str,
+    params: &Params,
+) -> Result<(Value, Option<IssueDesc>), IssueDesc> {
+    let invalid = || IssueDesc::InvalidConstantInIf(n.to_string());
+    let digits: String = n.chars().filter(|c| *c != '\'').collect();
+
+    let (radix, body) = match digits.get(..2) {
+        Some("0x") | Some("0X") => (16, &digits[2..]),
+        Some("0b") | Some("0B") => (2, &digits[2..]),
+        _ if digits.starts_with('0') => (8, &digits[..]),
+        _ => (10, &digits[..]),
+    };
+    let end = body
+        .find(|c: char| !c.is_digit(radix.max(10)))
+        .unwrap_or(body.len());
+    let (body, suffix) = body.split_at(end);
+    if body.is_empty() && radix != 8 {
+        return Err(invalid());
+    }
+
+    let unsigned = match suffix {
+        "" | "l" | "L" | "ll" | "LL" => false,
+        "u" | "U" | "ul" | "uL" | "Ul" | "UL" | "lu" | "lU" | "Lu" | "LU"
+        | "ull" | "uLL" | "Ull" | "ULL" | "llu" | "llU" | "LLu" | "LLU" => true,
+        // Floating constants end up here too.
+        _ => return Err(invalid()),
+    };
+
+    let mut value: u64 = 0;
+    for c in body.chars() {
+        let d = c.to_digit(radix).ok_or_else(invalid)?;
+        value = value
+            .checked_mul(u64::from(radix))
+            .and_then(|v| v.checked_add(u64::from(d)))
+            .ok_or_else(|| IssueDesc::IntegerTooLargeInIf(n.to_string()))?;
+    }
+
+    let mut warning = None;
+    if radix == 2
+        && params.pedantic
+        && params.version.ver_ls(CVersion::C2x, CppVersion::Cpp14)
+    {
+        warning = Some(IssueDesc::GnuExtension("binary constants".to_string()));
+    }
+
+    // Constants too big for 'intmax_t' are unsigned, which is only allowed
+    // without a suffix for octal and hexadecimal constants.
+    let value = if unsigned || value > i64::MAX as u64 {
+        if !unsigned && radix == 10 {
+            warning = Some(IssueDesc::LargeIntegerIsUnsigned(n.to_string()));
+        }
+        Value::Unsigned(value)
+    } else {
+        Value::Signed(value as i64)
+    };
+    Ok((value, warning))
+}
+
+// Decodes the characters of a char constant. `true` means the character is
+// a byte written with an octal or hexadecimal escape, rather than a code
+// point.
+fn chars(s: &str) -> Vec<(u32, bool)> {
+    let mut ret = vec![];
+    let mut it = s.chars().peekable();
+    while let Some(c) = it.next() {
+        if c != '\\' {
+            ret.push((c as u32, false));
+            continue;
+        }
+        let c = match it.next() {
+            Some(c) => c,
+            None => break,
+        };
+        let simple = match c {
+            'a' => Some(7),
+            'b' => Some(8),
+            'f' => Some(12),
+            'n' => Some(10),
+            'r' => Some(13),
+            't' => Some(9),
+            'v' => Some(11),
+            // GNU extension.
+            'e' | 'E' => Some(27),
+            _ => None,
+        };
+        if let Some(v) = simple {
+            ret.push((v, false));
+            continue;
+        }
+
+        let (radix, max, byte) = match c {
+            '0'..='7' => (8, 3, true),
+            'x' => (16, usize::MAX, true),
+            'u' => (16, 4, false),
+            'U' => (16, 8, false),
+            // '\'', '\"', '\?', '\\', and unknown escapes.
+            c => {
+                ret.push((c as u32, false));
+                continue;
+            }
+        };
+        let mut v: u32 = 0;
+        let mut n = 0;
+        if radix == 8 {
+            v = c.to_digit(8).unwrap();
+            n = 1;
+        }
+        while n < max {
+            match it.peek().and_then(|c| c.to_digit(radix)) {
+                Some(d) => v = v.wrapping_mul(radix).wrapping_add(d),
+                None => break,
+            }
+            it.next();
+            n += 1;
+        }
+        ret.push((v, byte));
+    }
+    ret
+}
+
+// Section 6.4.4.4, C11 spec
+//
+// 'char' is signed and 8 bits, 'wchar_t' is a signed 32 bits, and the other
+// character types are unsigned, like gcc on x86. Multi-character constants
+// have the value gcc gives them, with a warning.
+pub fn parse_char_const(
+    CharConst(prefix, s): &CharConst,
+) -> Result<(Value, Option<IssueDesc>), IssueDesc> {
+    let spelling = || PPToken::CharConst(CharConst(prefix.clone(), s.clone()));
+    let chars = chars(s);
+    if chars.is_empty() {
+        return Err(IssueDesc::InvalidConstantInIf(spelling().to_string()));
+    }
+    let multi = || Some(IssueDesc::MultiCharConstant(spelling().to_string()));
+
+    Ok(match prefix {
+        CharConstPrefix::None => {
+            let mut bytes = vec![];
+            for (c, byte) in chars {
+                match std::char::from_u32(c) {
+                    Some(c) if !byte => {
+                        let mut buf = [0; 4];
+                        bytes.extend(c.encode_utf8(&mut buf).bytes());
+                    }
+                    _ => bytes.push(c as u8),
+                }
+            }
+            let warning = if bytes.len() > 1 { multi() } else { None };
+            let value = if bytes.len() == 1 {
+                i64::from(bytes[0] as i8)
+            } else {
+                let v = bytes
+                    .iter()
+                    .fold(0u32, |v, b| v.wrapping_shl(8) | u32::from(*b));
+                i64::from(v as i32)
+            };
+            (Value::Signed(value), warning)
+        }
+        _ => {
+            let warning = if chars.len() > 1 { multi() } else { None };
+            let c = chars.last().unwrap().0;
+            let value = match prefix {
+                CharConstPrefix::L => Value::Signed(i64::from(c as i32)),
+                CharConstPrefix::u => Value::Unsigned(u64::from(c as u16)),
+                CharConstPrefix::u8 => Value::Unsigned(u64::from(c as u8)),
+                _ => Value::Unsigned(u64::from(c)),
+            };
+            (value, warning)
+        }
+    })
+}
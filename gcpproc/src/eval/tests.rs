@@ -0,0 +1,394 @@
+use super::*;
+use crate::a::Token;
+use crate::common::Location;
+use crate::directive;
+use crate::p1to3::preproc_phases_1_to_3;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+// Evaluates every '#if' in `src`, after the '#define's before it.
+fn eval_src(
+    src: &str,
+    params: &Params,
+) -> (Vec<Result<bool, Issue>>, Vec<Issue>) {
+    let output = preproc_phases_1_to_3(src, &FILENAME, params);
+    let (tokens, mut issues) = directive::parse(&output, params);
+    let mut macros = MacroTable::new();
+    let mut values = vec![];
+    for (token, span) in &tokens {
+        match token {
+            Token::If(expr) => values.push(eval(
+                expr,
+                &macros,
+                params,
+                &output.loc_mapping,
+                &mut issues,
+            )),
+            token => {
+                let loc = span.location(&output.loc_mapping);
+                assert!(macros.handle_token(token, loc, params, &mut issues));
+            }
+        }
+    }
+    (values, issues)
+}
+
+fn params(version: Version) -> Params {
+    Params {
+        version,
+        ..Params::default()
+    }
+}
+
+// Every '#if' in `src` should be true, without any issues.
+fn assert_true(src: &str, params: &Params) {
+    let (values, issues) = eval_src(src, params);
+    fassert_eq!(issues, vec![]);
+    for (i, v) in values.into_iter().enumerate() {
+        fassert_eq!(v, Ok(true), "#if number {}", i + 1);
+    }
+}
+
+#[test]
+fn arithmetic() {
+    assert_true(
+        "#if 1 + 2 * 3 == 7 && (1 + 2) * 3 == 9\n\
+         #if 10 / 3 == 3 && 10 % 3 == 1 && -7 / 2 == -3 && -7 % 2 == -1\n\
+         #if (1 - 2) < 0 && 5 - 7 == -2 && - -1 == +1\n\
+         #if ~0 == -1 && !0 == 1 && !5 == 0\n\
+         #if (6 & 3) == 2 && (6 | 3) == 7 && (6 ^ 3) == 5\n\
+         #if 1 << 4 == 16 && -16 >> 2 == -4 && 1 >> -1 == 2\n\
+         #if 1 < 2 && 2 > 1 && 1 <= 1 && 1 >= 1 && 1 != 2\n\
+         #if 0x7fffffffffffffff == 9223372036854775807\n\
+         #if 010 == 8 && 0x10 == 16 && 0XaBc == 2748 && 0 == 00\n\
+         #if 1L == 1 && 1ll == 1 && 1uLL == 1 && 1LLU == 1\n",
+        &Params::default(),
+    );
+}
+
+#[test]
+fn unsigned_conversions() {
+    assert_true(
+        "#if -1 > 0u && -1 > 0U && ~0u == 0xffffffffffffffff\n\
+         #if 0xffffffffffffffff == -1 && 0x8000000000000000 > 0\n\
+         #if (1 ? -1 : 0u) > 0 && (0 ? 0u : -1) > 0\n\
+         #if 0u - 1 == 18446744073709551615u\n\
+         #if -1u >> 63 == 1 && -1 >> 63 == -1 && -1u << 64 == 0\n",
+        &Params::default(),
+    );
+
+    let (values, issues) =
+        eval_src("#if 18446744073709551615 == -1\n", &Params::default());
+    fassert_eq!(values, vec![Ok(true)]);
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            Some(location!(1, 5)),
+            IssueType::Warning,
+            IssueDesc::LargeIntegerIsUnsigned(
+                "18446744073709551615".to_string()
+            ),
+        )]
+    );
+}
+
+#[test]
+fn overflow() {
+    let (values, issues) = eval_src(
+        "#if 0x7fffffffffffffff + 1 < 0\n\
+         #if 0 && 0x7fffffffffffffff * 2\n\
+         #if -0x7fffffffffffffff - 1 == -0x7fffffffffffffff - 1\n\
+         #if 1 << 63 < 0\n",
+        &Params::default(),
+    );
+    fassert_eq!(values, vec![Ok(true), Ok(false), Ok(true), Ok(true)]);
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(1, 24)),
+                IssueType::Warning,
+                IssueDesc::IntegerOverflowInIf,
+            ),
+            Issue::new(
+                Some(location!(4, 7)),
+                IssueType::Warning,
+                IssueDesc::IntegerOverflowInIf,
+            ),
+        ]
+    );
+}
+
+#[test]
+fn short_circuit() {
+    assert_true(
+        "#if !(0 && 1 / 0) && (1 || 1 / 0) && (0 ? 1 % 0 : 2)\n\
+         #if 1 ? 2 : 1 / 0\n\
+         #if (0 && (1, 1 / 0)) == 0\n",
+        &Params::default(),
+    );
+
+    let (values, issues) = eval_src(
+        "#if 1 / 0\n\
+         #if 1 && 2 % (1 - 1)\n",
+        &Params::default(),
+    );
+    fassert_eq!(
+        values,
+        vec![
+            Err(Issue::new(
+                Some(location!(1, 7)),
+                IssueType::Error,
+                IssueDesc::DivisionByZeroInIf,
+            )),
+            Err(Issue::new(
+                Some(location!(2, 12)),
+                IssueType::Error,
+                IssueDesc::DivisionByZeroInIf,
+            )),
+        ]
+    );
+    fassert_eq!(issues, vec![]);
+}
+
+#[test]
+fn defined() {
+    assert_true(
+        "#define A\n\
+         #define B A\n\
+         #define D defined(A)\n\
+         #if defined A && defined(B) && defined ( A ) && !defined C\n\
+         #if D && (defined B) == 1\n\
+         #undef A\n\
+         #if !defined A && defined B\n",
+        &Params::default(),
+    );
+
+    let (values, _) = eval_src(
+        "#if defined\n#if defined(A\n#if defined 1\n",
+        &Params::default(),
+    );
+    let err = |nline| {
+        Err(Issue::new(
+            Some(location!(nline, 5)),
+            IssueType::Error,
+            IssueDesc::DefinedWithoutIdentifier,
+        ))
+    };
+    fassert_eq!(values, vec![err(1), err(2), err(3)]);
+}
+
+#[test]
+fn identifiers() {
+    assert_true(
+        "#define ONE 1\n\
+         #define F(x) (x + 1)\n\
+         #if UNDEFINED == 0 && ONE && F(ONE) == 2 && !true\n",
+        &Params::default(),
+    );
+    assert_true(
+        "#if true && !false\n",
+        &params(Version::Cpp(CppVersion::Cpp11)),
+    );
+
+    let wundef = Params {
+        wundef: true,
+        ..Params::default()
+    };
+    let (values, issues) = eval_src("#if A || B\n", &wundef);
+    fassert_eq!(values, vec![Ok(false)]);
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(1, 5)),
+                IssueType::Warning,
+                IssueDesc::UndefinedIdentifierInIf("A".to_string()),
+            ),
+            Issue::new(
+                Some(location!(1, 10)),
+                IssueType::Warning,
+                IssueDesc::UndefinedIdentifierInIf("B".to_string()),
+            ),
+        ]
+    );
+
+    // But not where they aren't evaluated.
+    let (values, issues) = eval_src(
+        "#if defined(A) && A > 2
+         #if 0 && B
+         #if 1 || C
+         #if 1 ? 2 : D
+         #if 0 ? E : 0
+",
+        &wundef,
+    );
+    fassert_eq!(
+        values,
+        vec![Ok(false), Ok(false), Ok(true), Ok(true), Ok(false)]
+    );
+    fassert_eq!(issues, vec![]);
+}
+
+#[test]
+fn char_consts() {
+    assert_true(
+        r"#if 'a' == 97 && '\n' == 10 && '\0' == 0 && '\x41' == 'A'
+#if '\377' < 0 && '\xff' == -1 && '\'' == 39 && '\e' == 27 && '\?' == 63
+#if L'\xff' == 255 && L'a' == 97 && U'\U0001F600' == 0x1F600
+#if u'\xffff' == 65535 && u'a' - 98 > 0
+",
+        &Params::default(),
+    );
+    assert_true(
+        "#if u8'a' == 97\n",
+        &params(Version::Cpp(CppVersion::Cpp17)),
+    );
+
+    let (values, issues) =
+        eval_src("#if 'ab' == 24930 && '\\xe9' != 'é'\n", &Params::default());
+    fassert_eq!(values, vec![Ok(true)]);
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(1, 5)),
+                IssueType::Warning,
+                IssueDesc::MultiCharConstant("'ab'".to_string()),
+            ),
+            Issue::new(
+                Some(location!(1, 32)),
+                IssueType::Warning,
+                IssueDesc::MultiCharConstant("'é'".to_string()),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn version_dependent_constants() {
+    assert_true(
+        "#if 0b101 == 5 && 1'000 == 1000\n",
+        &params(Version::Cpp(CppVersion::Cpp14)),
+    );
+
+    let pedantic = Params {
+        pedantic: true,
+        ..Params::default()
+    };
+    let (values, issues) = eval_src("#if 0b101 == 5\n", &pedantic);
+    fassert_eq!(values, vec![Ok(true)]);
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            Some(location!(1, 5)),
+            IssueType::Warning,
+            IssueDesc::GnuExtension("binary constants".to_string()),
+        )]
+    );
+}
+
+#[test]
+fn has_attribute() {
+    assert_true(
+        "#if __has_attribute(noreturn) && __has_attribute(__packed__)\n\
+         #if __has_attribute(gnu::cold) && !__has_attribute(nonexistent)\n\
+         #if !__has_attribute(foo::cold) && !__has_cpp_attribute\n\
+         #if defined(__has_attribute) && !defined __has_cpp_attribute\n",
+        &Params::default(),
+    );
+    assert_true(
+        "#if defined __has_attribute && defined(__has_cpp_attribute)\n\
+         #if __has_cpp_attribute(nodiscard) == 201907\n\
+         #if __has_cpp_attribute(likely) == 0\n\
+         #if __has_cpp_attribute(gnu::always_inline) == 1\n",
+        &params(Version::Cpp(CppVersion::Cpp17)),
+    );
+    assert_true(
+        "#if __has_cpp_attribute(likely) == 201803\n",
+        &params(Version::Cpp(CppVersion::Cpp20)),
+    );
+
+    let (values, _) = eval_src("#if __has_attribute()\n", &Params::default());
+    fassert_eq!(
+        values,
+        vec![Err(Issue::new(
+            Some(location!(1, 5)),
+            IssueType::Error,
+            IssueDesc::MissingTokenInIf("attribute name".to_string()),
+        ))]
+    );
+}
+
+#[test]
+fn comma() {
+    let pedantic = Params {
+        pedantic: true,
+        ..Params::default()
+    };
+    let (values, issues) = eval_src("#if (0, 1)\n#if 0 && (0, 1)\n", &pedantic);
+    fassert_eq!(values, vec![Ok(true), Ok(false)]);
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            Some(location!(1, 7)),
+            IssueType::Warning,
+            IssueDesc::CommaInIf,
+        )]
+    );
+}
+
+#[test]
+fn errors() {
+    let (values, _) = eval_src(
+        "#if 1 +\n\
+         #if (1\n\
+         #if 1 = 1\n\
+         #if 1.0\n\
+         #if \"s\"\n\
+         #if 1 2\n\
+         #if 99999999999999999999\n\
+         #if 1 ? 2\n\
+         #if 1u2\n\
+         #if ''\n\
+         #define EMPTY\n\
+         #if EMPTY\n",
+        &Params::default(),
+    );
+    let err = |nline, nchar, desc| {
+        Err(Issue::new(
+            Some(location!(nline, nchar)),
+            IssueType::Error,
+            desc,
+        ))
+    };
+    let s = |s: &str| s.to_string();
+    fassert_eq!(
+        values,
+        vec![
+            err(1, 7, IssueDesc::MissingTokenInIf(s("expression"))),
+            err(2, 6, IssueDesc::MissingTokenInIf(s(")"))),
+            err(3, 7, IssueDesc::InvalidTokenInIf(s("="))),
+            err(4, 5, IssueDesc::InvalidConstantInIf(s("1.0"))),
+            err(5, 5, IssueDesc::InvalidTokenInIf(s("\"s\""))),
+            err(6, 7, IssueDesc::InvalidTokenInIf(s("2"))),
+            err(
+                7,
+                5,
+                IssueDesc::IntegerTooLargeInIf(s("99999999999999999999"))
+            ),
+            err(8, 9, IssueDesc::MissingTokenInIf(s(":"))),
+            err(9, 5, IssueDesc::InvalidConstantInIf(s("1u2"))),
+            err(10, 5, IssueDesc::InvalidConstantInIf(s("''"))),
+            err(12, 5, IssueDesc::MissingTokenInIf(s("expression"))),
+        ]
+    );
+}
@@ -0,0 +1,652 @@
+//! Macro replacement, section 6.10.3, C11 spec.
+//!
+//! Rescanning follows Dave Prosser's algorithm: every token carries a hide
+//! set of the macros it was produced by, and a macro is never expanded by a
+//! token that has it in its hide set.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{
+    Identifer, PPNumber, PPToken, Punct, Span, SpannedPPToken, StrLit,
+    StrLitPrefix,
+};
+use crate::common::{Issue, IssueDesc, IssueType, Params};
+use crate::directive::LocMapping;
+use crate::lexer::Lexer;
+use crate::macros::{
+    is_ident, va_opt_content, Dynamic, MacroDef, MacroTable, VA_OPT,
+};
+
+use std::collections::HashSet;
+
+pub type HideSet = HashSet<Identifer>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HToken {
+    pub tok: PPToken,
+    pub span: Span,
+    pub hs: HideSet,
+}
+
+impl HToken {
+    pub fn new(SpannedPPToken(tok, span): SpannedPPToken) -> Self {
+        HToken {
+            tok,
+            span,
+            hs: HideSet::new(),
+        }
+    }
+
+    pub fn spanned(self) -> SpannedPPToken {
+        SpannedPPToken(self.tok, self.span)
+    }
+
+    fn is_whitespace(&self) -> bool {
+        self.tok == PPToken::Space || self.tok == PPToken::NewLine
+    }
+}
+
+pub struct Expander<'a> {
+    macros: &'a MacroTable,
+    params: &'a Params,
+    loc_mapping: &'a LocMapping,
+    issues: &'a mut Vec<Issue>,
+    // In '#if', the operand of a 'defined' from an expansion isn't expanded.
+    in_if: bool,
+}
+
+impl<'a> Expander<'a> {
+    pub fn new(
+        macros: &'a MacroTable,
+        params: &'a Params,
+        loc_mapping: &'a LocMapping,
+        issues: &'a mut Vec<Issue>,
+    ) -> Self {
+        Expander {
+            macros,
+            params,
+            loc_mapping,
+            issues,
+            in_if: false,
+        }
+    }
+
+    pub fn in_if(mut self) -> Self {
+        self.in_if = true;
+        self
+    }
+
+    fn issue(&mut self, span: Span, itype: IssueType, desc: IssueDesc) {
+        self.issues.push(Issue::new(
+            span.location(self.loc_mapping),
+            itype,
+            desc,
+        ));
+    }
+
+    // Macro expands `toks`, which may span multiple lines. A function-like
+    // macro's arguments can span lines, in which case the newlines between
+    // them become whitespace.
+    pub fn expand(&mut self, toks: Vec<SpannedPPToken>) -> Vec<SpannedPPToken> {
+        self.expand_htokens(toks.into_iter().map(HToken::new).collect())
+            .into_iter()
+            .map(HToken::spanned)
+            .collect()
+    }
+
+    pub fn expand_htokens(&mut self, toks: Vec<HToken>) -> Vec<HToken> {
+        self.expand_until(toks, false, false).0
+    }
+
+    // Like `expand`, but stops at the first pragma operator it comes to, so
+    // that it can be carried out before the tokens after it are expanded.
+    // With `more`, there's more text after `toks`, so it also stops at a
+    // function-like macro call that isn't closed in them, for the rest of its
+    // arguments. Gives the expanded tokens, and the ones from where it
+    // stopped on.
+    pub fn expand_until_pragma(
+        &mut self,
+        toks: Vec<HToken>,
+        more: bool,
+    ) -> (Vec<HToken>, Vec<HToken>) {
+        self.expand_until(toks, true, more)
+    }
+
+    fn expand_until(
+        &mut self,
+        toks: Vec<HToken>,
+        pragma: bool,
+        more: bool,
+    ) -> (Vec<HToken>, Vec<HToken>) {
+        let macros = self.macros;
+
+        // Reversed, so that the tokens from an expansion can be pushed back
+        // onto the front to be rescanned.
+        let mut input: Vec<HToken> = toks.into_iter().rev().collect();
+        let mut output = Vec::with_capacity(input.len());
+        let mut after_defined = false;
+        while let Some(t) = input.pop() {
+            if after_defined {
+                match &t.tok {
+                    PPToken::Space
+                    | PPToken::NewLine
+                    | PPToken::Punct(Punct::LParen) => {
+                        output.push(t);
+                        continue;
+                    }
+                    PPToken::Identifer(_) => {
+                        after_defined = false;
+                        output.push(t);
+                        continue;
+                    }
+                    _ => after_defined = false,
+                }
+            }
+            if self.in_if && is_ident(&t.tok, "defined") {
+                after_defined = true;
+                output.push(t);
+                continue;
+            }
+            if pragma && is_pragma_operator(&t.tok, self.params) {
+                input.push(t);
+                break;
+            }
+
+            let m = match &t.tok {
+                PPToken::Identifer(i) if !t.hs.contains(i) => macros.get(i),
+                _ => None,
+            };
+            let name = match (m, &t.tok) {
+                (Some(_), PPToken::Identifer(i)) => i.clone(),
+                _ => {
+                    output.push(t);
+                    continue;
+                }
+            };
+
+            let def = &m.unwrap().def;
+            let (args, mut hs) = match def {
+                MacroDef::Dynamic(d) => {
+                    let tok = self.dynamic(*d, t.span);
+                    output.push(HToken { tok, ..t });
+                    continue;
+                }
+                MacroDef::Object(_) => (vec![], t.hs.clone()),
+                MacroDef::Function(..) => {
+                    let next = input.iter().rposition(|t| !t.is_whitespace());
+                    let lparen = match next {
+                        Some(i) if input[i].tok.is_punct(&Punct::LParen) => i,
+                        _ => {
+                            output.push(t);
+                            continue;
+                        }
+                    };
+
+                    let nparams = def.nparams();
+                    let variadic = def.is_variadic();
+                    let max_args = if variadic { nparams } else { usize::MAX };
+                    let (args, rparen) =
+                        match self.collect_args(&mut input, lparen, max_args) {
+                            Some(ar) => ar,
+                            None if more => {
+                                input.push(t);
+                                break;
+                            }
+                            None => {
+                                // Like gcc, the rest is left as it is.
+                                self.issue(
+                                    t.span,
+                                    IssueType::Error,
+                                    IssueDesc::UnterminatedArgumentList(name.0),
+                                );
+                                output.push(t);
+                                output.extend(input.drain(..).rev());
+                                break;
+                            }
+                        };
+
+                    // 'f()' is a call with no arguments, if 'f' has no
+                    // parameters. The variadic argument can be left out
+                    // entirely, like gcc allows.
+                    let mut args = if nparams == 0 && args == [vec![]] {
+                        vec![]
+                    } else {
+                        args
+                    };
+                    if variadic && args.len() + 1 == nparams {
+                        args.push(vec![]);
+                    }
+                    if args.len() != nparams {
+                        self.issue(
+                            t.span,
+                            IssueType::Error,
+                            IssueDesc::WrongMacroArgCount(
+                                name.0.clone(),
+                                nparams,
+                                args.len(),
+                            ),
+                        );
+                        output.push(t);
+                        continue;
+                    }
+
+                    let hs = t.hs.intersection(&rparen.hs).cloned().collect();
+                    (args, hs)
+                }
+            };
+
+            hs.insert(name);
+            let toks = self.subst(def, &args, &hs, t.span);
+            input.extend(toks.into_iter().rev());
+        }
+        input.reverse();
+        (output, input)
+    }
+
+    // What the dynamic macro `d`, used at `span`, expands to. '__FILE__' and
+    // '__LINE__' are where it was used, after any '#line'.
+    fn dynamic(&self, d: Dynamic, span: Span) -> PPToken {
+        let builtins = &self.macros.builtins;
+        let str_lit =
+            |s: &str| PPToken::StrLit(StrLit(StrLitPrefix::None, escape(s)));
+        let number = |n: u64| PPToken::PPNumber(PPNumber(n.to_string()));
+        let loc = span.location(self.loc_mapping);
+        match d {
+            Dynamic::File => str_lit(loc.as_ref().map_or("", |l| &l.filename)),
+            Dynamic::Line => {
+                number(loc.and_then(|l| l.nline).unwrap_or(0) as u64)
+            }
+            Dynamic::Date => str_lit(&builtins.date),
+            Dynamic::Time => str_lit(&builtins.time),
+            Dynamic::Timestamp => str_lit(&builtins.timestamp),
+            Dynamic::Counter => {
+                let counter = builtins.counter.get();
+                builtins.counter.set(counter + 1);
+                number(counter)
+            }
+            Dynamic::BaseFile => str_lit(&builtins.base_file),
+            Dynamic::IncludeLevel => number(builtins.include_level as u64),
+        }
+    }
+
+    // Takes the arguments of a function-like macro call from `input`, whose
+    // '(' is at `lparen`. Returns the arguments, without surrounding
+    // whitespace, and the ')', or `None` if there's no ')', in which case
+    // `input` is left as it is.
+    //
+    // Any arguments past `max_args` are part of the last one, commas
+    // included, for the variadic parameter.
+    fn collect_args(
+        &mut self,
+        input: &mut Vec<HToken>,
+        lparen: usize,
+        max_args: usize,
+    ) -> Option<(Vec<Vec<HToken>>, HToken)> {
+        let mut depth = 0;
+        input[..lparen].iter().rposition(|t| {
+            match t.tok.punct() {
+                Some(Punct::LParen) => depth += 1,
+                Some(Punct::RParen) if depth == 0 => return true,
+                Some(Punct::RParen) => depth -= 1,
+                _ => (),
+            }
+            false
+        })?;
+        input.truncate(lparen);
+
+        let mut args = vec![vec![]];
+        let mut depth = 0;
+        let rparen = loop {
+            let mut t = input.pop()?;
+            match t.tok.punct().cloned() {
+                Some(Punct::RParen) if depth == 0 => break t,
+                Some(Punct::Comma) if depth == 0 && args.len() < max_args => {
+                    args.push(vec![]);
+                    continue;
+                }
+                Some(Punct::LParen) => depth += 1,
+                Some(Punct::RParen) => depth -= 1,
+                _ => (),
+            }
+
+            let arg = args.last_mut().unwrap();
+            if t.is_whitespace() {
+                if arg.last().is_none_or(HToken::is_whitespace) {
+                    continue;
+                }
+                t.tok = PPToken::Space;
+            }
+            arg.push(t);
+        };
+
+        for arg in &mut args {
+            if arg.last().is_some_and(HToken::is_whitespace) {
+                arg.pop();
+            }
+        }
+        Some((args, rparen))
+    }
+
+    // Substitutes the arguments into the replacement list, and handles '#'
+    // and '##'. Every token in the result gets `hs` added to its hide set.
+    //
+    // Arguments are fully macro expanded first, unless they are an operand
+    // of '#' or '##'.
+    fn subst(
+        &mut self,
+        def: &MacroDef,
+        args: &[Vec<HToken>],
+        hs: &HideSet,
+        span: Span,
+    ) -> Vec<HToken> {
+        let mut expanded = vec![None; args.len()];
+        self.subst_tokens(def, def.body(), args, &mut expanded, span)
+            .into_iter()
+            .flatten()
+            .map(|mut t| {
+                t.hs.extend(hs.iter().cloned());
+                t
+            })
+            .collect()
+    }
+
+    // Does the work of `subst` for `body`, which is either the replacement
+    // list or the contents of a '__VA_OPT__'. `None` is a placemarker,
+    // section 6.10.3.3p2, C11 spec.
+    fn subst_tokens(
+        &mut self,
+        def: &MacroDef,
+        body: &[PPToken],
+        args: &[Vec<HToken>],
+        expanded: &mut Vec<Option<Vec<HToken>>>,
+        span: Span,
+    ) -> Vec<Option<HToken>> {
+        let mut output: Vec<Option<HToken>> = Vec::with_capacity(body.len());
+        let mut paste = false;
+        let mut i = 0;
+        while i < body.len() {
+            let tok = &body[i];
+            i += 1;
+            let next = body[i..].iter().position(|t| *t != PPToken::Space);
+            let next = next.map(|n| i + n);
+
+            if *tok == PPToken::Space {
+                if !paste {
+                    output.push(Some(self.body_token(tok, span)));
+                }
+                continue;
+            }
+
+            if tok.is_punct(&Punct::DoubleHash) {
+                while let Some(Some(t)) = output.last() {
+                    if !t.is_whitespace() {
+                        break;
+                    }
+                    output.pop();
+                }
+                paste = true;
+                continue;
+            }
+
+            // GNU ', ## __VA_ARGS__', the comma is removed if the variadic
+            // argument is empty, and otherwise nothing is pasted.
+            if let Some(p) = def.variadic_param(tok) {
+                let comma = match output.last() {
+                    Some(Some(t)) => t.tok.is_punct(&Punct::Comma),
+                    _ => false,
+                };
+                if paste && comma {
+                    if args[p].is_empty() {
+                        output.pop();
+                    } else {
+                        output.extend(args[p].iter().cloned().map(Some));
+                    }
+                    paste = false;
+                    continue;
+                }
+            }
+
+            let pasted = paste
+                || next.is_some_and(|n| body[n].is_punct(&Punct::DoubleHash));
+            let toks =
+                match (tok.punct(), next.map(|n| &body[n]), def.param(tok)) {
+                    (Some(Punct::Hash), Some(n), _) if is_ident(n, VA_OPT) => {
+                        let (toks, end) = self.va_opt(
+                            def,
+                            body,
+                            next.unwrap(),
+                            args,
+                            expanded,
+                            span,
+                        );
+                        i = end;
+                        let toks: Vec<_> = toks.into_iter().flatten().collect();
+                        vec![Some(self.stringize(&toks, span))]
+                    }
+                    (Some(Punct::Hash), Some(n), _)
+                        if def.param(n).is_some() =>
+                    {
+                        i = next.unwrap() + 1;
+                        let p = def.param(n).unwrap();
+                        vec![Some(self.stringize(&args[p], span))]
+                    }
+                    (_, _, Some(p)) => {
+                        if pasted {
+                            if args[p].is_empty() {
+                                vec![None]
+                            } else {
+                                args[p].iter().cloned().map(Some).collect()
+                            }
+                        } else {
+                            if expanded[p].is_none() {
+                                expanded[p] =
+                                    Some(self.expand_htokens(args[p].clone()));
+                            }
+                            expanded[p]
+                                .iter()
+                                .flatten()
+                                .cloned()
+                                .map(Some)
+                                .collect()
+                        }
+                    }
+                    _ if def.is_variadic() && is_ident(tok, VA_OPT) => {
+                        let (toks, end) =
+                            self.va_opt(def, body, i - 1, args, expanded, span);
+                        i = end;
+                        toks
+                    }
+                    _ => vec![Some(self.body_token(tok, span))],
+                };
+
+            let mut toks = toks.into_iter();
+            if paste {
+                if let (Some(lhs), Some(rhs)) = (output.pop(), toks.next()) {
+                    output.extend(self.paste(lhs, rhs));
+                }
+            }
+            output.extend(toks);
+            paste = false;
+        }
+        output
+    }
+
+    // C++20 and C2x '__VA_OPT__(content)', at `body[i]`. Becomes a
+    // placemarker if the variadic argument has no tokens once macro
+    // expanded, and the substituted content otherwise. Also returns the index
+    // after the ')'.
+    fn va_opt(
+        &mut self,
+        def: &MacroDef,
+        body: &[PPToken],
+        i: usize,
+        args: &[Vec<HToken>],
+        expanded: &mut Vec<Option<Vec<HToken>>>,
+        span: Span,
+    ) -> (Vec<Option<HToken>>, usize) {
+        // Checked when the macro was defined.
+        let (content, end) = va_opt_content(body, i).unwrap();
+        let p = args.len() - 1;
+        if expanded[p].is_none() {
+            expanded[p] = Some(self.expand_htokens(args[p].clone()));
+        }
+        let empty = expanded[p].iter().flatten().all(HToken::is_whitespace);
+        if empty {
+            (vec![None], end)
+        } else {
+            let toks = self.subst_tokens(def, content, args, expanded, span);
+            (toks, end)
+        }
+    }
+
+    fn body_token(&self, tok: &PPToken, span: Span) -> HToken {
+        HToken {
+            tok: tok.clone(),
+            span,
+            hs: HideSet::new(),
+        }
+    }
+
+    // Section 6.10.3.2p2, C11 spec
+    fn stringize(&self, arg: &[HToken], span: Span) -> HToken {
+        let start = arg.iter().position(|t| !t.is_whitespace());
+        let end = arg.iter().rposition(|t| !t.is_whitespace());
+        let arg = match (start, end) {
+            (Some(start), Some(end)) => &arg[start..=end],
+            _ => &[],
+        };
+
+        let mut s = String::new();
+        let mut last_space = false;
+        for t in arg {
+            if t.is_whitespace() {
+                if !last_space {
+                    s.push(' ');
+                }
+                last_space = true;
+                continue;
+            }
+            last_space = false;
+            match &t.tok {
+                PPToken::StrLit(_) | PPToken::CharConst(_) => {
+                    s.push_str(&escape(&t.tok.to_string()))
+                }
+                tok => s.push_str(&tok.to_string()),
+            }
+        }
+        self.body_token(&PPToken::StrLit(StrLit(StrLitPrefix::None, s)), span)
+    }
+
+    // Section 6.10.3.3p3, C11 spec
+    //
+    // If the result isn't a single valid token, we complain and keep both
+    // tokens, like gcc does.
+    fn paste(
+        &mut self,
+        lhs: Option<HToken>,
+        rhs: Option<HToken>,
+    ) -> Vec<Option<HToken>> {
+        let (lhs, rhs) = match (lhs, rhs) {
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+            (lhs, None) => return vec![lhs],
+            (None, rhs) => return vec![rhs],
+        };
+
+        let spelling = format!("{}{}", lhs.tok, rhs.tok);
+        let mut toks = Lexer::new(&spelling, self.params)
+            .filter(|t| t.0 != PPToken::NewLine);
+        match (toks.next(), toks.next()) {
+            (Some(SpannedPPToken(tok, _)), None) if valid_paste(&tok) => {
+                vec![Some(HToken {
+                    tok,
+                    span: lhs.span,
+                    hs: lhs.hs.intersection(&rhs.hs).cloned().collect(),
+                })]
+            }
+            _ => {
+                self.issue(
+                    lhs.span,
+                    IssueType::Error,
+                    IssueDesc::InvalidPaste(
+                        lhs.tok.to_string(),
+                        rhs.tok.to_string(),
+                    ),
+                );
+                vec![Some(lhs), Some(rhs)]
+            }
+        }
+    }
+}
+
+// Unclosed literals and stray characters aren't valid tokens.
+fn valid_paste(tok: &PPToken) -> bool {
+    !matches!(
+        tok,
+        PPToken::NonPreProc(_) | PPToken::Space | PPToken::NewLine
+    )
+}
+
+// Whether `lhs` and `rhs` would lex as different tokens if they were written
+// without whitespace between them.
+pub fn would_paste(lhs: &PPToken, rhs: &PPToken, params: &Params) -> bool {
+    let word =
+        |t: &PPToken| matches!(t, PPToken::Identifer(_) | PPToken::PPNumber(_));
+    match (lhs, rhs) {
+        (PPToken::Space, _) | (_, PPToken::Space) => false,
+        (PPToken::NewLine, _) | (_, PPToken::NewLine) => false,
+        _ if word(lhs) && word(rhs) => true,
+        // Would start a comment.
+        (PPToken::Punct(Punct::Div), PPToken::Punct(Punct::Div))
+        | (PPToken::Punct(Punct::Div), PPToken::Punct(Punct::Mul)) => true,
+        (PPToken::Identifer(_), PPToken::CharConst(_))
+        | (PPToken::Identifer(_), PPToken::StrLit(_))
+        | (PPToken::Punct(_), _)
+        | (PPToken::Digraph(_), _)
+        | (PPToken::PPNumber(_), _) => {
+            let spelling = format!("{}{}", lhs, rhs);
+            Lexer::new(&spelling, params).next().map(|t| t.0).as_ref()
+                != Some(lhs)
+        }
+        _ => false,
+    }
+}
+
+// Whether `tok` is '_Pragma', or with -fms-extensions '__pragma'.
+pub fn is_pragma_operator(tok: &PPToken, params: &Params) -> bool {
+    is_ident(tok, "_Pragma")
+        || (params.ms_extensions && is_ident(tok, "__pragma"))
+}
+
+// Escapes the '"'s and '\'s in `s`, for a string literal.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Spells out the tokens, adding a space where two tokens would otherwise lex
+// differently. Runs of whitespace left by macros that expanded to nothing
+// are collapsed.
+pub fn spell(toks: &[SpannedPPToken], params: &Params) -> String {
+    let mut s = String::new();
+    let mut last: Option<&PPToken> = None;
+    for SpannedPPToken(t, _) in toks {
+        if *t == PPToken::Space && last == Some(&PPToken::Space) {
+            continue;
+        }
+        if let Some(last) = last {
+            if would_paste(last, t, params) {
+                s.push(' ');
+            }
+        }
+        s.push_str(&t.to_string());
+        last = Some(t);
+    }
+    s
+}
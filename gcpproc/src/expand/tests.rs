@@ -0,0 +1,352 @@
+use super::*;
+use crate::common::{CppVersion, Location, Params, Version};
+use crate::driver::preprocess;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+// Without linemarkers, so only the text is compared.
+fn params() -> Params {
+    Params {
+        disable_linemarkers: true,
+        ..Params::default()
+    }
+}
+
+fn pp(src: &str) -> (String, Vec<Issue>) {
+    preprocess(src, &FILENAME, &params())
+}
+
+#[test]
+fn object_like() {
+    let (text, issues) = pp("#define a 1 + 2\n\
+         #define b a * a\n\
+         #define c\n\
+         b c;\n");
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "1 + 2 * 1 + 2 ;\n");
+}
+
+#[test]
+fn self_referential() {
+    let (text, issues) = pp("#define foo foo\n\
+         #define a a b\n\
+         #define b a\n\
+         #define f(x) f(x) x\n\
+         foo a b f(f(1))\n");
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "foo a a a b f(f(1) 1) f(1) 1\n");
+}
+
+#[test]
+fn function_like() {
+    let (text, issues) = pp("#define f(x, y) (x) + (y)\n\
+         #define g() 1\n\
+         #define h(x) [x]\n\
+         f(1, (2, 3)) f (a,\n\
+         b) f g() g h() h(\n\
+         )\n\
+         f\n");
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "(1) + ((2, 3)) (a) + (b) f 1 g [] []\nf\n");
+}
+
+#[test]
+fn arg_count() {
+    let (text, issues) = pp("#define f(x, y) x y\n\
+         #define g() 1\n\
+         f(1) g(2) f(1, 2, 3)\n\
+         f(a g()\n");
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(3, 1)),
+                IssueType::Error,
+                IssueDesc::WrongMacroArgCount("f".to_string(), 2, 1),
+            ),
+            Issue::new(
+                Some(location!(3, 6)),
+                IssueType::Error,
+                IssueDesc::WrongMacroArgCount("g".to_string(), 0, 1),
+            ),
+            Issue::new(
+                Some(location!(3, 11)),
+                IssueType::Error,
+                IssueDesc::WrongMacroArgCount("f".to_string(), 2, 3),
+            ),
+            Issue::new(
+                Some(location!(4, 1)),
+                IssueType::Error,
+                IssueDesc::UnterminatedArgumentList("f".to_string()),
+            ),
+        ]
+    );
+    fassert_eq!(text, "f g f\nf(a g()\n");
+}
+
+#[test]
+fn stringize() {
+    let (text, issues) = pp(r#"#define str(x) #x
+#define xstr(x) str(x)
+#define a 1 +  2
+str(  a  b   c  ) str() str("a\n" '"' '\'') xstr(a) str(\)
+"#);
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        text,
+        r#""a b c" "" "\"a\\n\" '\"' '\\''" "1 + 2" "\"
+"#
+    );
+}
+
+#[test]
+fn paste() {
+    let (text, issues) = pp("#define cat(a, b) a ## b\n\
+         #define xcat(a, b) cat(a, b)\n\
+         #define ab done\n\
+         #define one 1\n\
+         cat(a, b) cat(one, 2) xcat(one, 2) cat(<, <=) cat(%:, %:)\n\
+         cat(, a) cat(b, ) cat(,) cat(L, 'a') cat(1, .5e) cat(-, >)\n");
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "done one2 12 <<= %:%:\na b L'a' 1.5e ->\n");
+}
+
+#[test]
+fn invalid_paste() {
+    let (text, issues) = pp("#define cat(a, b) a ## b\n\
+         #define x(a) a ## .\n\
+         cat(+, /) cat(a, \"b\") cat(/, /) x(1)\n");
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(3, 5)),
+                IssueType::Error,
+                IssueDesc::InvalidPaste("+".to_string(), "/".to_string()),
+            ),
+            Issue::new(
+                Some(location!(3, 15)),
+                IssueType::Error,
+                IssueDesc::InvalidPaste("a".to_string(), "\"b\"".to_string()),
+            ),
+            Issue::new(
+                Some(location!(3, 27)),
+                IssueType::Error,
+                IssueDesc::InvalidPaste("/".to_string(), "/".to_string()),
+            ),
+        ]
+    );
+    fassert_eq!(text, "+/ a\"b\" / / 1.\n");
+}
+
+#[test]
+fn bad_definitions() {
+    let (text, issues) = pp("#define a ## b\n\
+         #define b(x) x ##\n\
+         #define c(x) #y\n\
+         #define d #y\n\
+         a b(1) c(1) d\n");
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(1, 1)),
+                IssueType::Error,
+                IssueDesc::PasteAtEdgeOfMacro("a".to_string()),
+            ),
+            Issue::new(
+                Some(location!(2, 1)),
+                IssueType::Error,
+                IssueDesc::PasteAtEdgeOfMacro("b".to_string()),
+            ),
+            Issue::new(
+                Some(location!(3, 1)),
+                IssueType::Error,
+                IssueDesc::StringifyWithoutParam("c".to_string()),
+            ),
+        ]
+    );
+    fassert_eq!(text, "a b(1) c(1) #y\n");
+}
+
+#[test]
+fn avoid_accidental_pastes() {
+    let (text, issues) = pp("#define neg -1\n\
+         #define e\n\
+         #define plus +\n\
+         -neg -e- +plus x/**/e/**/y\n");
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "- -1 - - + + x y\n");
+}
+
+// Example 3 from section 6.10.3.5, C11 spec.
+#[test]
+fn spec_example_3() {
+    let (text, issues) = pp("#define x 3\n\
+         #define f(a) f(x * (a))\n\
+         #undef x\n\
+         #define x 2\n\
+         #define g f\n\
+         #define z z[0]\n\
+         #define h g(~\n\
+         #define m(a) a(w)\n\
+         #define w 0,1\n\
+         #define t(a) a\n\
+         #define p() int\n\
+         #define q(x) x\n\
+         #define r(x,y) x ## y\n\
+         #define str(x) # x\n\
+         f(y+1) + f(f(z)) % t(t(g)(0) + t)(1);\n\
+         g(x+(3,4)-w) | h 5) & m\n\
+         \x20     (f)^m(m);\n\
+         p() i[q()] = { q(1), r(2,3), r(4,), r(,5), r(,) };\n\
+         char c[2][6] = { str(hello), str() };\n");
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        text,
+        "f(2 * (y+1)) + f(2 * (f(2 * (z[0])))) % f(2 * (0)) + t(1);\n\
+         f(2 * (2+(3,4)-0,1)) | f(2 * (~ 5)) & f(2 * (0,1))^m(0,1);\n\
+         int i[] = { 1, 23, 4, 5, };\n\
+         char c[2][6] = { \"hello\", \"\" };\n"
+    );
+}
+
+// Example 4 from section 6.10.3.5, C11 spec, minus the '#include'.
+#[test]
+fn spec_example_4() {
+    let (text, issues) = pp(r#"#define str(s) # s
+#define xstr(s) str(s)
+#define debug(s, t) printf("x" # s "= %d, x" # t "= %s", \
+ x ## s, x ## t)
+#define INCFILE(n) vers ## n
+#define glue(a, b) a ## b
+#define xglue(a, b) glue(a, b)
+#define HIGHLOW "hello"
+#define LOW LOW ", world"
+debug(1, 2);
+fputs(str(strncmp("abc\0d", "abc", '\4') // this goes away
+ == 0) str(: @\n), s);
+xstr(INCFILE(2).h)
+glue(HIGH, LOW);
+xglue(HIGH, LOW)
+"#);
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        text,
+        r#"printf("x" "1" "= %d, x" "2" "= %s", x1, x2);
+fputs("strncmp(\"abc\\0d\", \"abc\", '\\4') == 0" ": @\n", s);
+"vers2.h"
+"hello";
+"hello" ", world"
+"#
+    );
+}
+
+// Example 5 from section 6.10.3.5, C11 spec.
+#[test]
+fn spec_example_5() {
+    let (text, issues) = pp("#define t(x,y,z) x ## y ## z\n\
+         int j[] = { t(1,2,3), t(,4,5), t(6,,7), t(8,9,),\n\
+         \x20          t(10,,), t(,11,), t(,,12), t(,,) };\n");
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(text, "int j[] = { 123, 45, 67, 89,\n10, 11, 12, };\n");
+}
+
+fn pp_cpp20(src: &str) -> (String, Vec<Issue>) {
+    let params = Params {
+        version: Version::Cpp(CppVersion::Cpp20),
+        ..params()
+    };
+    preprocess(src, &FILENAME, &params)
+}
+
+// Example 7 from section 6.10.3.5, C11 spec.
+#[test]
+fn spec_example_7() {
+    let (text, issues) = pp(r#"#define debug(...) fprintf(stderr, __VA_ARGS__)
+#define showlist(...) puts(#__VA_ARGS__)
+#define report(test, ...) ((test)?puts(#test):\
+ printf(__VA_ARGS__))
+debug("Flag");
+debug("X = %d\n", x);
+showlist(The first, second, and third items.);
+report(x>y, "x is %d but y is %d", x, y);
+"#);
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        text,
+        r#"fprintf(stderr, "Flag");
+fprintf(stderr, "X = %d\n", x);
+puts("The first, second, and third items.");
+((x>y)?puts("x>y"): printf("x is %d but y is %d", x, y));
+"#
+    );
+}
+
+// The examples from [cpp.subst], C++20 spec.
+#[test]
+fn va_opt() {
+    let (text, issues) = pp_cpp20(
+        "#define F(...) f(0 __VA_OPT__(,) __VA_ARGS__)\n\
+         #define G(X, ...) f(0, X __VA_OPT__(,) __VA_ARGS__)\n\
+         #define SDEF(sname, ...) S sname __VA_OPT__(= { __VA_ARGS__ })\n\
+         #define EMP\n\
+         F(a,b,c) F() F(EMP)\n\
+         G(a,b,c) G(a,) G(a)\n\
+         SDEF(foo); SDEF(bar, 1, 2);\n\
+         #define H2(X, Y, ...) __VA_OPT__(X ## Y,) __VA_ARGS__\n\
+         #define H3(X, ...) #__VA_OPT__(X##X X##X)\n\
+         #define H4(X, ...) __VA_OPT__(a X ## X) ## b\n\
+         #define H5A(...) __VA_OPT__()/**/__VA_OPT__()\n\
+         #define H5B(X) a ## X ## b\n\
+         #define H5C(X) H5B(X)\n\
+         H2(a, b, c, d) H3(, 0) H4(, 1) H5C(H5A())\n",
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        text,
+        "f(0 , a,b,c) f(0 ) f(0 )\n\
+         f(0, a , b,c) f(0, a ) f(0, a )\n\
+         S foo ; S bar = { 1, 2 };\n\
+         ab, c, d \"\" a b ab\n"
+    );
+}
+
+#[test]
+fn gnu_variadics() {
+    let (text, issues) = pp(
+        "#define e(fmt, ...) printf(fmt, ## __VA_ARGS__)\n\
+         #define n(fmt, args...) printf(fmt , ##args)\n\
+         #define l(args...) f(args)\n\
+         e(\"a\") e(\"a\",) e(\"a\", 1, 2) n(\"b\") n(\"b\", x) l(1, (2, 3))\n",
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        text,
+        "printf(\"a\") printf(\"a\") printf(\"a\",1, 2) printf(\"b\" ) \
+         printf(\"b\" ,x) f(1, (2, 3))\n"
+    );
+}
+
+#[test]
+fn variadic_arg_count() {
+    let (text, issues) = pp("#define v(x, y, ...) x y __VA_ARGS__\n\
+         v(1) v(1, 2) v(1, 2, 3, 4)\n");
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            Some(location!(2, 1)),
+            IssueType::Error,
+            IssueDesc::WrongMacroArgCount("v".to_string(), 3, 1),
+        )]
+    );
+    fassert_eq!(text, "v 1 2 1 2 3, 4\n");
+}
@@ -0,0 +1,334 @@
+//! What happens when preprocessing fails.
+//!
+//! We stop at an '#error', or a '#warning' made an error, and run the real
+//! preprocessor locally to get its exact error output, as ours won't match
+//! it. It's given the same source as we were, on its standard input, as that
+//! needn't be what's in the file. The failure is cached, so the same
+//! translation unit doesn't run it again. If the real preprocessor doesn't
+//! fail, our prediction was a bug.
+
+#[cfg(test)]
+mod tests;
+
+use crate::common::{
+    CVersion, CppVersion, IncludeDir, Issue, IssueDesc, IssueType, MacroDefine,
+    Params, Version,
+};
+use crate::driver::{Preprocessor, COMMAND_LINE};
+use crate::expand;
+use crate::macros::{self, MacroTable};
+use crate::search::Resolution;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+// A translation unit that failed, as the real preprocessor reported it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    // Its exit code, or `None` if it couldn't be run, or was killed.
+    pub status: Option<i32>,
+    pub stderr: String,
+    // Our issues, up to where we stopped.
+    pub issues: Vec<Issue>,
+}
+
+// What a failure is cached by: the command line, the main file, and every
+// header we looked for before stopping, with the contents of the one found.
+// Also the macro state when we stopped that isn't in the files: the
+// command line's definitions still in force, and those saved by
+// 'push_macro', `None` for when it wasn't defined, in name order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    args: Vec<String>,
+    filename: String,
+    src: String,
+    headers: Vec<(String, String, Option<Vec<u8>>)>,
+    command_line: Vec<String>,
+    pushed: Vec<(String, Vec<Option<String>>)>,
+}
+
+impl Key {
+    pub fn new(
+        params: &Params,
+        filename: &str,
+        src: &str,
+        resolutions: &[Resolution],
+        macros: &MacroTable,
+    ) -> Self {
+        let headers = resolutions
+            .iter()
+            .map(|r| {
+                let contents =
+                    r.found.as_ref().and_then(|f| fs::read(&f.path).ok());
+                (r.header.1.clone(), r.includer.clone(), contents)
+            })
+            .collect();
+        let mut command_line: Vec<_> = macros
+            .iter()
+            .filter(|(_, m)| {
+                m.loc.as_ref().is_some_and(|l| l.filename == COMMAND_LINE)
+            })
+            .map(|(name, m)| macros::definition(name, &m.def))
+            .collect();
+        command_line.sort();
+        let mut pushed: Vec<_> = macros
+            .pushed()
+            .filter(|(_, stack)| !stack.is_empty())
+            .map(|(name, stack)| {
+                let stack = stack
+                    .iter()
+                    .map(|m| {
+                        m.as_ref().map(|m| macros::definition(name, &m.def))
+                    })
+                    .collect();
+                (name.0.clone(), stack)
+            })
+            .collect();
+        pushed.sort();
+        Key {
+            args: args(params),
+            filename: filename.to_string(),
+            src: src.to_string(),
+            headers,
+            command_line,
+            pushed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FailureCache {
+    failures: HashMap<Key, Failure>,
+}
+
+impl FailureCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&Failure> {
+        self.failures.get(key)
+    }
+
+    pub fn insert(&mut self, key: Key, failure: Failure) {
+        self.failures.insert(key, failure);
+    }
+}
+
+// The real preprocessor.
+#[derive(Debug, Clone)]
+pub struct Fallback {
+    pub program: String,
+    // Given before the ones from `Params`.
+    pub args: Vec<String>,
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Fallback {
+            program: "cpp".to_string(),
+            args: vec![],
+        }
+    }
+}
+
+impl Fallback {
+    // Runs it on `src`, the contents of the file `filename`, giving its exit
+    // code, output and error output. A '#line' names the file, and its
+    // directory is searched first for '#include "..."', like the file's own.
+    fn run(
+        &self,
+        params: &Params,
+        filename: &str,
+        src: &str,
+    ) -> std::io::Result<(Option<i32>, String, String)> {
+        let dir = match Path::new(filename).parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg("-iquote")
+            .arg(dir)
+            .args(args(params))
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Written from another thread, so that it can't block on a full pipe
+        // while its output isn't read. It may stop reading before the end.
+        let input =
+            format!("#line 1 \"{}\"\n{}", expand::escape(filename), src);
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+        let output = child.wait_with_output()?;
+        let _ = writer.join();
+        Ok((
+            output.status.code(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+}
+
+// The options of gcc's preprocessor that give `params`. The standard
+// directories are the real preprocessor's own.
+pub fn args(params: &Params) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = |a: &str| args.push(a.to_string());
+    let (lang, std) = match params.version {
+        Version::C(v) => (
+            "c",
+            match v {
+                CVersion::C89 => Some("c89"),
+                CVersion::C99 => Some("c99"),
+                CVersion::C11 => Some("c11"),
+                CVersion::C18 => Some("c17"),
+                CVersion::C2x => Some("c2x"),
+                CVersion::Min | CVersion::Max => None,
+            },
+        ),
+        Version::Cpp(v) => (
+            "c++",
+            match v {
+                CppVersion::Cpp98 => Some("c++98"),
+                CppVersion::Cpp03 => Some("c++03"),
+                CppVersion::Cpp11 => Some("c++11"),
+                CppVersion::Cpp14 => Some("c++14"),
+                CppVersion::Cpp17 => Some("c++17"),
+                CppVersion::Cpp20 => Some("c++20"),
+                CppVersion::Cpp23 => Some("c++23"),
+                CppVersion::Min | CppVersion::Max => None,
+            },
+        ),
+    };
+    arg("-x");
+    arg(lang);
+    if let Some(std) = std {
+        arg(&format!("-std={}", std));
+    }
+
+    let flags = [
+        (params.pedantic, "-pedantic"),
+        (params.trigraphs, "-trigraphs"),
+        (!params.wtrigraphs, "-Wno-trigraphs"),
+        (params.wundef, "-Wundef"),
+        (!params.wendif_labels, "-Wno-endif-labels"),
+        (!params.wdeprecated, "-Wno-deprecated"),
+        (params.wsystem_headers, "-Wsystem-headers"),
+        (params.wunknown_pragmas, "-Wunknown-pragmas"),
+        (params.werror, "-Werror"),
+        (params.undef, "-undef"),
+        (params.disable_linemarkers, "-P"),
+        (!params.hosted, "-ffreestanding"),
+        (params.ms_extensions, "-fms-extensions"),
+        (params.fdirectives_only, "-fdirectives-only"),
+    ];
+    for (set, flag) in &flags {
+        if *set {
+            arg(flag);
+        }
+    }
+    for option in &params.werror_options {
+        arg(&format!("-Werror={}", option));
+    }
+
+    if let Some(sysroot) = &params.isysroot {
+        arg("-isysroot");
+        arg(sysroot);
+    }
+    if let Some(multilib) = &params.imultilib {
+        arg("-imultilib");
+        arg(multilib);
+    }
+    for dir in &params.include_dirs {
+        let (opt, dir) = match dir {
+            IncludeDir::I(d) => ("-I", d),
+            IncludeDir::IQuote(d) => ("-iquote", d),
+            IncludeDir::ISystem(d) => ("-isystem", d),
+            IncludeDir::IDirAfter(d) => ("-idirafter", d),
+            IncludeDir::IPrefix(d) => ("-iprefix", d),
+            IncludeDir::IWithPrefix(d) => ("-iwithprefix", d),
+            IncludeDir::IWithPrefixBefore(d) => ("-iwithprefixbefore", d),
+        };
+        arg(opt);
+        arg(dir);
+    }
+    for define in &params.macro_defines {
+        match define {
+            MacroDefine::D(d) => arg(&format!("-D{}", d)),
+            MacroDefine::U(name) => arg(&format!("-U{}", name)),
+        }
+    }
+    for file in &params.imacros {
+        arg("-imacros");
+        arg(file);
+    }
+    for file in &params.include {
+        arg("-include");
+        arg(file);
+    }
+    args
+}
+
+// Preprocesses `src`, the contents of the file `filename`, giving the text
+// and our issues, or the real preprocessor's failure if we predict one.
+pub fn preprocess(
+    src: &str,
+    filename: &str,
+    params: &Params,
+    fallback: &Fallback,
+    cache: &mut FailureCache,
+) -> Result<(String, Vec<Issue>), Failure> {
+    let mut pp = Preprocessor::new(params);
+    pp.run(src, filename);
+    if !pp.failed {
+        return Ok((pp.output, pp.issues));
+    }
+
+    let key = Key::new(params, filename, src, &pp.resolutions, &pp.macros);
+    if let Some(failure) = cache.get(&key) {
+        return Err(failure.clone());
+    }
+    let mut issues = pp.issues;
+    match fallback.run(params, filename, src) {
+        Ok((Some(0), stdout, _)) => {
+            issues.push(Issue::new(
+                None,
+                IssueType::Error,
+                IssueDesc::InternalBug(filename.to_string()),
+            ));
+            Ok((stdout, issues))
+        }
+        Ok((status, _, stderr)) => {
+            let failure = Failure {
+                status,
+                stderr,
+                issues,
+            };
+            cache.insert(key, failure.clone());
+            Err(failure)
+        }
+        // Not cached, as it might be runnable later.
+        Err(e) => {
+            issues.push(Issue::new(
+                None,
+                IssueType::Error,
+                IssueDesc::CannotRunPreprocessor(
+                    fallback.program.clone(),
+                    e.to_string(),
+                ),
+            ));
+            Err(Failure {
+                status: None,
+                stderr: String::new(),
+                issues,
+            })
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use super::*;
+use crate::a::{Identifer, MessageType, PPToken};
+use crate::common::Location;
+use crate::macros::MacroDef;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+// A real preprocessor that runs `script`, ignoring its arguments.
+fn sh(script: &str) -> Fallback {
+    Fallback {
+        program: "sh".to_string(),
+        args: vec!["-c".to_string(), script.to_string(), "sh".to_string()],
+    }
+}
+
+// Our issue for '#error no' on the first line.
+fn error() -> Issue {
+    Issue::new(
+        Some(Location::new(FILENAME.clone(), 1, 1)),
+        IssueType::Error,
+        IssueDesc::Message(MessageType::Error, "no".to_string()),
+    )
+}
+
+#[test]
+fn cached_failure() {
+    let params = Params::default();
+    let mut cache = FailureCache::new();
+    let src = "#error no\n";
+    let failure = Failure {
+        status: Some(1),
+        stderr: "filename:1:2: error: #error no\n".to_string(),
+        issues: vec![error()],
+    };
+    let fallback = sh("echo 'filename:1:2: error: #error no' >&2; exit 1");
+    fassert_eq!(
+        preprocess(src, &FILENAME, &params, &fallback, &mut cache),
+        Err(failure.clone())
+    );
+    // The second time, it's not run.
+    let fallback = sh("exit 2");
+    fassert_eq!(
+        preprocess(src, &FILENAME, &params, &fallback, &mut cache),
+        Err(failure)
+    );
+    // But it is for other inputs.
+    fassert_eq!(
+        preprocess("#error no\n\n", &FILENAME, &params, &fallback, &mut cache)
+            .map_err(|f| f.status),
+        Err(Some(2))
+    );
+}
+
+#[test]
+fn mismatch() {
+    let params = Params::default();
+    let mut cache = FailureCache::new();
+    let fallback = sh("echo text");
+    fassert_eq!(
+        preprocess("#error no\n", &FILENAME, &params, &fallback, &mut cache),
+        Ok((
+            "text\n".to_string(),
+            vec![
+                error(),
+                Issue::new(
+                    None,
+                    IssueType::Error,
+                    IssueDesc::InternalBug(FILENAME.clone()),
+                ),
+            ]
+        ))
+    );
+
+    // Not being able to run it isn't cached.
+    let fallback = Fallback {
+        program: "/nonexistent/cpp".to_string(),
+        args: vec![],
+    };
+    let ret =
+        preprocess("#error no\n", &FILENAME, &params, &fallback, &mut cache);
+    fassert_eq!(ret.map_err(|f| (f.status, f.issues.len())), Err((None, 2)));
+    fassert_eq!(cache.failures.len(), 0);
+}
+
+// It's given our source, not the file's, which here doesn't exist.
+#[test]
+fn src_on_stdin() {
+    let params = Params::default();
+    let mut cache = FailureCache::new();
+    let fallback = sh("cat >&2; exit 1");
+    fassert_eq!(
+        preprocess("#error no\n", &FILENAME, &params, &fallback, &mut cache)
+            .map_err(|f| f.stderr),
+        Err("#line 1 \"filename\"\n#error no\n".to_string())
+    );
+}
+
+// The macros from the command line and those pushed are part of the key,
+// since neither is in the files.
+#[test]
+fn key_macros() {
+    let params = Params::default();
+    let key =
+        |macros: &MacroTable| Key::new(&params, &FILENAME, "x\n", &[], macros);
+    let x = Identifer("X".to_string());
+    let mut macros = MacroTable::new();
+    let empty = key(&macros);
+    let mut issues = vec![];
+    let one = MacroDef::Object(vec![PPToken::Identifer(Identifer(
+        "one".to_string(),
+    ))]);
+    let in_file = Some(Location::new(FILENAME.clone(), 1, 1));
+    macros.define(x.clone(), one.clone(), in_file, &params, &mut issues);
+    fassert_eq!(key(&macros), empty);
+
+    let on_command_line = Some(Location::new_noline(COMMAND_LINE.to_string()));
+    macros.define(x.clone(), one, on_command_line, &params, &mut issues);
+    let defined = key(&macros);
+    fassert_eq!(defined.command_line, vec!["X one"]);
+    macros.push_macro(&x);
+    macros.undef(&x);
+    let pushed = key(&macros);
+    fassert_eq!(pushed.command_line, Vec::<String>::new());
+    fassert_eq!(
+        pushed.pushed,
+        vec![("X".to_string(), vec![Some("X one".to_string())])]
+    );
+    macros.pop_macro(&x);
+    fassert_eq!(key(&macros), defined);
+    fassert_eq!(issues, vec![]);
+}
+
+#[test]
+fn command_line() {
+    let params = Params {
+        version: Version::Cpp(CppVersion::Cpp17),
+        pedantic: true,
+        wtrigraphs: false,
+        werror_options: vec!["undef".to_string()],
+        include_dirs: vec![
+            IncludeDir::I("a".to_string()),
+            IncludeDir::ISystem("b".to_string()),
+        ],
+        macro_defines: vec![
+            MacroDefine::D("X=1".to_string()),
+            MacroDefine::U("Y".to_string()),
+        ],
+        include: vec!["c.h".to_string()],
+        ..Params::default()
+    };
+    fassert_eq!(
+        args(&params),
+        vec![
+            "-x",
+            "c++",
+            "-std=c++17",
+            "-pedantic",
+            "-Wno-trigraphs",
+            "-Werror=undef",
+            "-I",
+            "a",
+            "-isystem",
+            "b",
+            "-DX=1",
+            "-UY",
+            "-include",
+            "c.h",
+        ]
+    );
+}
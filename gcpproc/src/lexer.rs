@@ -0,0 +1,521 @@
+//! The rest of translation phase 3: splitting the output of
+//! `preproc_phases_1_to_3` into preprocessing tokens.
+//!
+//! Section 6.4, C18 spec.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{
+    CharConst, CharConstPrefix, HeaderName, HeaderNameType, Identifer,
+    PPNumber, PPToken, Punct, Span, SpannedPPToken, StrLit, StrLitPrefix,
+};
+use crate::common::{
+    CVersion, CharNumber, CppVersion, LineNumber, Params, Version,
+};
+use crate::p1to3::Output;
+
+// Header names are only tokens in '#include', '#include_next' and '#import'
+// directives, so we have to keep track of where in the line we are.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum LineState {
+    Start,
+    FoundHash,
+    FoundInclude,
+    Other,
+}
+
+pub struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    nline: LineNumber,
+    nchar: CharNumber,
+    version: Version,
+    line_state: LineState,
+}
+
+// Excludes newline.
+fn is_whitespace(c: u8) -> bool {
+    c == b'\x0C' // Form feed
+        || c == b'\x0B' // Vert tab
+        || c == b' '
+        || c == b'\t'
+}
+
+// Non-ascii chars are allowed in identifiers as an extension, like gcc does.
+fn is_ident_nondigit(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'$' || !c.is_ascii()
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str, params: &Params) -> Self {
+        Lexer {
+            src,
+            pos: 0,
+            nline: 1,
+            nchar: 0,
+            version: params.version,
+            line_state: LineState::Start,
+        }
+    }
+
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.src.as_bytes().get(self.pos + n).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.src[self.pos..].starts_with(s)
+    }
+
+    // Consumes `n` bytes, keeping track of the line and char we are at.
+    fn bump(&mut self, n: usize) {
+        for c in self.src[self.pos..self.pos + n].chars() {
+            if c == '\n' {
+                self.nline += 1;
+                self.nchar = 0;
+            } else {
+                self.nchar += 1;
+            }
+        }
+        self.pos += n;
+    }
+
+    // If the line we are at the start of is a directive, the directive's
+    // name and the span of the '#', found without tokenizing the line.
+    //
+    // The name is empty if there is no identifier after the '#'.
+    pub fn peek_directive(&self) -> Option<(&'a str, Span)> {
+        let bytes = self.src.as_bytes();
+        let skip_space = |mut i: usize| {
+            while bytes.get(i).is_some_and(|&c| is_whitespace(c)) {
+                i += 1;
+            }
+            i
+        };
+
+        let hash = skip_space(self.pos);
+        let digraphs = self.version.ver_ge(CVersion::C99, CppVersion::Min);
+        let rest = &self.src[hash..];
+        let hash_len = if rest.starts_with('#') && !rest.starts_with("##") {
+            1
+        } else if digraphs
+            && rest.starts_with("%:")
+            && !rest.starts_with("%:%:")
+        {
+            2
+        } else {
+            return None;
+        };
+
+        let start = skip_space(hash + hash_len);
+        let len = self.src[start..]
+            .bytes()
+            .take_while(|&c| is_ident_nondigit(c) || c.is_ascii_digit())
+            .count();
+        let span = Span {
+            nline: self.nline,
+            nchar: self.nchar + 1 + self.src[self.pos..hash].chars().count(),
+            len: hash_len,
+        };
+        Some((&self.src[start..start + len], span))
+    }
+
+    // Whether the line we are at the start of is only whitespace.
+    pub fn at_blank_line(&self) -> bool {
+        self.pos < self.src.len()
+            && self.src[self.pos..]
+                .bytes()
+                .take_while(|&c| c != b'\n')
+                .all(is_whitespace)
+    }
+
+    // Moves to the start of the next line without tokenizing the rest of
+    // this one. Returns false if there are no more lines.
+    pub fn skip_line(&mut self) -> bool {
+        if self.pos >= self.src.len() {
+            return false;
+        }
+        match self.src[self.pos..].find('\n') {
+            Some(n) => self.pos += n + 1,
+            None => self.pos = self.src.len(),
+        }
+        self.nline += 1;
+        self.nchar = 0;
+        self.line_state = LineState::Start;
+        true
+    }
+
+    // Length in bytes of the universal-character-name at `n`, if any.
+    fn ucn_len(&self, n: usize) -> Option<usize> {
+        let len = match (self.peek(n), self.peek(n + 1)) {
+            (Some(b'\\'), Some(b'u')) => 6,
+            (Some(b'\\'), Some(b'U')) => 10,
+            _ => return None,
+        };
+        if (n + 2..n + len)
+            .all(|i| matches!(self.peek(i), Some(c) if c.is_ascii_hexdigit()))
+        {
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    // Length in bytes of the identifier-nondigit at `n`, if any.
+    fn ident_nondigit_len(&self, n: usize) -> Option<usize> {
+        match self.peek(n) {
+            Some(c) if c.is_ascii() && is_ident_nondigit(c) => Some(1),
+            Some(c) if !c.is_ascii() => self.src[self.pos + n..]
+                .chars()
+                .next()
+                .map(|c| c.len_utf8()),
+            Some(b'\\') => self.ucn_len(n),
+            _ => None,
+        }
+    }
+
+    fn ident_len(&self) -> usize {
+        let mut len = 0;
+        loop {
+            if let Some(n) = self.ident_nondigit_len(len) {
+                len += n;
+            } else if matches!(self.peek(len), Some(c) if c.is_ascii_digit()) {
+                len += 1;
+            } else {
+                return len;
+            }
+        }
+    }
+
+    // Section 6.4.8, C18 spec
+    fn pp_number_len(&self) -> usize {
+        let mut len = if self.peek(0) == Some(b'.') { 2 } else { 1 };
+        loop {
+            match (self.peek(len), self.peek(len + 1)) {
+                (Some(b'e'), Some(b'+'))
+                | (Some(b'e'), Some(b'-'))
+                | (Some(b'E'), Some(b'+'))
+                | (Some(b'E'), Some(b'-'))
+                | (Some(b'p'), Some(b'+'))
+                | (Some(b'p'), Some(b'-'))
+                | (Some(b'P'), Some(b'+'))
+                | (Some(b'P'), Some(b'-')) => len += 2,
+                (Some(c), _) if c.is_ascii_digit() || c == b'.' => len += 1,
+                // C++14 digit separators.
+                (Some(b'\''), Some(c))
+                    if (c.is_ascii_alphanumeric() || c == b'_')
+                        && self
+                            .version
+                            .ver_ge(CVersion::Max, CppVersion::Cpp14) =>
+                {
+                    len += 2
+                }
+                _ => match self.ident_nondigit_len(len) {
+                    Some(n) => len += n,
+                    None => return len,
+                },
+            }
+        }
+    }
+
+    // Length in bytes of the char constant or string literal at `n`,
+    // including both quotation marks. `None` if it's not closed before the
+    // end of the line.
+    fn quoted_len(&self, n: usize) -> Option<usize> {
+        let q = self.peek(n)?;
+        let mut len = n + 1;
+        loop {
+            match self.peek(len)? {
+                b'\n' => return None,
+                b'\\' if self.peek(len + 1) != Some(b'\n') => len += 2,
+                c if c == q => return Some(len + 1),
+                _ => len += 1,
+            }
+        }
+    }
+
+    // Splits the literal at the current position into its prefix and
+    // contents, and consumes it.
+    fn quoted(&mut self, prefix_len: usize, len: usize) -> String {
+        let contents =
+            self.src[self.pos + prefix_len + 1..self.pos + len - 1].to_string();
+        self.bump(len);
+        contents
+    }
+
+    fn char_const_prefix(&self, prefix: &str) -> Option<CharConstPrefix> {
+        match prefix {
+            "" => Some(CharConstPrefix::None),
+            "L" => Some(CharConstPrefix::L),
+            "u" if self.version.ver_ge(CVersion::C11, CppVersion::Cpp11) => {
+                Some(CharConstPrefix::u)
+            }
+            "U" if self.version.ver_ge(CVersion::C11, CppVersion::Cpp11) => {
+                Some(CharConstPrefix::U)
+            }
+            "u8" if self.version.ver_ge(CVersion::Max, CppVersion::Cpp17) => {
+                Some(CharConstPrefix::u8)
+            }
+            _ => None,
+        }
+    }
+
+    fn str_lit_prefix(&self, prefix: &str) -> Option<StrLitPrefix> {
+        let unicode = self.version.ver_ge(CVersion::C11, CppVersion::Cpp11);
+        match prefix {
+            "" => Some(StrLitPrefix::None),
+            "L" => Some(StrLitPrefix::L),
+            "u" if unicode => Some(StrLitPrefix::u),
+            "U" if unicode => Some(StrLitPrefix::U),
+            "u8" if unicode => Some(StrLitPrefix::u8),
+            _ => None,
+        }
+    }
+
+    // Tries lexing a char constant or string literal with a `prefix_len`
+    // long prefix at the current position.
+    fn literal(&mut self, prefix_len: usize) -> Option<PPToken> {
+        let prefix = &self.src[self.pos..self.pos + prefix_len];
+        let tok = match self.peek(prefix_len) {
+            Some(b'\'') => {
+                let prefix = self.char_const_prefix(prefix)?;
+                let len = self.quoted_len(prefix_len)?;
+                PPToken::CharConst(CharConst(
+                    prefix,
+                    self.quoted(prefix_len, len),
+                ))
+            }
+            Some(b'"') => {
+                let prefix = self.str_lit_prefix(prefix)?;
+                let len = self.quoted_len(prefix_len)?;
+                PPToken::StrLit(StrLit(prefix, self.quoted(prefix_len, len)))
+            }
+            _ => return None,
+        };
+        Some(tok)
+    }
+
+    // Section 6.4.7, C18 spec
+    fn header_name(&mut self) -> Option<PPToken> {
+        let (close, htype) = match self.peek(0)? {
+            b'<' => (b'>', HeaderNameType::HChar),
+            b'"' => (b'"', HeaderNameType::QChar),
+            _ => return None,
+        };
+        let len = self.src[self.pos + 1..]
+            .bytes()
+            .take_while(|c| *c != b'\n')
+            .position(|c| c == close)?
+            + 2;
+        Some(PPToken::HeaderName(HeaderName(htype, self.quoted(0, len))))
+    }
+
+    // Section 6.4.6, C18 spec
+    //
+    // Returns the punctuator, whether it is a digraph and its length.
+    fn punct(&self) -> Option<(Punct, bool, usize)> {
+        let cpp = match self.version {
+            Version::Cpp(_) => true,
+            Version::C(_) => false,
+        };
+        let digraphs = self.version.ver_ge(CVersion::C99, CppVersion::Min);
+
+        // Longest first, so we get maximal munch.
+        let puncts: &[(&str, Punct, bool)] = match self.peek(0)? {
+            b'[' => &[("[", Punct::LSqrtBrk, true)],
+            b']' => &[("]", Punct::RSqrtBrk, true)],
+            b'(' => &[("(", Punct::LParen, true)],
+            b')' => &[(")", Punct::RParen, true)],
+            b'{' => &[("{", Punct::LCurlyBrk, true)],
+            b'}' => &[("}", Punct::RCurlyBrk, true)],
+            b'~' => &[("~", Punct::Comp, true)],
+            b'?' => &[("?", Punct::Quest, true)],
+            b';' => &[(";", Punct::SColon, true)],
+            b',' => &[(",", Punct::Comma, true)],
+            b'.' => &[
+                ("...", Punct::TripleDot, true),
+                (".*", Punct::DotStar, cpp),
+                (".", Punct::Dot, true),
+            ],
+            b'-' => &[
+                ("->*", Punct::PointerStar, cpp),
+                ("->", Punct::Pointer, true),
+                ("--", Punct::DoubleSub, true),
+                ("-=", Punct::MinusEq, true),
+                ("-", Punct::Sub, true),
+            ],
+            b'+' => &[
+                ("++", Punct::DoubleAdd, true),
+                ("+=", Punct::PlusEq, true),
+                ("+", Punct::Add, true),
+            ],
+            b'&' => &[
+                ("&&", Punct::DoubleAmp, true),
+                ("&=", Punct::AmpEq, true),
+                ("&", Punct::Amp, true),
+            ],
+            b'|' => &[
+                ("||", Punct::DoublePipe, true),
+                ("|=", Punct::PipeEq, true),
+                ("|", Punct::Pipe, true),
+            ],
+            b'*' => &[("*=", Punct::MulEq, true), ("*", Punct::Mul, true)],
+            b'/' => &[("/=", Punct::DivEq, true), ("/", Punct::Div, true)],
+            b'%' => &[
+                ("%:%:", Punct::DoubleHash, digraphs),
+                ("%:", Punct::Hash, digraphs),
+                ("%>", Punct::RCurlyBrk, digraphs),
+                ("%=", Punct::ModEq, true),
+                ("%", Punct::Mod, true),
+            ],
+            b'<' => {
+                // C++11 and up, '<::' is '<' followed by '::', unless the
+                // next char is ':' or '>'.
+                if digraphs
+                    && self.starts_with("<::")
+                    && self.version.ver_ge(CVersion::Max, CppVersion::Cpp11)
+                    && self.peek(3) != Some(b':')
+                    && self.peek(3) != Some(b'>')
+                {
+                    return Some((Punct::LAngleBrk, false, 1));
+                }
+                &[
+                    ("<<=", Punct::LDoubleAngleBrkEq, true),
+                    (
+                        "<=>",
+                        Punct::Spaceship,
+                        self.version.ver_ge(CVersion::Max, CppVersion::Cpp20),
+                    ),
+                    ("<<", Punct::LDoubleAngleBrk, true),
+                    ("<=", Punct::LAngleBrkEq, true),
+                    ("<:", Punct::LSqrtBrk, digraphs),
+                    ("<%", Punct::LCurlyBrk, digraphs),
+                    ("<", Punct::LAngleBrk, true),
+                ]
+            }
+            b'>' => &[
+                (">>=", Punct::RDoubleAngleBrkEq, true),
+                (">>", Punct::RDoubleAngleBrk, true),
+                (">=", Punct::RAngleBrkEq, true),
+                (">", Punct::RAngleBrk, true),
+            ],
+            b'=' => &[("==", Punct::DoubleEq, true), ("=", Punct::Eq, true)],
+            b'!' => &[("!=", Punct::NotEq, true), ("!", Punct::Not, true)],
+            b'^' => &[("^=", Punct::PowEq, true), ("^", Punct::Pow, true)],
+            b':' => &[
+                (":>", Punct::RSqrtBrk, digraphs),
+                ("::", Punct::DoubleColon, cpp),
+                (":", Punct::Colon, true),
+            ],
+            b'#' => {
+                &[("##", Punct::DoubleHash, true), ("#", Punct::Hash, true)]
+            }
+            _ => return None,
+        };
+
+        puncts
+            .iter()
+            .find(|(s, _, enabled)| *enabled && self.starts_with(s))
+            .map(|(s, p, _)| (p.clone(), *s != p.as_str(), s.len()))
+    }
+
+    fn lex(&mut self) -> Option<PPToken> {
+        let c = self.peek(0)?;
+
+        if c == b'\n' {
+            self.bump(1);
+            return Some(PPToken::NewLine);
+        }
+
+        if is_whitespace(c) {
+            let len = self.src[self.pos..]
+                .bytes()
+                .take_while(|c| is_whitespace(*c))
+                .count();
+            self.bump(len);
+            return Some(PPToken::Space);
+        }
+
+        if self.line_state == LineState::FoundInclude {
+            if let Some(tok) = self.header_name() {
+                return Some(tok);
+            }
+        }
+
+        if c.is_ascii_digit()
+            || (c == b'.'
+                && matches!(self.peek(1), Some(c) if c.is_ascii_digit()))
+        {
+            let len = self.pp_number_len();
+            let s = self.src[self.pos..self.pos + len].to_string();
+            self.bump(len);
+            return Some(PPToken::PPNumber(PPNumber(s)));
+        }
+
+        if c == b'\'' || c == b'"' {
+            if let Some(tok) = self.literal(0) {
+                return Some(tok);
+            }
+        }
+
+        if self.ident_nondigit_len(0).is_some() {
+            let len = self.ident_len();
+            if len <= 2 {
+                if let Some(tok) = self.literal(len) {
+                    return Some(tok);
+                }
+            }
+            let s = self.src[self.pos..self.pos + len].to_string();
+            self.bump(len);
+            return Some(PPToken::Identifer(Identifer(s)));
+        }
+
+        if let Some((p, digraph, len)) = self.punct() {
+            self.bump(len);
+            return Some(if digraph {
+                PPToken::Digraph(p)
+            } else {
+                PPToken::Punct(p)
+            });
+        }
+
+        // Each non-white-space character that cannot be one of the above.
+        let len = self.src[self.pos..].chars().next()?.len_utf8();
+        let s = self.src[self.pos..self.pos + len].to_string();
+        self.bump(len);
+        Some(PPToken::NonPreProc(s))
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = SpannedPPToken;
+
+    fn next(&mut self) -> Option<SpannedPPToken> {
+        let (nline, nchar, start) = (self.nline, self.nchar + 1, self.pos);
+        let tok = self.lex()?;
+        let len = self.src[start..self.pos].chars().count();
+
+        self.line_state = match (&tok, self.line_state) {
+            (PPToken::NewLine, _) => LineState::Start,
+            (PPToken::Space, s) => s,
+            (t, LineState::Start) if t.is_punct(&Punct::Hash) => {
+                LineState::FoundHash
+            }
+            (PPToken::Identifer(Identifer(i)), LineState::FoundHash)
+                if i == "include" || i == "include_next" || i == "import" =>
+            {
+                LineState::FoundInclude
+            }
+            _ => LineState::Other,
+        };
+
+        Some(SpannedPPToken(tok, Span { nline, nchar, len }))
+    }
+}
+
+// Tokenizes the entire output of `preproc_phases_1_to_3`. Use `Span::location`
+// with `output.loc_mapping` to find where each token came from.
+#[allow(dead_code)]
+pub fn tokenize(output: &Output, params: &Params) -> Vec<SpannedPPToken> {
+    Lexer::new(&output.new_file, params).collect()
+}
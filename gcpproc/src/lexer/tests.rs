@@ -0,0 +1,361 @@
+use super::*;
+use crate::common::{Location, Params};
+use crate::p1to3::preproc_phases_1_to_3;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+fn params(version: Version) -> Params {
+    Params {
+        version,
+        ..Default::default()
+    }
+}
+
+fn lex(src: &str, params: &Params) -> Vec<PPToken> {
+    let output = preproc_phases_1_to_3(src, &FILENAME, params);
+    tokenize(&output, params)
+        .into_iter()
+        .map(|SpannedPPToken(tok, _)| tok)
+        .filter(|tok| *tok != PPToken::Space && *tok != PPToken::NewLine)
+        .collect()
+}
+
+fn ident(s: &str) -> PPToken {
+    PPToken::Identifer(Identifer(s.to_string()))
+}
+
+fn num(s: &str) -> PPToken {
+    PPToken::PPNumber(PPNumber(s.to_string()))
+}
+
+fn punct(p: Punct) -> PPToken {
+    PPToken::Punct(p)
+}
+
+#[test]
+fn maximal_munch() {
+    let params = params(Version::C(CVersion::C11));
+    fassert_eq!(
+        lex("a<<=b<<c<=d<e...f..g->h-->i>>=j##k#l+++m", &params),
+        vec![
+            ident("a"),
+            punct(Punct::LDoubleAngleBrkEq),
+            ident("b"),
+            punct(Punct::LDoubleAngleBrk),
+            ident("c"),
+            punct(Punct::LAngleBrkEq),
+            ident("d"),
+            punct(Punct::LAngleBrk),
+            ident("e"),
+            punct(Punct::TripleDot),
+            ident("f"),
+            punct(Punct::Dot),
+            punct(Punct::Dot),
+            ident("g"),
+            punct(Punct::Pointer),
+            ident("h"),
+            punct(Punct::DoubleSub),
+            punct(Punct::RAngleBrk),
+            ident("i"),
+            punct(Punct::RDoubleAngleBrkEq),
+            ident("j"),
+            punct(Punct::DoubleHash),
+            ident("k"),
+            punct(Punct::Hash),
+            ident("l"),
+            punct(Punct::DoubleAdd),
+            punct(Punct::Add),
+            ident("m"),
+        ]
+    );
+}
+
+#[test]
+fn digraphs() {
+    let src = "<: :> <% %> %: %:%: %:% <::a";
+    fassert_eq!(
+        lex(src, &params(Version::C(CVersion::C99))),
+        vec![
+            PPToken::Digraph(Punct::LSqrtBrk),
+            PPToken::Digraph(Punct::RSqrtBrk),
+            PPToken::Digraph(Punct::LCurlyBrk),
+            PPToken::Digraph(Punct::RCurlyBrk),
+            PPToken::Digraph(Punct::Hash),
+            PPToken::Digraph(Punct::DoubleHash),
+            PPToken::Digraph(Punct::Hash),
+            punct(Punct::Mod),
+            PPToken::Digraph(Punct::LSqrtBrk),
+            punct(Punct::Colon),
+            ident("a"),
+        ]
+    );
+    fassert_eq!(
+        lex("<::a <::> <:::", &params(Version::Cpp(CppVersion::Cpp11))),
+        vec![
+            punct(Punct::LAngleBrk),
+            punct(Punct::DoubleColon),
+            ident("a"),
+            PPToken::Digraph(Punct::LSqrtBrk),
+            PPToken::Digraph(Punct::RSqrtBrk),
+            PPToken::Digraph(Punct::LSqrtBrk),
+            punct(Punct::DoubleColon),
+        ]
+    );
+    fassert_eq!(
+        lex("<: %:", &params(Version::C(CVersion::C89))),
+        vec![
+            punct(Punct::LAngleBrk),
+            punct(Punct::Colon),
+            punct(Punct::Mod),
+            punct(Punct::Colon),
+        ]
+    );
+}
+
+#[test]
+fn cpp_puncts() {
+    let src = "a::b.*c->*d<=>e";
+    fassert_eq!(
+        lex(src, &params(Version::Cpp(CppVersion::Cpp20))),
+        vec![
+            ident("a"),
+            punct(Punct::DoubleColon),
+            ident("b"),
+            punct(Punct::DotStar),
+            ident("c"),
+            punct(Punct::PointerStar),
+            ident("d"),
+            punct(Punct::Spaceship),
+            ident("e"),
+        ]
+    );
+    fassert_eq!(
+        lex(src, &params(Version::C(CVersion::C18))),
+        vec![
+            ident("a"),
+            punct(Punct::Colon),
+            punct(Punct::Colon),
+            ident("b"),
+            punct(Punct::Dot),
+            punct(Punct::Mul),
+            ident("c"),
+            punct(Punct::Pointer),
+            punct(Punct::Mul),
+            ident("d"),
+            punct(Punct::LAngleBrkEq),
+            punct(Punct::RAngleBrk),
+            ident("e"),
+        ]
+    );
+}
+
+#[test]
+fn pp_numbers() {
+    let src = "1 0x1p-3 1e+5f .5e+ 1.2.3 0xe+1 1_a$ 08 1'000";
+    fassert_eq!(
+        lex(src, &params(Version::Cpp(CppVersion::Cpp14))),
+        vec![
+            num("1"),
+            num("0x1p-3"),
+            num("1e+5f"),
+            num(".5e+"),
+            num("1.2.3"),
+            num("0xe+1"),
+            num("1_a$"),
+            num("08"),
+            num("1'000"),
+        ]
+    );
+    fassert_eq!(
+        lex("1'000'", &params(Version::C(CVersion::C11))),
+        vec![
+            num("1"),
+            PPToken::CharConst(CharConst(
+                CharConstPrefix::None,
+                String::from("000")
+            )),
+        ]
+    );
+}
+
+#[test]
+fn literal_prefixes() {
+    let src = r#"'a' L'b' u'c' U'd' u8'e' "f" L"g" u"h" U"i" u8"j" x"k""#;
+    let chr = |p, s: &str| PPToken::CharConst(CharConst(p, s.to_string()));
+    let strl = |p, s: &str| PPToken::StrLit(StrLit(p, s.to_string()));
+
+    fassert_eq!(
+        lex(src, &params(Version::Cpp(CppVersion::Cpp17))),
+        vec![
+            chr(CharConstPrefix::None, "a"),
+            chr(CharConstPrefix::L, "b"),
+            chr(CharConstPrefix::u, "c"),
+            chr(CharConstPrefix::U, "d"),
+            chr(CharConstPrefix::u8, "e"),
+            strl(StrLitPrefix::None, "f"),
+            strl(StrLitPrefix::L, "g"),
+            strl(StrLitPrefix::u, "h"),
+            strl(StrLitPrefix::U, "i"),
+            strl(StrLitPrefix::u8, "j"),
+            ident("x"),
+            strl(StrLitPrefix::None, "k"),
+        ]
+    );
+    fassert_eq!(
+        lex(src, &params(Version::C(CVersion::C11))),
+        vec![
+            chr(CharConstPrefix::None, "a"),
+            chr(CharConstPrefix::L, "b"),
+            chr(CharConstPrefix::u, "c"),
+            chr(CharConstPrefix::U, "d"),
+            ident("u8"),
+            chr(CharConstPrefix::None, "e"),
+            strl(StrLitPrefix::None, "f"),
+            strl(StrLitPrefix::L, "g"),
+            strl(StrLitPrefix::u, "h"),
+            strl(StrLitPrefix::U, "i"),
+            strl(StrLitPrefix::u8, "j"),
+            ident("x"),
+            strl(StrLitPrefix::None, "k"),
+        ]
+    );
+    fassert_eq!(
+        lex(r#"u'a' u8"b" L"c""#, &params(Version::C(CVersion::C99))),
+        vec![
+            ident("u"),
+            chr(CharConstPrefix::None, "a"),
+            ident("u8"),
+            strl(StrLitPrefix::None, "b"),
+            strl(StrLitPrefix::L, "c"),
+        ]
+    );
+}
+
+#[test]
+fn escapes_and_unclosed_literals() {
+    let params = params(Version::C(CVersion::C11));
+    fassert_eq!(
+        lex(r#"'\'' "a\"b\\" '"#, &params),
+        vec![
+            PPToken::CharConst(CharConst(
+                CharConstPrefix::None,
+                String::from("\\'")
+            )),
+            PPToken::StrLit(StrLit(
+                StrLitPrefix::None,
+                String::from("a\\\"b\\\\")
+            )),
+            PPToken::NonPreProc(String::from("'")),
+        ]
+    );
+}
+
+#[test]
+fn header_names() {
+    let params = params(Version::C(CVersion::C11));
+    let hname = |t, s: &str| PPToken::HeaderName(HeaderName(t, s.to_string()));
+    fassert_eq!(
+        lex(
+            "#include <a/b.h>\n # include_next \"c.h\"\n%:import<d>\n\
+             #if a<b>c\n#include <e\nf<g>\n",
+            &params
+        ),
+        vec![
+            punct(Punct::Hash),
+            ident("include"),
+            hname(HeaderNameType::HChar, "a/b.h"),
+            punct(Punct::Hash),
+            ident("include_next"),
+            hname(HeaderNameType::QChar, "c.h"),
+            PPToken::Digraph(Punct::Hash),
+            ident("import"),
+            hname(HeaderNameType::HChar, "d"),
+            punct(Punct::Hash),
+            ident("if"),
+            ident("a"),
+            punct(Punct::LAngleBrk),
+            ident("b"),
+            punct(Punct::RAngleBrk),
+            ident("c"),
+            punct(Punct::Hash),
+            ident("include"),
+            punct(Punct::LAngleBrk),
+            ident("e"),
+            ident("f"),
+            punct(Punct::LAngleBrk),
+            ident("g"),
+            punct(Punct::RAngleBrk),
+        ]
+    );
+}
+
+#[test]
+fn other_chars() {
+    let params = params(Version::C(CVersion::C11));
+    fassert_eq!(
+        lex("a@`\\ \\u00e9x é", &params),
+        vec![
+            ident("a"),
+            PPToken::NonPreProc(String::from("@")),
+            PPToken::NonPreProc(String::from("`")),
+            PPToken::NonPreProc(String::from("\\")),
+            ident("\\u00e9x"),
+            ident("é"),
+        ]
+    );
+}
+
+#[test]
+fn round_trip() {
+    let src = "#define a(x) <:x##%:%: \"s\\\"\" u8\"v\"\n\
+               int main() { return a(0x1p-3 ...); }\n";
+    let params = params(Version::C(CVersion::C11));
+    let output = preproc_phases_1_to_3(src, &FILENAME, &params);
+    let spelt: String = tokenize(&output, &params)
+        .iter()
+        .map(|SpannedPPToken(tok, _)| tok.to_string())
+        .collect();
+    fassert_eq!(spelt, output.new_file);
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+#[test]
+fn spans_map_to_source() {
+    let src = "#define  a\\\r\nb /* c\r\n d */ e\r\n\
+               f // g\r\n\
+               \x20 h  i\\\nj\n\
+               #if k\rl\r\n";
+    let params = params(Version::C(CVersion::C11));
+    let output = preproc_phases_1_to_3(src, &FILENAME, &params);
+    let idents: Vec<_> = tokenize(&output, &params)
+        .into_iter()
+        .filter_map(|SpannedPPToken(tok, span)| match tok {
+            PPToken::Identifer(Identifer(i)) => {
+                Some((i, span.location(&output.loc_mapping)))
+            }
+            _ => None,
+        })
+        .collect();
+
+    fassert_eq!(
+        idents,
+        vec![
+            (String::from("define"), Some(location!(1, 2))),
+            (String::from("ab"), Some(location!(1, 10))),
+            (String::from("e"), Some(location!(3, 7))),
+            (String::from("f"), Some(location!(4, 1))),
+            (String::from("h"), Some(location!(5, 3))),
+            (String::from("ij"), Some(location!(5, 6))),
+            (String::from("if"), Some(location!(7, 2))),
+            (String::from("k"), Some(location!(7, 5))),
+            (String::from("l"), Some(location!(8, 1))),
+        ]
+    );
+}
@@ -0,0 +1,642 @@
+//! The table of currently defined macros.
+//!
+//! Filled from `Token::Define`, `Token::DefineFn` and `Token::Undef`, and
+//! saved and restored by '#pragma push_macro' and '#pragma pop_macro'.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{Identifer, PPNumber, PPToken, Punct, Token, Variadic};
+use crate::common::{
+    CVersion, CppVersion, Issue, IssueDesc, IssueType, Location, Params,
+    Version,
+};
+
+use std::cell::Cell;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroDef {
+    // '#define name replacement-list'
+    Object(Vec<PPToken>),
+    // '#define name(params) replacement-list', the variadic parameter, if
+    // any, is not in `params`.
+    Function(Vec<Identifer>, Variadic, Vec<PPToken>),
+    // Predefined, and expanded by the `Expander` itself.
+    Dynamic(Dynamic),
+}
+
+// The macros whose expansions depend on where, or when, they're used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dynamic {
+    File,
+    Line,
+    Date,
+    Time,
+    Timestamp,
+    Counter,
+    BaseFile,
+    IncludeLevel,
+}
+
+impl Dynamic {
+    // The rest are GNU extensions.
+    fn is_standard(self) -> bool {
+        matches!(
+            self,
+            Dynamic::File | Dynamic::Line | Dynamic::Date | Dynamic::Time
+        )
+    }
+}
+
+// How `name` would be defined as `def`, e.g., "f(x) [x]", or just its name
+// if it's dynamic.
+pub fn definition(name: &Identifer, def: &MacroDef) -> String {
+    let spell = |body: &[PPToken]| -> String {
+        body.iter().map(|t| t.to_string()).collect()
+    };
+    match def {
+        MacroDef::Object(body) => format!("{} {}", name.0, spell(body)),
+        MacroDef::Function(params, variadic, body) => {
+            let mut params: Vec<_> =
+                params.iter().map(|p| p.0.clone()).collect();
+            match variadic {
+                Variadic::No => (),
+                Variadic::Anonymous => params.push("...".to_string()),
+                Variadic::Named(p) => params.push(format!("{}...", p.0)),
+            }
+            format!("{}({}) {}", name.0, params.join(", "), spell(body))
+        }
+        MacroDef::Dynamic(_) => name.0.clone(),
+    }
+}
+
+const DYNAMIC: [(&str, Dynamic); 8] = [
+    ("__FILE__", Dynamic::File),
+    ("__LINE__", Dynamic::Line),
+    ("__DATE__", Dynamic::Date),
+    ("__TIME__", Dynamic::Time),
+    ("__TIMESTAMP__", Dynamic::Timestamp),
+    ("__COUNTER__", Dynamic::Counter),
+    ("__BASE_FILE__", Dynamic::BaseFile),
+    ("__INCLUDE_LEVEL__", Dynamic::IncludeLevel),
+];
+
+// Like gcc, these can't be defined or undefined.
+const NOT_MACRO_NAMES: [&str; 1] = ["defined"];
+
+// Like gcc, defining or undefining these is warned about, even when they
+// aren't defined.
+fn is_builtin(name: &Identifer) -> bool {
+    DYNAMIC.iter().any(|(n, _)| *n == name.0)
+        || name.0 == "__has_attribute"
+        || name.0 == "__has_cpp_attribute"
+}
+
+pub const VA_ARGS: &str = "__VA_ARGS__";
+pub const VA_OPT: &str = "__VA_OPT__";
+
+pub fn is_ident(tok: &PPToken, name: &str) -> bool {
+    match tok {
+        PPToken::Identifer(Identifer(i)) => i == name,
+        _ => false,
+    }
+}
+
+impl MacroDef {
+    pub fn body(&self) -> &[PPToken] {
+        match self {
+            MacroDef::Object(body) | MacroDef::Function(_, _, body) => body,
+            MacroDef::Dynamic(_) => &[],
+        }
+    }
+
+    // The number of parameters, including the variadic one.
+    pub fn nparams(&self) -> usize {
+        match self {
+            MacroDef::Object(_) | MacroDef::Dynamic(_) => 0,
+            MacroDef::Function(params, Variadic::No, _) => params.len(),
+            MacroDef::Function(params, _, _) => params.len() + 1,
+        }
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        match self {
+            MacroDef::Function(_, Variadic::No, _)
+            | MacroDef::Object(_)
+            | MacroDef::Dynamic(_) => false,
+            MacroDef::Function(..) => true,
+        }
+    }
+
+    // The index of `ident` in the parameter list. The variadic parameter is
+    // last.
+    pub fn param(&self, ident: &PPToken) -> Option<usize> {
+        let (params, variadic, i) = match (self, ident) {
+            (MacroDef::Function(params, v, _), PPToken::Identifer(i)) => {
+                (params, v, i)
+            }
+            _ => return None,
+        };
+        params
+            .iter()
+            .position(|p| p == i)
+            .or_else(|| match variadic {
+                Variadic::Anonymous if i.0 == VA_ARGS => Some(params.len()),
+                Variadic::Named(v) if v == i => Some(params.len()),
+                _ => None,
+            })
+    }
+
+    // The index of the variadic parameter, if `ident` is it.
+    pub fn variadic_param(&self, ident: &PPToken) -> Option<usize> {
+        match self.param(ident) {
+            Some(p) if self.is_variadic() && p + 1 == self.nparams() => Some(p),
+            _ => None,
+        }
+    }
+
+    // Section 6.10.3.2p1 and 6.10.3.3p1, C11 spec
+    //
+    // In function-like macros, each '#' must be followed by a parameter, and
+    // '##' can't be at either end of any replacement list.
+    //
+    // Each '__VA_OPT__' must be followed by parenthesised tokens, which can't
+    // contain another '__VA_OPT__'.
+    //
+    // Parameters must be unique, including a named variadic one.
+    fn check(&self, name: &Identifer) -> Option<IssueDesc> {
+        if let MacroDef::Function(params, variadic, _) = self {
+            let mut all: Vec<_> = params.iter().collect();
+            if let Variadic::Named(v) = variadic {
+                all.push(v);
+            }
+            for (i, p) in all.iter().enumerate() {
+                if all[..i].contains(p) {
+                    return Some(IssueDesc::DuplicateMacroParam(p.0.clone()));
+                }
+            }
+        }
+
+        let body = self.body();
+        let is_paste = |t: Option<&PPToken>| {
+            t.is_some_and(|t| t.is_punct(&Punct::DoubleHash))
+        };
+        if is_paste(body.first()) || is_paste(body.last()) {
+            return Some(IssueDesc::PasteAtEdgeOfMacro(name.0.clone()));
+        }
+
+        if let MacroDef::Function(..) = self {
+            let mut toks = body.iter().filter(|t| **t != PPToken::Space);
+            while let Some(t) = toks.next() {
+                if t.is_punct(&Punct::Hash) {
+                    match toks.next() {
+                        Some(t) if self.param(t).is_some() => continue,
+                        Some(t)
+                            if is_ident(t, VA_OPT) && self.is_variadic() => {}
+                        _ => {
+                            return Some(IssueDesc::StringifyWithoutParam(
+                                name.0.clone(),
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.is_variadic() {
+            let mut i = 0;
+            while let Some(n) =
+                body[i..].iter().position(|t| is_ident(t, VA_OPT))
+            {
+                match va_opt_content(body, i + n) {
+                    Some((content, end))
+                        if !content.iter().any(|t| is_ident(t, VA_OPT)) =>
+                    {
+                        i = end
+                    }
+                    _ => {
+                        return Some(IssueDesc::MisplacedIdentifier(
+                            VA_OPT.to_string(),
+                        ))
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Warns about features used outside of the versions that allow them, or,
+    // with -pedantic, GNU extensions.
+    fn check_features(&self, params: &Params) -> Vec<IssueDesc> {
+        let mut descs = vec![];
+        let variadic = match self {
+            MacroDef::Function(_, v, _) => v,
+            MacroDef::Object(_) | MacroDef::Dynamic(_) => &Variadic::No,
+        };
+        match variadic {
+            Variadic::Anonymous
+                if params.version.ver_ls(CVersion::C99, CppVersion::Cpp11) =>
+            {
+                descs.push(IssueDesc::UnsupportedInVersion("...".to_string()))
+            }
+            Variadic::Named(_) if params.pedantic => descs.push(
+                IssueDesc::GnuExtension("named variadic macros".to_string()),
+            ),
+            _ => (),
+        }
+
+        let body = self.body();
+        if body.iter().any(|t| is_ident(t, VA_ARGS))
+            && *variadic != Variadic::Anonymous
+        {
+            descs.push(IssueDesc::MisplacedIdentifier(VA_ARGS.to_string()));
+        }
+        if body.iter().any(|t| is_ident(t, VA_OPT)) {
+            if !self.is_variadic() {
+                descs.push(IssueDesc::MisplacedIdentifier(VA_OPT.to_string()));
+            } else if params.version.ver_ls(CVersion::C2x, CppVersion::Cpp20) {
+                descs.push(IssueDesc::UnsupportedInVersion(VA_OPT.to_string()));
+            }
+        }
+
+        let mut toks = body.iter().filter(|t| **t != PPToken::Space);
+        let mut last = None;
+        while let Some(t) = toks.next() {
+            if params.pedantic
+                && t.is_punct(&Punct::DoubleHash)
+                && last.is_some_and(|l: &PPToken| l.is_punct(&Punct::Comma))
+                && toks.next().and_then(|t| self.variadic_param(t)).is_some()
+            {
+                descs.push(IssueDesc::GnuExtension(
+                    ", ## __VA_ARGS__".to_string(),
+                ));
+            }
+            last = Some(t);
+        }
+        descs
+    }
+}
+
+// The tokens between the parentheses after the '__VA_OPT__' at `body[i]`,
+// without surrounding whitespace, and the index after the ')'.
+pub fn va_opt_content(
+    body: &[PPToken],
+    i: usize,
+) -> Option<(&[PPToken], usize)> {
+    let lparen =
+        i + 1 + body[i + 1..].iter().position(|t| *t != PPToken::Space)?;
+    if !body[lparen].is_punct(&Punct::LParen) {
+        return None;
+    }
+    let mut depth = 0;
+    for (j, t) in body.iter().enumerate().skip(lparen + 1) {
+        match t.punct() {
+            Some(Punct::LParen) => depth += 1,
+            Some(Punct::RParen) if depth == 0 => {
+                let mut content = &body[lparen + 1..j];
+                while let [PPToken::Space, rest @ ..] = content {
+                    content = rest;
+                }
+                while let [rest @ .., PPToken::Space] = content {
+                    content = rest;
+                }
+                return Some((content, j + 1));
+            }
+            Some(Punct::RParen) => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub def: MacroDef,
+    // Where the macro was defined.
+    pub loc: Option<Location>,
+}
+
+// What the dynamic macros expand to, other than '__FILE__' and '__LINE__',
+// which come from where they're used.
+#[derive(Debug, Clone, Default)]
+pub struct Builtins {
+    pub base_file: String,
+    pub include_level: usize,
+    // When preprocessing started, e.g., "Sep  9 2001" and "01:46:40".
+    pub date: String,
+    pub time: String,
+    // When the current file was last modified, e.g.,
+    // "Sun Sep  9 01:46:40 2001".
+    pub timestamp: String,
+    // Incremented by every expansion of '__COUNTER__', even in '#if'.
+    pub counter: Cell<u64>,
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+    "Nov", "Dec",
+];
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+// The '__DATE__', '__TIME__' and '__TIMESTAMP__' spellings of `secs` since
+// the epoch, in UTC.
+pub fn date_time(secs: u64) -> (String, String, String) {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let time =
+        format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+
+    // Howard Hinnant's civil_from_days, for days since 1970-01-01.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+
+    let month = MONTHS[month as usize - 1];
+    let date = format!("{} {:2} {}", month, day, year);
+    let timestamp = format!(
+        "{} {} {:2} {} {}",
+        DAYS[(days % 7) as usize],
+        month,
+        day,
+        time,
+        year
+    );
+    (date, time, timestamp)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MacroTable {
+    macros: HashMap<Identifer, Macro>,
+    // The definitions saved by 'push_macro', `None` for when it wasn't
+    // defined, the last pushed last.
+    pushed: HashMap<Identifer, Vec<Option<Macro>>>,
+    // The answers of each predicate given by '#assert'. Predicates are apart
+    // from the macros, so a name can be both.
+    assertions: HashMap<Identifer, Vec<Vec<PPToken>>>,
+    pub builtins: Builtins,
+}
+
+impl MacroTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The macros every translation unit starts with. Like gcc, '__STDC__' is
+    // also defined for C++. With -undef, only the standard ones are.
+    pub fn predefine(&mut self, params: &Params) {
+        let mut object = vec![("__STDC__", "1")];
+        match params.version {
+            Version::C(v) if v >= CVersion::C2x => {
+                object.push(("__STDC_VERSION__", "202000L"))
+            }
+            Version::C(v) if v >= CVersion::C18 => {
+                object.push(("__STDC_VERSION__", "201710L"))
+            }
+            Version::C(v) if v >= CVersion::C11 => {
+                object.push(("__STDC_VERSION__", "201112L"))
+            }
+            Version::C(v) if v >= CVersion::C99 => {
+                object.push(("__STDC_VERSION__", "199901L"))
+            }
+            Version::C(_) => (),
+            Version::Cpp(v) if v >= CppVersion::Cpp23 => {
+                object.push(("__cplusplus", "202302L"))
+            }
+            Version::Cpp(v) if v >= CppVersion::Cpp20 => {
+                object.push(("__cplusplus", "202002L"))
+            }
+            Version::Cpp(v) if v >= CppVersion::Cpp17 => {
+                object.push(("__cplusplus", "201703L"))
+            }
+            Version::Cpp(v) if v >= CppVersion::Cpp14 => {
+                object.push(("__cplusplus", "201402L"))
+            }
+            Version::Cpp(v) if v >= CppVersion::Cpp11 => {
+                object.push(("__cplusplus", "201103L"))
+            }
+            Version::Cpp(_) => object.push(("__cplusplus", "199711L")),
+        }
+        object.push(("__STDC_HOSTED__", if params.hosted { "1" } else { "0" }));
+
+        for (name, value) in object {
+            let body = vec![PPToken::PPNumber(PPNumber(value.to_string()))];
+            self.macros.insert(
+                Identifer(name.to_string()),
+                Macro {
+                    def: MacroDef::Object(body),
+                    loc: None,
+                },
+            );
+        }
+        for (name, d) in &DYNAMIC {
+            if params.undef && !d.is_standard() {
+                continue;
+            }
+            self.macros.insert(
+                Identifer(name.to_string()),
+                Macro {
+                    def: MacroDef::Dynamic(*d),
+                    loc: None,
+                },
+            );
+        }
+    }
+
+    pub fn get(&self, name: &Identifer) -> Option<&Macro> {
+        self.macros.get(name)
+    }
+
+    pub fn is_defined(&self, name: &Identifer) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    // Section 6.10.3p2, C11 spec
+    //
+    // A macro may only be redefined if both definitions are object-like or
+    // both are function-like, with the same parameters and identical
+    // replacement lists. Whitespace separations are all considered
+    // identical, which holds for us as runs of whitespace are a single
+    // `PPToken::Space`, and the replacement lists never start or end with
+    // one.
+    //
+    // Incompatible redefinitions are still done, like gcc does.
+    pub fn define(
+        &mut self,
+        name: Identifer,
+        def: MacroDef,
+        loc: Option<Location>,
+        params: &Params,
+        issues: &mut Vec<Issue>,
+    ) {
+        if NOT_MACRO_NAMES.contains(&name.0.as_str()) {
+            issues.push(Issue::new(
+                loc,
+                IssueType::Error,
+                IssueDesc::InvalidMacroName(name.0),
+            ));
+            return;
+        }
+        if let Some(desc) = def.check(&name) {
+            issues.push(Issue::new(loc, IssueType::Error, desc));
+            return;
+        }
+        for desc in def.check_features(params) {
+            issues.push(Issue::new(loc.clone(), IssueType::Warning, desc));
+        }
+
+        if is_builtin(&name) {
+            issues.push(Issue::new(
+                loc.clone(),
+                IssueType::Warning,
+                IssueDesc::BuiltinMacroRedefined(name.0.clone()),
+            ));
+        } else if let Some(old) = self.macros.get(&name) {
+            if old.def != def {
+                issues.push(Issue::new(
+                    loc.clone(),
+                    IssueType::Warning,
+                    IssueDesc::MacroRedefined(
+                        name.0.clone(),
+                        old.loc.clone().map(Box::new),
+                    ),
+                ));
+            }
+        }
+        self.macros.insert(name, Macro { def, loc });
+    }
+
+    // Returns the old definition, if there was one.
+    pub fn undef(&mut self, name: &Identifer) -> Option<Macro> {
+        self.macros.remove(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Identifer, &Macro)> {
+        self.macros.iter()
+    }
+
+    // The definitions saved by 'push_macro', by name.
+    pub fn pushed(
+        &self,
+    ) -> impl Iterator<Item = (&Identifer, &[Option<Macro>])> {
+        self.pushed
+            .iter()
+            .map(|(name, stack)| (name, stack.as_slice()))
+    }
+
+    // https://gcc.gnu.org/onlinedocs/gcc/Push_002fPop-Macro-Pragmas.html
+    pub fn push_macro(&mut self, name: &Identifer) {
+        let old = self.macros.get(name).cloned();
+        self.pushed.entry(name.clone()).or_default().push(old);
+    }
+
+    // Like gcc, popping a macro that wasn't pushed does nothing.
+    pub fn pop_macro(&mut self, name: &Identifer) {
+        let old = match self.pushed.get_mut(name).and_then(|s| s.pop()) {
+            Some(old) => old,
+            None => return,
+        };
+        match old {
+            Some(m) => self.macros.insert(name.clone(), m),
+            None => self.macros.remove(name),
+        };
+    }
+
+    pub fn assert(&mut self, pred: Identifer, answer: Vec<PPToken>) {
+        let answers = self.assertions.entry(pred).or_default();
+        if !answers.contains(&answer) {
+            answers.push(answer);
+        }
+    }
+
+    // Without an answer, all of the predicate's answers are dropped.
+    pub fn unassert(&mut self, pred: &Identifer, answer: Option<&[PPToken]>) {
+        match answer {
+            Some(answer) => {
+                if let Some(answers) = self.assertions.get_mut(pred) {
+                    answers.retain(|a| a != answer);
+                }
+            }
+            None => {
+                self.assertions.remove(pred);
+            }
+        }
+    }
+
+    // '#predicate ( answer )' in '#if', or without an answer, whether the
+    // predicate has any.
+    pub fn is_asserted(
+        &self,
+        pred: &Identifer,
+        answer: Option<&[PPToken]>,
+    ) -> bool {
+        self.assertions
+            .get(pred)
+            .is_some_and(|answers| match answer {
+                Some(answer) => answers.iter().any(|a| a == answer),
+                None => !answers.is_empty(),
+            })
+    }
+
+    // Updates the table for '#define', '#undef', '#assert' and '#unassert'.
+    // Returns false if `token` is anything else.
+    pub fn handle_token(
+        &mut self,
+        token: &Token,
+        loc: Option<Location>,
+        params: &Params,
+        issues: &mut Vec<Issue>,
+    ) -> bool {
+        match token {
+            Token::Define(name, body) => self.define(
+                name.clone(),
+                MacroDef::Object(body.clone()),
+                loc,
+                params,
+                issues,
+            ),
+            Token::DefineFn(name, fparams, variadic, body) => self.define(
+                name.clone(),
+                MacroDef::Function(
+                    fparams.clone(),
+                    variadic.clone(),
+                    body.clone(),
+                ),
+                loc,
+                params,
+                issues,
+            ),
+            Token::Undef(name) => {
+                if NOT_MACRO_NAMES.contains(&name.0.as_str()) {
+                    issues.push(Issue::new(
+                        loc,
+                        IssueType::Error,
+                        IssueDesc::InvalidMacroName(name.0.clone()),
+                    ));
+                    return true;
+                }
+                if is_builtin(name) {
+                    issues.push(Issue::new(
+                        loc,
+                        IssueType::Warning,
+                        IssueDesc::UndefiningBuiltinMacro(name.0.clone()),
+                    ));
+                }
+                self.undef(name);
+            }
+            Token::Assert(pred, answer) => {
+                self.assert(pred.clone(), answer.clone())
+            }
+            Token::Unassert(pred, answer) => {
+                self.unassert(pred, answer.as_deref())
+            }
+            _ => return false,
+        }
+        true
+    }
+}
@@ -0,0 +1,329 @@
+use super::*;
+use crate::common::{CVersion, CppVersion, Params, Version};
+use crate::directive;
+use crate::p1to3::preproc_phases_1_to_3;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+fn define_src(src: &str) -> (MacroTable, Vec<Issue>) {
+    define_src_with(src, &Params::default())
+}
+
+fn define_src_with(src: &str, params: &Params) -> (MacroTable, Vec<Issue>) {
+    let output = preproc_phases_1_to_3(src, &FILENAME, params);
+    let (tokens, mut issues) = directive::parse(&output, params);
+    let mut macros = MacroTable::new();
+    for (token, span) in &tokens {
+        assert!(macros.handle_token(
+            token,
+            span.location(&output.loc_mapping),
+            params,
+            &mut issues
+        ));
+    }
+    (macros, issues)
+}
+
+fn ident(s: &str) -> Identifer {
+    Identifer(s.to_string())
+}
+
+#[test]
+fn define_and_undef() {
+    let (macros, issues) = define_src(
+        "#define a 1\n\
+         #define b(x) x\n\
+         #define c\n\
+         #undef c\n\
+         #undef never_defined\n",
+    );
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(
+        macros.get(&ident("a")).map(|m| m.loc.clone()),
+        Some(Some(location!(1, 1)))
+    );
+    fassert_eq!(
+        macros.get(&ident("b")).map(|m| &m.def),
+        Some(&MacroDef::Function(
+            vec![ident("x")],
+            Variadic::No,
+            vec![PPToken::Identifer(ident("x"))]
+        ))
+    );
+    assert!(!macros.is_defined(&ident("c")));
+    assert!(!macros.is_defined(&ident("never_defined")));
+}
+
+// The examples from section 6.10.3.5p8, C11 spec.
+#[test]
+fn valid_redefinitions() {
+    let (_, issues) = define_src(
+        "#define OBJ_LIKE (1-1)\n\
+         #define OBJ_LIKE /* white space */ (1-1) /* other */\n\
+         #define FUNC_LIKE(a) ( a )\n\
+         #define FUNC_LIKE( a )( /* note the white space */ \\\n\
+         \x20                        a /* other stuff on this line\n\
+         \x20                            */ )\n\
+         #define EMPTY\n\
+         #define EMPTY  \n",
+    );
+    fassert_eq!(issues, vec![]);
+}
+
+#[test]
+fn invalid_redefinitions() {
+    let (macros, issues) = define_src(
+        "#define OBJ_LIKE (1-1)\n\
+         #define OBJ_LIKE (0) // different token sequence\n\
+         #define OBJ_LIKE (0)\n\
+         #define FUNC_LIKE(a) ( a )\n\
+         #define FUNC_LIKE(b) ( a ) // different parameter usage\n\
+         #define FUNC_LIKE(b) (b)\n\
+         #define FUNC_LIKE (b)\n\
+         #define SPACE a+b\n\
+         #define SPACE a + b\n\
+         #define DIGRAPH [\n\
+         #define DIGRAPH <:\n",
+    );
+    fassert_eq!(
+        issues,
+        vec![
+            Issue::new(
+                Some(location!(2, 1)),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "OBJ_LIKE".to_string(),
+                    Some(Box::new(location!(1, 1)))
+                ),
+            ),
+            Issue::new(
+                Some(location!(5, 1)),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "FUNC_LIKE".to_string(),
+                    Some(Box::new(location!(4, 1)))
+                ),
+            ),
+            Issue::new(
+                Some(location!(6, 1)),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "FUNC_LIKE".to_string(),
+                    Some(Box::new(location!(5, 1)))
+                ),
+            ),
+            Issue::new(
+                Some(location!(7, 1)),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "FUNC_LIKE".to_string(),
+                    Some(Box::new(location!(6, 1)))
+                ),
+            ),
+            Issue::new(
+                Some(location!(9, 1)),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "SPACE".to_string(),
+                    Some(Box::new(location!(8, 1)))
+                ),
+            ),
+            Issue::new(
+                Some(location!(11, 1)),
+                IssueType::Warning,
+                IssueDesc::MacroRedefined(
+                    "DIGRAPH".to_string(),
+                    Some(Box::new(location!(10, 1)))
+                ),
+            ),
+        ]
+    );
+    // The last definition wins.
+    fassert_eq!(
+        macros.get(&ident("FUNC_LIKE")).map(|m| &m.def),
+        Some(&MacroDef::Object(vec![
+            PPToken::Punct(crate::a::Punct::LParen),
+            PPToken::Identifer(ident("b")),
+            PPToken::Punct(crate::a::Punct::RParen),
+        ]))
+    );
+}
+
+// Like gcc, a repeated parameter or "defined" is an error, and the builtins
+// can be defined and undefined, with a warning.
+#[test]
+fn invalid_definitions() {
+    let (macros, issues) = define_src(
+        "#define f(a, b, a) a\n\
+         #define g(a, a...) a\n\
+         #define defined 1\n\
+         #undef defined\n\
+         #define __LINE__ 1\n\
+         #undef __FILE__\n\
+         #define __has_attribute(x) 0\n",
+    );
+    let issue = |nline, issue_type, desc| {
+        Issue::new(Some(location!(nline, 1)), issue_type, desc)
+    };
+    fassert_eq!(
+        issues,
+        vec![
+            issue(
+                1,
+                IssueType::Error,
+                IssueDesc::DuplicateMacroParam("a".to_string())
+            ),
+            issue(
+                2,
+                IssueType::Error,
+                IssueDesc::DuplicateMacroParam("a".to_string())
+            ),
+            issue(
+                3,
+                IssueType::Error,
+                IssueDesc::InvalidMacroName("defined".to_string())
+            ),
+            issue(
+                4,
+                IssueType::Error,
+                IssueDesc::InvalidMacroName("defined".to_string())
+            ),
+            issue(
+                5,
+                IssueType::Warning,
+                IssueDesc::BuiltinMacroRedefined("__LINE__".to_string())
+            ),
+            issue(
+                6,
+                IssueType::Warning,
+                IssueDesc::UndefiningBuiltinMacro("__FILE__".to_string())
+            ),
+            issue(
+                7,
+                IssueType::Warning,
+                IssueDesc::BuiltinMacroRedefined("__has_attribute".to_string())
+            ),
+        ]
+    );
+    for name in &["f", "g", "defined"] {
+        assert!(!macros.is_defined(&ident(name)));
+    }
+    assert!(macros.is_defined(&ident("__LINE__")));
+    assert!(macros.is_defined(&ident("__has_attribute")));
+}
+
+#[test]
+fn redefine_after_undef() {
+    let (_, issues) = define_src(
+        "#define a 1\n\
+         #undef a\n\
+         #define a 2\n",
+    );
+    fassert_eq!(issues, vec![]);
+}
+
+#[test]
+fn variadic_features() {
+    let src = "#define a(...) __VA_ARGS__\n\
+               #define b(x, args...) x , ## args\n\
+               #define c(...) __VA_OPT__(x)\n\
+               #define d(x) __VA_ARGS__ __VA_OPT__(x)\n";
+    let issue = |nline, desc| {
+        Issue::new(Some(location!(nline, 1)), IssueType::Warning, desc)
+    };
+    let misplaced = vec![
+        issue(4, IssueDesc::MisplacedIdentifier("__VA_ARGS__".to_string())),
+        issue(4, IssueDesc::MisplacedIdentifier("__VA_OPT__".to_string())),
+    ];
+
+    let mut params = Params {
+        version: Version::Cpp(CppVersion::Cpp20),
+        ..Params::default()
+    };
+    let (_, issues) = define_src_with(src, &params);
+    fassert_eq!(issues, misplaced);
+
+    params.version = Version::C(CVersion::C89);
+    params.pedantic = true;
+    let (_, issues) = define_src_with(src, &params);
+    let mut expected = vec![
+        issue(1, IssueDesc::UnsupportedInVersion("...".to_string())),
+        issue(
+            2,
+            IssueDesc::GnuExtension("named variadic macros".to_string()),
+        ),
+        issue(2, IssueDesc::GnuExtension(", ## __VA_ARGS__".to_string())),
+        issue(3, IssueDesc::UnsupportedInVersion("...".to_string())),
+        issue(3, IssueDesc::UnsupportedInVersion("__VA_OPT__".to_string())),
+    ];
+    expected.extend(misplaced);
+    fassert_eq!(issues, expected);
+}
+
+#[test]
+fn malformed_va_opt() {
+    let (macros, issues) = define_src(
+        "#define a(...) __VA_OPT__ x\n\
+         #define b(...) __VA_OPT__(__VA_OPT__())\n\
+         #define c(...) __VA_OPT__(\n\
+         #define d(...) #__VA_OPT__(x)\n",
+    );
+    let issue = |nline| {
+        Issue::new(
+            Some(location!(nline, 1)),
+            IssueType::Error,
+            IssueDesc::MisplacedIdentifier("__VA_OPT__".to_string()),
+        )
+    };
+    fassert_eq!(
+        issues,
+        vec![
+            issue(1),
+            issue(2),
+            issue(3),
+            Issue::new(
+                Some(location!(4, 1)),
+                IssueType::Warning,
+                IssueDesc::UnsupportedInVersion("__VA_OPT__".to_string()),
+            ),
+        ]
+    );
+    assert!(macros.is_defined(&ident("d")));
+}
+
+#[test]
+fn dates() {
+    fassert_eq!(
+        date_time(0),
+        (
+            "Jan  1 1970".to_string(),
+            "00:00:00".to_string(),
+            "Thu Jan  1 00:00:00 1970".to_string()
+        )
+    );
+    fassert_eq!(
+        date_time(1000000000),
+        (
+            "Sep  9 2001".to_string(),
+            "01:46:40".to_string(),
+            "Sun Sep  9 01:46:40 2001".to_string()
+        )
+    );
+    fassert_eq!(
+        date_time(951782400),
+        (
+            "Feb 29 2000".to_string(),
+            "00:00:00".to_string(),
+            "Tue Feb 29 00:00:00 2000".to_string()
+        )
+    );
+    fassert_eq!(date_time(253402300799).2, "Fri Dec 31 23:59:59 9999");
+}
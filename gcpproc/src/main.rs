@@ -1,78 +1,33 @@
 #[macro_use]
-extern crate log;
-#[macro_use]
 extern crate lalrpop_util;
+#[cfg(test)]
 #[macro_use]
 extern crate lazy_static;
 
-lalrpop_mod!(pub preproc);
+lalrpop_mod!(
+    #[allow(clippy::all, unused)]
+    pub preproc
+);
 
+mod a;
+#[macro_use]
 mod common;
+mod cond;
+mod diagnostic;
+mod directive;
+mod driver;
+mod eval;
+mod expand;
+mod fallback;
+mod lexer;
+mod macros;
 mod p1to3;
+mod pragma;
+mod search;
 
-use common::{CVersion, Issue, IssueDesc, IssueType, Params, Version};
+use common::Params;
 
 use env_logger::Env;
-use logos::Logos;
-
-// These are the tokens we will pass to Lalrpop to convert to an AST.
-#[derive(Debug, PartialEq)]
-enum LalrpopToken {
-    PreprocStart,
-    NonPreproc(String),
-    Space,
-    Ident(String),
-    Number(String),
-    Punct(char),
-}
-
-#[derive(Logos, Debug, PartialEq)]
-enum SourceLex {
-    #[end]
-    End,
-
-    #[error]
-    Error,
-
-    #[token = "\n#"]
-    PreprocStart,
-
-    // Works as long as there is a new line at both the start and end of the
-    // file.
-    #[regex = "\n[^#\n][^\n]*"]
-    NonPreprocStart,
-}
-
-#[derive(Logos, Debug, PartialEq)]
-enum PreprocLex {
-    #[end]
-    End,
-
-    #[error]
-    Error,
-
-    //     preprocessing-token:
-    //                  header-name
-    //                  identifier
-    //                  pp-number
-    //                  character-constant
-    //                  string-literal
-    //                  operator
-    //                  punctuator
-    //                  each non-white-space character that cannot be one of
-    //                  the above
-    #[token = " "]
-    Space,
-
-    #[regex = "[a-zA-Z_]+"]
-    Ident,
-
-    #[regex = "[0-9]+"]
-    Number,
-
-    #[regex = "[!\"#%&'()*+,-./:;<=>?\\[\\\\\\]^_{}|~]"]
-    Punct,
-}
 
 fn main() {
     env_logger::from_env(Env::default().default_filter_or("warn")).init();
@@ -91,16 +46,11 @@ n\"
     dbg!(&ret);
     println!("{}", ret.new_file);
 
-    enum ActiveLexer {}
-
-    let mut lexer = SourceLex::lexer(&file as &str);
-
-    while lexer.token != SourceLex::End && lexer.token != SourceLex::Error {
-        dbg!((&lexer.token, lexer.slice(), lexer.range()));
-        lexer.advance();
+    // Ours, with the real preprocessor to report any failure.
+    let fallback = fallback::Fallback::default();
+    let mut cache = fallback::FailureCache::new();
+    match fallback::preprocess(file, filename, &params, &fallback, &mut cache) {
+        Ok((text, _)) => println!("{}", text),
+        Err(failure) => eprint!("{}", failure.stderr),
     }
-
-    //dbg!(preproc::GroupParser::new().parse("22"));
-    //dbg!(preproc::GroupParser::new().parse("(  22)"));
-    //dbg!(preproc::GroupParser::new().parse("(  22) \n 22"));
 }
@@ -8,7 +8,6 @@ mod tests;
 
 use crate::common::{
     CVersion, CppVersion, Issue, IssueDesc, IssueType, Location, Params,
-    Version,
 };
 
 type CharStack = [Option<(char, Location)>; 3];
@@ -20,6 +19,7 @@ enum CommentType {
     MultiLine,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq)]
 enum LineState {
     SeekingHash,
@@ -92,7 +92,7 @@ impl State {
             ([None, s1, Some((s2, _))], LineState::SeekingHash) => {
                 let s1 = s1.as_ref().map(|(s1, _)| *s1);
                 if is_whitespace(*s2) {
-                    return;
+                    // Leading whitespace, keep seeking.
                 } else if *s2 == '#' {
                     self.line_state = LineState::FoundHash
                 } else if *s2 != '?'
@@ -118,53 +118,51 @@ impl State {
                 s0
             };
 
-            if !self.lc_active.is_some() {
-                if self.quot_active.is_some()
+            if self.lc_active.is_none()
+                && (self.quot_active.is_some()
                     || (((self.last_add != Some(' ')
                         && self.last_add != Some('\n'))
                         || !is_whitespace(s0))
-                        && (s0 != '\n' || self.last_add != Some('\n')))
-                {
-                    let s0 = if is_whitespace(s0) { ' ' } else { s0 };
-                    if s0 == '\n' && self.last_add == Some(' ') {
-                        self.new_file.pop();
-                    } else {
-                        *self.oloc.nchar.as_mut().unwrap() += 1;
-                    }
+                        && (s0 != '\n' || self.last_add != Some('\n'))))
+            {
+                let s0 = if is_whitespace(s0) { ' ' } else { s0 };
+                if s0 == '\n' && self.last_add == Some(' ') {
+                    self.new_file.pop();
+                } else {
+                    *self.oloc.nchar.as_mut().unwrap() += 1;
+                }
 
-                    if s0 == '\n' {
-                        *self.oloc.nchar.as_mut().unwrap() = 0;
-                        *self.oloc.nline.as_mut().unwrap() += 1;
-                    } else if s0 != ' ' {
-                        let this_mapping = (sloc, self.oloc.clone());
-                        if if let Some(lm) = self.loc_mapping.last() {
-                            let mut lm = lm.clone();
-                            if lm.0.nline != this_mapping.0.nline {
-                                *lm.1.nline.as_mut().unwrap() =
-                                    lm.1.nline.unwrap()
-                                        + this_mapping.0.nline.unwrap()
-                                        - lm.0.nline.unwrap();
-                                *lm.0.nline.as_mut().unwrap() =
-                                    this_mapping.0.nline.unwrap();
-                                *lm.1.nchar.as_mut().unwrap() = 1;
-                                *lm.0.nchar.as_mut().unwrap() = 1;
-                            }
-                            *lm.1.nchar.as_mut().unwrap() = lm.1.nchar.unwrap()
-                                + this_mapping.0.nchar.unwrap()
-                                - lm.0.nchar.unwrap();
-                            *lm.0.nchar.as_mut().unwrap() =
-                                this_mapping.0.nchar.unwrap();
-                            lm != this_mapping
-                        } else {
-                            true
-                        } {
-                            self.loc_mapping.push(this_mapping);
+                if s0 == '\n' {
+                    *self.oloc.nchar.as_mut().unwrap() = 0;
+                    *self.oloc.nline.as_mut().unwrap() += 1;
+                } else if s0 != ' ' {
+                    let this_mapping = (sloc, self.oloc.clone());
+                    if if let Some(lm) = self.loc_mapping.last() {
+                        let mut lm = lm.clone();
+                        if lm.0.nline != this_mapping.0.nline {
+                            *lm.1.nline.as_mut().unwrap() = lm.1.nline.unwrap()
+                                + this_mapping.0.nline.unwrap()
+                                - lm.0.nline.unwrap();
+                            *lm.0.nline.as_mut().unwrap() =
+                                this_mapping.0.nline.unwrap();
+                            *lm.1.nchar.as_mut().unwrap() = 1;
+                            *lm.0.nchar.as_mut().unwrap() = 1;
                         }
+                        *lm.1.nchar.as_mut().unwrap() = lm.1.nchar.unwrap()
+                            + this_mapping.0.nchar.unwrap()
+                            - lm.0.nchar.unwrap();
+                        *lm.0.nchar.as_mut().unwrap() =
+                            this_mapping.0.nchar.unwrap();
+                        lm != this_mapping
+                    } else {
+                        true
+                    } {
+                        self.loc_mapping.push(this_mapping);
                     }
-
-                    self.new_file.push(s0);
-                    self.last_add = Some(s0);
                 }
+
+                self.new_file.push(s0);
+                self.last_add = Some(s0);
             }
         }
     }
@@ -216,7 +214,7 @@ impl State {
         let mut stack = self.cur_stack(stacks);
         *self.cloc.nchar.as_mut().unwrap() += 1;
 
-        if !self.lc_active.is_some() {
+        if self.lc_active.is_none() {
             match self.quot_active {
                 None if b == '\'' || b == '"' => {
                     self.quot_active = Some((b, self.cloc.clone()))
@@ -240,10 +238,9 @@ impl State {
 
         if b == '\n' {
             if stack[2].as_ref().map(|s| s.0) != Some('\\') {
-                if let Some(ref mut lca) = self.lc_active {
-                    if lca.0 == CommentType::SingleLine {
-                        self.end_comment(stack)
-                    }
+                if let Some((CommentType::SingleLine, _)) = self.lc_active {
+                    self.end_comment(stack);
+                    stack = self.cur_stack(stacks);
                 }
 
                 if let Some(ref qa) = self.quot_active {
@@ -338,6 +335,8 @@ impl State {
                     [_, _, Some(('*', _))],
                     '/',
                 ) => {
+                    // Nothing in a comment is output, so just forget it.
+                    *stack = [None, None, None];
                     self.lc_active = None;
                     return;
                 }
@@ -359,15 +358,14 @@ pub struct Output {
 // Will return the number of whitespaces (including new lines) and the prossessed
 // file.
 //
-// TODO: Officially, newlines are either '\n', '\r' or '\r\n'.... however, we
-// only treat '\n' as a newline.
+// Newlines are either '\n', '\r' or '\r\n', and are all normalised to '\n'.
 pub fn preproc_phases_1_to_3(
     file: &str,
     filename: &str,
     params: &Params,
 ) -> Output {
     let mut stacks: [CharStack; 2] = [[None, None, None], [None, None, None]];
-    let mut state = State::new(filename, &file);
+    let mut state = State::new(filename, file);
 
     // Makes our processing a lot easier, to insert a newline at the start.
     //
@@ -379,12 +377,17 @@ pub fn preproc_phases_1_to_3(
     //
     // But before that, we issue a warning if the behaviour is undefined.
     if params.version.ver_le(CVersion::Max, CppVersion::Cpp03)
-        && file.len() != 0
+        && !file.is_empty()
     {
-        if &file[file.len() - 1..file.len()] != "\n"
-            || &file[file.len() - 2..file.len() - 1] == "\\"
-            || &file[file.len() - 4..file.len() - 1] == "??/"
+        let missing_newline = match file
+            .strip_suffix("\r\n")
+            .or_else(|| file.strip_suffix('\n'))
+            .or_else(|| file.strip_suffix('\r'))
         {
+            Some(body) => body.ends_with('\\') || body.ends_with("??/"),
+            None => true,
+        };
+        if missing_newline {
             state.issues.push(Issue::new(
                 None,
                 IssueType::Warning,
@@ -404,7 +407,16 @@ pub fn preproc_phases_1_to_3(
     }
 
     state.process_char(params, &mut stacks, '\n');
-    for b in file.chars() {
+    let mut chars = file.chars().peekable();
+    while let Some(b) = chars.next() {
+        let b = if b == '\r' {
+            if chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            '\n'
+        } else {
+            b
+        };
         state.process_char(params, &mut stacks, b);
     }
     state.process_char(params, &mut stacks, '\n');
@@ -427,15 +439,12 @@ pub fn preproc_phases_1_to_3(
         _ => (),
     }
 
-    match state.quot_active {
-        Some((q, loc)) => {
-            state.issues.push(Issue::new(
-                Some(loc),
-                IssueType::Error,
-                IssueDesc::QuotationMarkNotClosed(q),
-            ));
-        }
-        _ => (),
+    if let Some((q, loc)) = state.quot_active {
+        state.issues.push(Issue::new(
+            Some(loc),
+            IssueType::Error,
+            IssueDesc::QuotationMarkNotClosed(q),
+        ));
     }
 
     Output {
@@ -1,8 +1,5 @@
 use super::*;
-use crate::common::{
-    CVersion, CharNumber, Issue, IssueDesc, IssueType, LineNumber, Params,
-    Version,
-};
+use crate::common::{CVersion, Issue, IssueDesc, IssueType, Params, Version};
 
 lazy_static! {
     static ref FILENAME: String = String::from("filename");
@@ -59,13 +56,8 @@ fn do_every<F: Fn(&str, &Params, bool)>(src: &str, mut params: Params, f: F) {
 
 fn output_will_preserve_trigraphs(params: &Params, conv: bool) -> bool {
     if conv {
-        if !params.trigraphs
+        !params.trigraphs
             || params.version.ver_ge(CVersion::Max, CppVersion::Cpp14)
-        {
-            true
-        } else {
-            false
-        }
     } else {
         false
     }
@@ -105,7 +97,7 @@ fn add_tri_issue(
                 _ => Some(i),
             },
         })
-        .filter_map(|mut i| match i.desc {
+        .map(|mut i| match i.desc {
             IssueDesc::TrigraphPresent(c) => {
                 let tp = if !params.wtrigraphs || !conv {
                     None
@@ -122,12 +114,12 @@ fn add_tri_issue(
                         i.desc = IssueDesc::TrigraphPresentAndIgnored(c);
                         Some(i)
                     };
-                Some((tp, tpi))
+                (tp, tpi)
             }
-            _ => Some((Some(i), None)),
+            _ => (Some(i), None),
         })
         .flat_map(|(a, b)| vec![a, b])
-        .filter_map(|a| a)
+        .flatten()
         .collect()
 }
 
@@ -175,7 +167,7 @@ fn locations(
 fn blank() {
     do_every("", Default::default(), |src, params, conv| {
         fassert_eq!(
-            preproc_phases_1_to_3(src, &*FILENAME, params),
+            preproc_phases_1_to_3(src, &FILENAME, params),
             Output {
                 new_file: String::from("\n"),
                 issues: add_tri_issue(vec![], params, conv),
@@ -195,12 +187,16 @@ fn absent_newline() {
             |src, params, conv| {
                 let owpt = output_will_preserve_trigraphs(params, conv);
                 fassert_eq!(
-                    preproc_phases_1_to_3(src, &*FILENAME, params),
+                    preproc_phases_1_to_3(src, &FILENAME, params),
                     Output {
                         new_file: if owpt {
                             "\n".to_string()
                                 + src
-                                + if !owpt || *ending == "" { "\n" } else { "" }
+                                + if !owpt || ending.is_empty() {
+                                    "\n"
+                                } else {
+                                    ""
+                                }
                         } else {
                             String::from("\n#a\n#a\n")
                         },
@@ -291,7 +287,7 @@ fn absent_newline() {
 fn unifty_non_preproc() {
     do_every("a\nb\nc\nd\n", Default::default(), |src, params, conv| {
         fassert_eq!(
-            preproc_phases_1_to_3(src, &*FILENAME, params),
+            preproc_phases_1_to_3(src, &FILENAME, params),
             Output {
                 new_file: String::from("\na\nb\nc\nd\n"),
                 issues: add_tri_issue(vec![], params, conv),
@@ -317,7 +313,7 @@ fn unifty_mixed_preproc() {
         |src, params, conv| {
             let owpt = output_will_preserve_trigraphs(params, conv);
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: if owpt {
                         String::from("\n") + src
@@ -495,7 +491,7 @@ fn singleline_merge() {
         |src, params, conv| {
             let owpt = output_will_preserve_trigraphs(params, conv);
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: if owpt {
                         trigraphify(
@@ -519,7 +515,7 @@ fn singleline_merge() {
                             )
                         )]
                         .into_iter()
-                        .chain((0..SLASHES).into_iter().map(|i| (
+                        .chain((0..SLASHES).map(|i| (
                             NeedConv::No,
                             NeedOWPT::No,
                             Issue::new(
@@ -548,7 +544,7 @@ fn singleline_merge() {
                             ),
                         ]
                         .into_iter()
-                        .chain((0..SLASHES - 2).into_iter().map(|i| (
+                        .chain((0..SLASHES - 2).map(|i| (
                             NeedConv::Yes,
                             NeedOWPT::Invalid,
                             (location!(1, 8 + i * 3), location!(2, 4 + i))
@@ -588,7 +584,7 @@ fn singleline_merge_nonblank() {
         |src, params, conv| {
             let owpt = output_will_preserve_trigraphs(params, conv);
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: if owpt {
                         trigraphify(&(String::from("\n") + src))
@@ -608,7 +604,7 @@ fn singleline_merge_nonblank() {
                             )
                         ),]
                         .into_iter()
-                        .chain((0..SLASHES).into_iter().map(|i| (
+                        .chain((0..SLASHES).map(|i| (
                             NeedConv::No,
                             NeedOWPT::No,
                             Issue::new(
@@ -617,7 +613,7 @@ fn singleline_merge_nonblank() {
                                 IssueDesc::TrigraphPresent('/')
                             )
                         )))
-                        .chain((0..NEWLINES).into_iter().map(|i| (
+                        .chain((0..NEWLINES).map(|i| (
                             NeedConv::No,
                             NeedOWPT::No,
                             Issue::new(
@@ -646,7 +642,7 @@ fn singleline_merge_nonblank() {
                             ),
                         ]
                         .into_iter()
-                        .chain((0..SLASHES - 2).into_iter().map(|i| (
+                        .chain((0..SLASHES - 2).map(|i| (
                             NeedConv::Yes,
                             NeedOWPT::Invalid,
                             (location!(1, 8 + i * 3), location!(2, 4 + i))
@@ -663,7 +659,7 @@ fn singleline_merge_nonblank() {
                                 (location!(2, 4), location!(2, 8))
                             ),
                         ])
-                        .chain((0..NEWLINES - 1).into_iter().map(|i| (
+                        .chain((0..NEWLINES - 1).map(|i| (
                             NeedConv::Yes,
                             NeedOWPT::Invalid,
                             (location!(3 + i, 4), location!(3 + i, 2))
@@ -707,7 +703,7 @@ fn header_comment() {
                 }
 
                 fassert_eq!(
-                    preproc_phases_1_to_3(src, &*FILENAME, params),
+                    preproc_phases_1_to_3(src, &FILENAME, params),
                     Output {
                         new_file,
                         issues: add_tri_issue(
@@ -740,13 +736,25 @@ fn header_comment() {
                             ]
                             .into_iter()
                             .chain(if comment.0 == "/*" {
-                                Some((
-                                    NeedConv::No,
-                                    NeedOWPT::No,
-                                    (location!(1, 20), location!(2, 8)),
-                                ))
+                                vec![
+                                    (
+                                        NeedConv::Invalid,
+                                        NeedOWPT::No,
+                                        (location!(1, 20), location!(2, 8)),
+                                    ),
+                                    (
+                                        NeedConv::Yes,
+                                        NeedOWPT::Yes,
+                                        (location!(1, 22), location!(2, 10)),
+                                    ),
+                                    (
+                                        NeedConv::Yes,
+                                        NeedOWPT::Invalid,
+                                        (location!(1, 22), location!(2, 8)),
+                                    ),
+                                ]
                             } else {
-                                None
+                                vec![]
                             })
                             .collect()
                         ),
@@ -757,6 +765,72 @@ fn header_comment() {
     }
 }
 
+#[test]
+fn newline_kinds() {
+    let src = "a\n#b\\\nc\\\nd\ne\nf // g\n#h\\\ni /* j\nk */\n#l\nm\n";
+    for ending in &["\r", "\r\n"] {
+        eprintln!("Testing for ending {:?}", ending);
+        let mut mixed = String::new();
+        for (i, line) in src.split_terminator('\n').enumerate() {
+            mixed += line;
+            mixed += if i % 2 == 0 { ending } else { "\n" };
+        }
+
+        for src in &[src.replace('\n', ending), mixed] {
+            do_every(src, Default::default(), |src, params, _| {
+                let unix_src = src.replace("\r\n", "\n").replace('\r', "\n");
+                fassert_eq!(
+                    preproc_phases_1_to_3(src, &FILENAME, params),
+                    preproc_phases_1_to_3(&unix_src, &FILENAME, params),
+                );
+            });
+        }
+    }
+}
+
+#[test]
+fn crlf_line_splice() {
+    do_every("#a\\\r\nb\r\n", Default::default(), |src, params, conv| {
+        let owpt = output_will_preserve_trigraphs(params, conv);
+        let output = preproc_phases_1_to_3(src, &FILENAME, params);
+        fassert_eq!(
+            output.new_file,
+            if owpt {
+                String::from("\n??=a??/\nb\n")
+            } else {
+                String::from("\n#ab\n")
+            }
+        );
+        assert!(!output
+            .issues
+            .iter()
+            .any(|i| i.desc == IssueDesc::FileEndMissingNewline));
+    });
+}
+
+#[test]
+fn consecutive_comments() {
+    do_every(
+        "#a /* b */ c // d\n#e /* f\n*/ /* g */ h\n",
+        Default::default(),
+        |src, params, conv| {
+            let owpt = output_will_preserve_trigraphs(params, conv);
+            let new_file = match params.version {
+                Version::C(CVersion::C89) => "\n#a c // d\n#e h\n",
+                _ => "\n#a c\n#e h\n",
+            };
+            fassert_eq!(
+                preproc_phases_1_to_3(src, &FILENAME, params).new_file,
+                if owpt {
+                    trigraphify(new_file)
+                } else {
+                    new_file.to_string()
+                }
+            );
+        },
+    );
+}
+
 /*
 #[test]
 fn quote_comment() {
@@ -766,7 +840,7 @@ fn quote_comment() {
             Default::default(),
             |src, params, conv| {
                 fassert_eq!(
-                    preproc_phases_1_to_3(src, &*FILENAME, params),
+                    preproc_phases_1_to_3(src, &FILENAME, params),
                     Output {
                         new_file: String::from("\n#a ")
                             + quo
@@ -796,7 +870,7 @@ fn line_merge_simple() {
             Default::default(),
             |src, params, conv| {
                 fassert_eq!(
-                    preproc_phases_1_to_3(src, &*FILENAME, params),
+                    preproc_phases_1_to_3(src, &FILENAME, params),
                     Output {
                         new_file: String::from("\n"),
                         issues: add_tri_issue(vec![], params, conv),
@@ -815,7 +889,7 @@ fn singleline_merge_comment() {
         Default::default(),
         |src, params, conv| {
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: String::from("\n"),
                     issues: add_tri_issue(vec![], params, conv),
@@ -833,7 +907,7 @@ fn multilineline_merge_comment() {
         Default::default(),
         |src, params, conv| {
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: String::from("\n"),
                     issues: add_tri_issue(vec![], params, conv),
@@ -848,7 +922,7 @@ fn multilineline_merge_comment() {
 fn multiline_comment() {
     do_every("#a /* b */ c\n", Default::default(), |src, params, conv| {
         fassert_eq!(
-            preproc_phases_1_to_3(src, &*FILENAME, params),
+            preproc_phases_1_to_3(src, &FILENAME, params),
             Output {
                 new_file: String::from("\n"),
                 issues: add_tri_issue(vec![], params, conv),
@@ -865,7 +939,7 @@ fn multiline_comment_split() {
         Default::default(),
         |src, params, conv| {
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: String::from("\n"),
                     issues: add_tri_issue(vec![], params, conv),
@@ -883,7 +957,7 @@ fn multiline_comment_merge() {
         Default::default(),
         |src, params, conv| {
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: String::from("\n"),
                     issues: add_tri_issue(vec![], params, conv),
@@ -901,7 +975,7 @@ fn singleline_comment_merge_split() {
         Default::default(),
         |src, params, conv| {
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: String::from("\n"),
                     issues: add_tri_issue(vec![], params, conv),
@@ -919,7 +993,7 @@ fn multiline_comment_merge_split() {
         Default::default(),
         |src, params, conv| {
             fassert_eq!(
-                preproc_phases_1_to_3(src, &*FILENAME, params),
+                preproc_phases_1_to_3(src, &FILENAME, params),
                 Output {
                     new_file: String::from("\n"),
                     issues: add_tri_issue(vec![], params, conv),
@@ -948,7 +1022,7 @@ fn cross_quote_line_merge() {
                     let owpt = output_will_preserve_trigraphs(params, conv);
                     let ms = 6 + if *m == " " { 1 } else { 0 };
                     fassert_eq!(
-                        preproc_phases_1_to_3(src, &*FILENAME, params),
+                        preproc_phases_1_to_3(src, &FILENAME, params),
                         Output {
                             new_file: if owpt {
                                 "\n".to_string() + src
@@ -0,0 +1,158 @@
+//! The pragmas we don't carry out ourselves.
+//!
+//! https://gcc.gnu.org/onlinedocs/cpp/Pragmas.html
+//!
+//! Handlers are registered by namespace, e.g., "omp", or by namespace and
+//! name, e.g., "clang loop". A pragma goes to the handler for its first two
+//! identifiers, or if there's none, the one for its first. Like gcc, those
+//! without one are written to the output as they are, for the compiler. The
+//! compiler's own pragmas are registered to be, so only the rest are unknown,
+//! and warned about with -Wunknown-pragmas.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{Identifer, PPToken};
+use crate::common::{Issue, Location};
+
+use std::collections::HashMap;
+
+// The pragmas gcc's compiler knows about, without -fopenmp and the like.
+const COMPILER_PRAGMAS: &[(&str, Option<&str>)] = &[
+    ("pack", None),
+    ("weak", None),
+    ("redefine_extname", None),
+    ("message", None),
+    ("scalar_storage_order", None),
+    ("STDC", None),
+    ("GCC", Some("visibility")),
+    ("GCC", Some("target")),
+    ("GCC", Some("optimize")),
+    ("GCC", Some("push_options")),
+    ("GCC", Some("pop_options")),
+    ("GCC", Some("reset_options")),
+    ("GCC", Some("ivdep")),
+    ("GCC", Some("unroll")),
+    ("GCC", Some("pch_preprocess")),
+];
+
+// Only an embedder's handlers consume or rewrite pragmas.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PragmaAction {
+    // Dropped from the output.
+    Consume,
+    // Written as '#pragma tokens' instead.
+    Rewrite(Vec<PPToken>),
+    // Written as it is.
+    PassThrough,
+}
+
+pub trait PragmaHandler {
+    // `toks` are the pragma's tokens after 'pragma', from its namespace on.
+    // Its issues are pushed to `issues`.
+    fn handle(
+        &mut self,
+        toks: &[PPToken],
+        loc: Option<&Location>,
+        issues: &mut Vec<Issue>,
+    ) -> PragmaAction;
+}
+
+impl<F> PragmaHandler for F
+where
+    F: FnMut(&[PPToken], Option<&Location>, &mut Vec<Issue>) -> PragmaAction,
+{
+    fn handle(
+        &mut self,
+        toks: &[PPToken],
+        loc: Option<&Location>,
+        issues: &mut Vec<Issue>,
+    ) -> PragmaAction {
+        self(toks, loc, issues)
+    }
+}
+
+pub struct PragmaRegistry {
+    // By namespace and name, with `None` for the whole namespace.
+    handlers: HashMap<(String, Option<String>), Box<dyn PragmaHandler>>,
+}
+
+impl Default for PragmaRegistry {
+    fn default() -> Self {
+        let mut registry = PragmaRegistry {
+            handlers: HashMap::new(),
+        };
+        for (namespace, name) in COMPILER_PRAGMAS {
+            registry.register(namespace, *name, pass_through);
+        }
+        registry
+    }
+}
+
+impl std::fmt::Debug for PragmaRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+impl PragmaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces the handler already registered for them, if any.
+    pub fn register<H: PragmaHandler + 'static>(
+        &mut self,
+        namespace: &str,
+        name: Option<&str>,
+        handler: H,
+    ) {
+        let key = (namespace.to_string(), name.map(str::to_string));
+        self.handlers.insert(key, Box::new(handler));
+    }
+
+    // What the handler for `toks` does with them, or `None` if they're
+    // unknown.
+    pub fn handle(
+        &mut self,
+        toks: &[PPToken],
+        loc: Option<&Location>,
+        issues: &mut Vec<Issue>,
+    ) -> Option<PragmaAction> {
+        let (namespace, name) = match names(toks) {
+            (Some(namespace), name) => (namespace, name),
+            (None, _) => return None,
+        };
+        let mut key = (namespace.to_string(), name.map(str::to_string));
+        if !self.handlers.contains_key(&key) {
+            key.1 = None;
+        }
+        let handler = self.handlers.get_mut(&key)?;
+        Some(handler.handle(toks, loc, issues))
+    }
+}
+
+// The handler of the compiler's pragmas.
+pub fn pass_through(
+    _: &[PPToken],
+    _: Option<&Location>,
+    _: &mut Vec<Issue>,
+) -> PragmaAction {
+    PragmaAction::PassThrough
+}
+
+// The namespace and name of the pragma `toks`, its first two tokens, if
+// they're identifiers.
+pub fn names(toks: &[PPToken]) -> (Option<&str>, Option<&str>) {
+    let mut idents =
+        toks.iter()
+            .filter(|t| **t != PPToken::Space)
+            .map(|t| match t {
+                PPToken::Identifer(Identifer(i)) => Some(i.as_str()),
+                _ => None,
+            });
+    let namespace = idents.next().flatten();
+    let name = namespace.and(idents.next().flatten());
+    (namespace, name)
+}
@@ -0,0 +1,132 @@
+use super::*;
+use crate::common::{IssueDesc, IssueType, Params};
+use crate::driver::Preprocessor;
+
+lazy_static! {
+    static ref FILENAME: String = String::from("filename");
+}
+
+macro_rules! location {
+    ($nline:expr, $nchar:expr) => {{
+        Location::new(FILENAME.clone(), $nline, $nchar)
+    }};
+}
+
+fn tident(s: &str) -> PPToken {
+    PPToken::Identifer(Identifer(s.to_string()))
+}
+
+fn consume(
+    _: &[PPToken],
+    _: Option<&Location>,
+    _: &mut Vec<Issue>,
+) -> PragmaAction {
+    PragmaAction::Consume
+}
+
+// 'acme embed_version' becomes 'acme version', and the rest of 'acme' is
+// dropped with a warning.
+fn acme(registry: &mut PragmaRegistry) {
+    registry.register(
+        "acme",
+        Some("embed_version"),
+        |_: &[PPToken], _: Option<&Location>, _: &mut Vec<Issue>| {
+            PragmaAction::Rewrite(vec![
+                tident("acme"),
+                PPToken::Space,
+                tident("version"),
+            ])
+        },
+    );
+    registry.register(
+        "acme",
+        None,
+        |_: &[PPToken], loc: Option<&Location>, issues: &mut Vec<Issue>| {
+            issues.push(Issue::new(
+                loc.cloned(),
+                IssueType::Warning,
+                IssueDesc::FromPragmaHandler("unknown acme pragma".to_string()),
+            ));
+            PragmaAction::Consume
+        },
+    );
+}
+
+#[test]
+fn registry() {
+    let mut registry = PragmaRegistry::new();
+    registry.register("clang", Some("loop"), consume);
+    registry.register("clang", None, pass_through);
+    let mut handle =
+        |toks: &[PPToken]| registry.handle(toks, None, &mut vec![]);
+    fassert_eq!(
+        handle(&[tident("clang"), PPToken::Space, tident("loop")]),
+        Some(PragmaAction::Consume)
+    );
+    fassert_eq!(
+        handle(&[tident("clang"), PPToken::Space, tident("other")]),
+        Some(PragmaAction::PassThrough)
+    );
+    fassert_eq!(handle(&[tident("clang")]), Some(PragmaAction::PassThrough));
+    fassert_eq!(
+        handle(&[tident("GCC"), PPToken::Space, tident("visibility")]),
+        Some(PragmaAction::PassThrough)
+    );
+    fassert_eq!(
+        handle(&[tident("GCC"), PPToken::Space, tident("other")]),
+        None
+    );
+    fassert_eq!(handle(&[tident("omp")]), None);
+    fassert_eq!(handle(&[PPToken::Punct(crate::a::Punct::Hash)]), None);
+}
+
+#[test]
+fn handlers() {
+    let src = "#pragma acme embed_version\n\
+               #pragma acme other\n\
+               #pragma omp parallel\n\
+               a _Pragma(\"acme embed_version\") b\n\
+               #pragma STDC FP_CONTRACT ON\n";
+    let params = Params {
+        disable_linemarkers: true,
+        wunknown_pragmas: true,
+        ..Params::default()
+    };
+    let mut pp = Preprocessor::new(&params);
+    acme(&mut pp.pragmas);
+    pp.run(src, &FILENAME);
+    fassert_eq!(
+        pp.issues,
+        vec![
+            Issue::new(
+                Some(location!(2, 1)),
+                IssueType::Warning,
+                IssueDesc::FromPragmaHandler("unknown acme pragma".to_string()),
+            ),
+            Issue::new(
+                Some(location!(3, 1)),
+                IssueType::Warning,
+                IssueDesc::UnknownPragma("omp parallel".to_string()),
+            ),
+        ]
+    );
+    fassert_eq!(
+        pp.output,
+        "#pragma acme version\n\
+         #pragma omp parallel\n\
+         a \n\
+         #pragma acme version\n \
+         b\n\
+         #pragma STDC FP_CONTRACT ON\n"
+    );
+
+    // Unknown pragmas aren't warned about by default.
+    let params = Params {
+        disable_linemarkers: true,
+        ..Params::default()
+    };
+    let mut pp = Preprocessor::new(&params);
+    pp.run("#pragma omp parallel\n", &FILENAME);
+    fassert_eq!(pp.issues, vec![]);
+    fassert_eq!(pp.output, "#pragma omp parallel\n");
+}
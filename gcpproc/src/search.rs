@@ -0,0 +1,247 @@
+//! Finding the files named by '#include'.
+//!
+//! https://gcc.gnu.org/onlinedocs/cpp/Search-Path.html
+//! https://gcc.gnu.org/onlinedocs/gcc/Directory-Options.html
+//!
+//! The directories are put in order like gcc's `incpath.c` does: the quote
+//! chain (-iquote), then the bracket chain (-I, -iwithprefixbefore), then
+//! the system chain (-isystem, the standard directories, -idirafter and
+//! -iwithprefix). Quoted names are looked for in the including file's
+//! directory first, then from the start of the quote chain, and bracketed
+//! names from the start of the bracket chain.
+
+#[cfg(test)]
+mod tests;
+
+use crate::a::{HeaderName, HeaderNameType, IncludeType};
+use crate::common::{IncludeDir, Issue, IssueDesc, IssueType, Params};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchDir {
+    pub path: PathBuf,
+    // Added by -isystem, -idirafter, -iwithprefix or the standard
+    // directories.
+    pub system: bool,
+}
+
+// Where a header was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Found {
+    pub path: PathBuf,
+    // The index in `SearchPath::dirs`, or `None` if the header was found in
+    // the including file's directory, or its name is an absolute path.
+    pub dir: Option<usize>,
+}
+
+// A header we looked for. The cache keys on every one of them, so that a
+// new file earlier in the search path is noticed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    pub itype: IncludeType,
+    pub header: HeaderName,
+    // The file with the '#include'.
+    pub includer: String,
+    pub found: Option<Found>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchPath {
+    // The quote chain, then the bracket chain, which starts at `bracket`.
+    dirs: Vec<SearchDir>,
+    bracket: usize,
+}
+
+// The directory and its canonical path, to find duplicates with.
+type Dir = (SearchDir, PathBuf);
+
+// '=' at the start of a directory stands for the sysroot, if there is one.
+fn sysroot_dir(dir: &str, params: &Params) -> String {
+    match (&params.isysroot, dir.strip_prefix('=')) {
+        (Some(sysroot), Some(rest)) => format!("{}{}", sysroot, rest),
+        _ => dir.to_string(),
+    }
+}
+
+// Like gcc, trailing slashes are dropped, but a root directory is kept.
+fn search_dir(dir: &str, system: bool) -> SearchDir {
+    let trimmed = dir.trim_end_matches('/');
+    let dir = if trimmed.is_empty() && !dir.is_empty() {
+        "/"
+    } else {
+        trimmed
+    };
+    SearchDir {
+        path: PathBuf::from(dir),
+        system,
+    }
+}
+
+// Drops the directories that don't exist, aren't directories, are in
+// `system`, or come earlier in `chain`. If the last one is the same as
+// `join`, the first directory of the next chain, it's dropped too.
+fn remove_duplicates(
+    chain: Vec<SearchDir>,
+    system: &[Dir],
+    join: Option<&Dir>,
+    issues: &mut Vec<Issue>,
+) -> Vec<Dir> {
+    let mut ret: Vec<Dir> = vec![];
+    let len = chain.len();
+    for (i, dir) in chain.into_iter().enumerate() {
+        match fs::metadata(&dir.path) {
+            Ok(m) if m.is_dir() => (),
+            Ok(_) => {
+                issues.push(Issue::new(
+                    None,
+                    IssueType::Warning,
+                    IssueDesc::NotADirectory(dir.path.display().to_string()),
+                ));
+                continue;
+            }
+            Err(_) => continue,
+        }
+        let canonical = match fs::canonicalize(&dir.path) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+
+        let dup = system
+            .iter()
+            .chain(ret.iter())
+            .any(|(_, c)| *c == canonical)
+            || (i + 1 == len && join.is_some_and(|(_, c)| *c == canonical));
+        if !dup {
+            ret.push((dir, canonical));
+        }
+    }
+    ret
+}
+
+impl SearchPath {
+    pub fn new(params: &Params, issues: &mut Vec<Issue>) -> Self {
+        let mut quote = vec![];
+        let mut bracket = vec![];
+        let mut system = vec![];
+        let mut after = vec![];
+        let mut prefix = "";
+        for opt in &params.include_dirs {
+            match opt {
+                IncludeDir::I(d) => {
+                    bracket.push(search_dir(&sysroot_dir(d, params), false))
+                }
+                IncludeDir::IQuote(d) => {
+                    quote.push(search_dir(&sysroot_dir(d, params), false))
+                }
+                IncludeDir::ISystem(d) => {
+                    system.push(search_dir(&sysroot_dir(d, params), true))
+                }
+                IncludeDir::IDirAfter(d) => {
+                    after.push(search_dir(&sysroot_dir(d, params), true))
+                }
+                IncludeDir::IPrefix(p) => prefix = p,
+                // The prefix is prepended as is, so it should end with a '/'
+                // if it's a directory.
+                IncludeDir::IWithPrefix(d) => {
+                    after.push(search_dir(&format!("{}{}", prefix, d), true))
+                }
+                IncludeDir::IWithPrefixBefore(d) => {
+                    bracket.push(search_dir(&format!("{}{}", prefix, d), false))
+                }
+            }
+        }
+
+        for (dir, multilib) in &params.std_dirs {
+            let mut dir = match &params.isysroot {
+                Some(sysroot) => format!("{}{}", sysroot, dir),
+                None => dir.clone(),
+            };
+            if let (true, Some(m)) = (multilib, &params.imultilib) {
+                dir = format!("{}/{}", dir, m);
+            }
+            system.push(search_dir(&dir, true));
+        }
+
+        system.extend(after);
+        let system = remove_duplicates(system, &[], None, issues);
+        let bracket =
+            remove_duplicates(bracket, &system, system.first(), issues);
+        let quote = remove_duplicates(
+            quote,
+            &system,
+            bracket.first().or_else(|| system.first()),
+            issues,
+        );
+
+        let nquote = quote.len();
+        SearchPath {
+            dirs: quote
+                .into_iter()
+                .chain(bracket)
+                .chain(system)
+                .map(|(d, _)| d)
+                .collect(),
+            bracket: nquote,
+        }
+    }
+
+    // Looks for `header`, which was included by the file called `includer`.
+    pub fn find(&self, header: &HeaderName, includer: &str) -> Option<Found> {
+        let HeaderName(htype, name) = header;
+        if Path::new(name).is_absolute() {
+            let path = PathBuf::from(name);
+            return if path.is_file() {
+                Some(Found { path, dir: None })
+            } else {
+                None
+            };
+        }
+
+        let start = match htype {
+            HeaderNameType::QChar => {
+                let dir = Path::new(includer).parent().unwrap_or(Path::new(""));
+                let path = dir.join(name);
+                if path.is_file() {
+                    return Some(Found { path, dir: None });
+                }
+                0
+            }
+            HeaderNameType::HChar => self.bracket,
+        };
+        self.find_from(name, start)
+    }
+
+    // '#include_next', from a file found in `dirs[dir]`, or in the including
+    // file's directory if `dir` is `None`, continues the search from the
+    // directory after. Like gcc, that's the start of the quote chain for
+    // the including file's directory, even for bracketed names.
+    pub fn find_next(
+        &self,
+        header: &HeaderName,
+        dir: Option<usize>,
+    ) -> Option<Found> {
+        if Path::new(&header.1).is_absolute() {
+            return self.find(header, "");
+        }
+        self.find_from(&header.1, dir.map_or(0, |d| d + 1))
+    }
+
+    pub fn is_system(&self, dir: usize) -> bool {
+        self.dirs[dir].system
+    }
+
+    // Looks for `name` in the directories from `dirs[start]` onwards.
+    pub fn find_from(&self, name: &str, start: usize) -> Option<Found> {
+        self.dirs
+            .iter()
+            .enumerate()
+            .skip(start)
+            .map(|(i, d)| Found {
+                path: d.path.join(name),
+                dir: Some(i),
+            })
+            .find(|f| f.path.is_file())
+    }
+}
@@ -0,0 +1,734 @@
+use super::*;
+use crate::common::{Location, MacroDefine};
+use crate::driver::Preprocessor;
+
+// A fresh directory for the test called `name`, with `files` in it. Names
+// ending with '/' are empty directories.
+fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!(
+        "gcpproc-search-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    for (file, contents) in files {
+        let path = root.join(file);
+        if file.ends_with('/') {
+            fs::create_dir_all(&path).unwrap();
+        } else {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+        }
+    }
+    root
+}
+
+fn dir(root: &Path, d: &str) -> String {
+    root.join(d).display().to_string()
+}
+
+fn quoted(name: &str) -> HeaderName {
+    HeaderName(HeaderNameType::QChar, name.to_string())
+}
+
+fn bracketed(name: &str) -> HeaderName {
+    HeaderName(HeaderNameType::HChar, name.to_string())
+}
+
+#[test]
+fn search_order() {
+    let root = tree(
+        "order",
+        &[
+            ("main/a.h", ""),
+            ("q/a.h", ""),
+            ("q/b.h", ""),
+            ("i/b.h", ""),
+            ("i/c.h", ""),
+            ("s/c.h", ""),
+            ("s/d.h", ""),
+            ("std/d.h", ""),
+            ("std/e.h", ""),
+            ("after/e.h", ""),
+            ("after/f.h", ""),
+        ],
+    );
+    let params = Params {
+        include_dirs: vec![
+            IncludeDir::IDirAfter(dir(&root, "after")),
+            IncludeDir::ISystem(dir(&root, "s")),
+            IncludeDir::I(dir(&root, "i")),
+            IncludeDir::IQuote(dir(&root, "q")),
+        ],
+        std_dirs: vec![(dir(&root, "std"), false)],
+        ..Params::default()
+    };
+    let mut issues = vec![];
+    let search = SearchPath::new(&params, &mut issues);
+    fassert_eq!(issues, vec![]);
+    fassert_eq!(search.bracket, 1);
+
+    let includer = dir(&root, "main/main.c");
+    let find =
+        |h: HeaderName| search.find(&h, &includer).map(|f| (f.path, f.dir));
+    let found = |d: &str, name: &str, i| Some((root.join(d).join(name), i));
+    fassert_eq!(find(quoted("a.h")), Some((root.join("main/a.h"), None)));
+    fassert_eq!(find(quoted("b.h")), found("q", "b.h", Some(0)));
+    fassert_eq!(find(bracketed("a.h")), None);
+    fassert_eq!(find(bracketed("b.h")), found("i", "b.h", Some(1)));
+    fassert_eq!(find(quoted("c.h")), found("i", "c.h", Some(1)));
+    fassert_eq!(find(bracketed("d.h")), found("s", "d.h", Some(2)));
+    fassert_eq!(find(bracketed("e.h")), found("std", "e.h", Some(3)));
+    fassert_eq!(find(quoted("f.h")), found("after", "f.h", Some(4)));
+    fassert_eq!(find(quoted("missing.h")), None);
+    let abs = dir(&root, "after/f.h");
+    fassert_eq!(find(bracketed(&abs)), Some((PathBuf::from(abs), None)));
+
+    let systems: Vec<_> = search.dirs.iter().map(|d| d.system).collect();
+    fassert_eq!(systems, vec![false, false, true, true, true]);
+}
+
+#[test]
+fn duplicates() {
+    let root = tree("duplicates", &[("a/", ""), ("b/", ""), ("file", "")]);
+    let params = Params {
+        include_dirs: vec![
+            IncludeDir::IQuote(dir(&root, "b")),
+            IncludeDir::IQuote(dir(&root, "a")),
+            IncludeDir::I(dir(&root, "a/")),
+            IncludeDir::I(dir(&root, "b")),
+            IncludeDir::I(dir(&root, "a/../b")),
+            IncludeDir::I(dir(&root, "missing")),
+            IncludeDir::I(dir(&root, "file")),
+            IncludeDir::ISystem(dir(&root, "b")),
+        ],
+        ..Params::default()
+    };
+    let mut issues = vec![];
+    let search = SearchPath::new(&params, &mut issues);
+    fassert_eq!(
+        issues,
+        vec![Issue::new(
+            None,
+            IssueType::Warning,
+            IssueDesc::NotADirectory(dir(&root, "file")),
+        )]
+    );
+
+    // 'b' is a system directory, so it's dropped from the other chains.
+    // The last quote directory, 'a', is dropped as the bracket chain starts
+    // with it.
+    fassert_eq!(
+        search.dirs,
+        vec![
+            SearchDir {
+                path: root.join("a"),
+                system: false
+            },
+            SearchDir {
+                path: root.join("b"),
+                system: true
+            },
+        ]
+    );
+    fassert_eq!(search.bracket, 0);
+}
+
+#[test]
+fn prefixes_and_sysroot() {
+    let root = tree(
+        "prefixes",
+        &[("before/", ""), ("after/", ""), ("i/", ""), ("std/32/", "")],
+    );
+    let params = Params {
+        include_dirs: vec![
+            IncludeDir::IWithPrefix("after".to_string()),
+            IncludeDir::IPrefix(format!("{}/", root.display())),
+            IncludeDir::IWithPrefix("after".to_string()),
+            IncludeDir::IWithPrefixBefore("before".to_string()),
+            IncludeDir::I("=/i".to_string()),
+        ],
+        isysroot: Some(root.display().to_string()),
+        imultilib: Some("32".to_string()),
+        std_dirs: vec![("/std".to_string(), true)],
+        ..Params::default()
+    };
+    let mut issues = vec![];
+    let search = SearchPath::new(&params, &mut issues);
+    fassert_eq!(issues, vec![]);
+    let paths: Vec<_> = search.dirs.iter().map(|d| d.path.clone()).collect();
+    fassert_eq!(
+        paths,
+        vec![
+            root.join("before"),
+            root.join("i"),
+            root.join("std/32"),
+            root.join("after"),
+        ]
+    );
+}
+
+#[test]
+fn includes() {
+    let root = tree(
+        "includes",
+        &[
+            ("main.c", ""),
+            ("a.h", "a\n#include \"sub/inner.h\"\n"),
+            ("sub/inner.h", "inner\n#include \"sibling.h\"\n"),
+            ("sub/sibling.h", "sibling\n"),
+            ("i/b.h", "#define B b\n"),
+        ],
+    );
+    let params = Params {
+        include_dirs: vec![IncludeDir::I(dir(&root, "i"))],
+        disable_linemarkers: true,
+        ..Params::default()
+    };
+    let main = dir(&root, "main.c");
+    let mut pp = Preprocessor::new(&params);
+    pp.run(
+        "#include \"a.h\"\n\
+         #define Q \"a.h\"\n\
+         #define H <b.h>\n\
+         #include H\n\
+         B\n\
+         #include <sibling.h>\n\
+         #include Q\n",
+        &main,
+    );
+
+    let loc = |nline| Some(Location::new(main.clone(), nline, 1));
+    fassert_eq!(
+        pp.issues,
+        vec![Issue::new(
+            loc(6),
+            IssueType::Error,
+            IssueDesc::HeaderNotFound("sibling.h".to_string()),
+        )]
+    );
+    fassert_eq!(pp.output, "a\ninner\nsibling\nb\na\ninner\nsibling\n");
+
+    let resolution =
+        |h, includer: &str, found: Option<(&str, Option<usize>)>| Resolution {
+            itype: IncludeType::Include,
+            header: h,
+            includer: includer.to_string(),
+            found: found.map(|(p, dir)| Found {
+                path: PathBuf::from(p),
+                dir,
+            }),
+        };
+    let a = dir(&root, "a.h");
+    let inner = dir(&root, "sub/inner.h");
+    let sibling = dir(&root, "sub/sibling.h");
+    let b = dir(&root, "i/b.h");
+    let nested = vec![
+        resolution(quoted("a.h"), &main, Some((&a, None))),
+        resolution(quoted("sub/inner.h"), &a, Some((&inner, None))),
+        resolution(quoted("sibling.h"), &inner, Some((&sibling, None))),
+    ];
+    let mut expected = nested.clone();
+    expected.extend(vec![
+        resolution(bracketed("b.h"), &main, Some((&b, Some(0)))),
+        resolution(bracketed("sibling.h"), &main, None),
+    ]);
+    expected.extend(nested);
+    fassert_eq!(pp.resolutions, expected);
+}
+
+#[test]
+fn malformed_includes() {
+    let root = tree("malformed", &[("self.h", "#include \"self.h\"\n")]);
+    let main = dir(&root, "main.c");
+    let params = Params::default();
+    let mut pp = Preprocessor::new(&params);
+    pp.run(
+        "#define EMPTY\n\
+         #define E \"\"\n\
+         #include EMPTY\n\
+         #include E\n\
+         #include <>\n\
+         #include x\n\
+         #include \"self.h\" x\n",
+        &main,
+    );
+
+    let issue = |filename: &str, nline, nchar, itype, desc| {
+        Issue::new(
+            Some(Location::new(filename.to_string(), nline, nchar)),
+            itype,
+            desc,
+        )
+    };
+    let include = "include".to_string();
+    fassert_eq!(
+        pp.issues,
+        vec![
+            issue(
+                &main,
+                3,
+                1,
+                IssueType::Error,
+                IssueDesc::IncludeExpectsFilename(include.clone()),
+            ),
+            issue(
+                &main,
+                4,
+                1,
+                IssueType::Error,
+                IssueDesc::EmptyFilename(include.clone()),
+            ),
+            issue(
+                &main,
+                5,
+                1,
+                IssueType::Error,
+                IssueDesc::EmptyFilename(include.clone()),
+            ),
+            issue(
+                &main,
+                6,
+                1,
+                IssueType::Error,
+                IssueDesc::IncludeExpectsFilename(include.clone()),
+            ),
+            issue(
+                &main,
+                7,
+                19,
+                IssueType::Warning,
+                IssueDesc::ExtraTokensAfterDirective(include),
+            ),
+            issue(
+                &dir(&root, "self.h"),
+                1,
+                1,
+                IssueType::Error,
+                IssueDesc::IncludeNestedTooDeeply(200),
+            ),
+        ]
+    );
+}
+
+// Wrapper headers, each including the next one of the same name.
+#[test]
+fn include_next() {
+    let root = tree(
+        "include_next",
+        &[
+            ("main/stdio.h", "main\n#include_next <stdio.h>\n"),
+            ("q/stdio.h", "q\n#include_next \"stdio.h\"\n"),
+            ("i/stdio.h", "i\n#include_next <stdio.h>\n"),
+            ("i/x.h", "#include_next <x.h>\n"),
+            ("s/stdio.h", "s\n"),
+        ],
+    );
+    let params = Params {
+        include_dirs: vec![
+            IncludeDir::IQuote(dir(&root, "q")),
+            IncludeDir::I(dir(&root, "i")),
+            IncludeDir::ISystem(dir(&root, "s")),
+        ],
+        pedantic: true,
+        disable_linemarkers: true,
+        ..Params::default()
+    };
+    let main = dir(&root, "main/main.c");
+    let mut pp = Preprocessor::new(&params);
+    pp.run(
+        "#include \"stdio.h\"\n\
+         #include <stdio.h>\n\
+         #include_next <x.h>\n",
+        &main,
+    );
+
+    let loc = |filename: &str, nline| {
+        Some(Location::new(filename.to_string(), nline, 1))
+    };
+    let extension = || IssueDesc::GnuExtension("#include_next".to_string());
+    let x = dir(&root, "i/x.h");
+    fassert_eq!(
+        pp.issues,
+        vec![
+            Issue::new(
+                loc(&dir(&root, "main/stdio.h"), 2),
+                IssueType::Warning,
+                extension(),
+            ),
+            Issue::new(
+                loc(&dir(&root, "q/stdio.h"), 2),
+                IssueType::Warning,
+                extension(),
+            ),
+            Issue::new(
+                loc(&dir(&root, "i/stdio.h"), 2),
+                IssueType::Warning,
+                extension(),
+            ),
+            Issue::new(
+                loc(&dir(&root, "i/stdio.h"), 2),
+                IssueType::Warning,
+                extension(),
+            ),
+            Issue::new(loc(&main, 3), IssueType::Warning, extension()),
+            Issue::new(
+                loc(&main, 3),
+                IssueType::Warning,
+                IssueDesc::IncludeNextInPrimaryFile,
+            ),
+            Issue::new(loc(&x, 1), IssueType::Warning, extension()),
+            Issue::new(
+                loc(&x, 1),
+                IssueType::Error,
+                IssueDesc::HeaderNotFound("x.h".to_string()),
+            ),
+        ]
+    );
+    // The header in the main file's directory goes on to the quote chain.
+    fassert_eq!(pp.output, "main\nq\ni\ns\ni\ns\n");
+}
+
+#[test]
+fn import() {
+    let root = tree(
+        "import",
+        &[
+            ("a.h", "a\n"),
+            ("sub/b.h", "b\n#import \"../a.h\"\n"),
+            ("c.h", "c\n"),
+        ],
+    );
+    let main = dir(&root, "main.c");
+    let src = "#include \"a.h\"\n\
+               #import \"a.h\"\n\
+               #import \"c.h\"\n\
+               #include \"c.h\"\n\
+               #import \"sub/b.h\"\n\
+               #include \"sub/../sub/b.h\"\n";
+    let params = Params {
+        disable_linemarkers: true,
+        ..Params::default()
+    };
+    let mut pp = Preprocessor::new(&params);
+    pp.run(src, &main);
+    let warning = |filename: &str, nline| {
+        Issue::new(
+            Some(Location::new(filename.to_string(), nline, 1)),
+            IssueType::Warning,
+            IssueDesc::DeprecatedGnuExtension("#import".to_string()),
+        )
+    };
+    fassert_eq!(
+        pp.issues,
+        vec![
+            warning(&main, 2),
+            warning(&main, 3),
+            warning(&main, 5),
+            warning(&dir(&root, "sub/b.h"), 2),
+        ]
+    );
+    fassert_eq!(pp.output, "a\nc\nb\n");
+
+    let params = Params {
+        wdeprecated: false,
+        disable_linemarkers: true,
+        ..Params::default()
+    };
+    let mut pp = Preprocessor::new(&params);
+    pp.run(src, &main);
+    fassert_eq!(pp.issues, vec![]);
+    fassert_eq!(pp.output, "a\nc\nb\n");
+}
+
+// Files with '#pragma once' or an include guard aren't reread, however
+// they're named.
+#[cfg(unix)]
+#[test]
+fn once_only() {
+    let root = tree(
+        "once_only",
+        &[
+            ("guard.h", "#ifndef G\n#define G\nguard\n#endif\n"),
+            ("not_guard.h", "#ifndef N\n#define N\nn\n#endif\nafter\n"),
+            (
+                "else_guard.h",
+                "#ifndef E\n#define E\ne\n#else\nelse\n#endif\n",
+            ),
+            ("once.h", "#pragma once\nonce\n"),
+        ],
+    );
+    std::os::unix::fs::symlink(root.join("once.h"), root.join("sym.h"))
+        .unwrap();
+    fs::hard_link(root.join("once.h"), root.join("hard.h")).unwrap();
+
+    let main = dir(&root, "main.c");
+    let params = Params {
+        disable_linemarkers: true,
+        ..Params::default()
+    };
+    let mut pp = Preprocessor::new(&params);
+    pp.run(
+        "#pragma once\n\
+         #include \"guard.h\"\n\
+         #include \"guard.h\"\n\
+         #undef G\n\
+         #include \"guard.h\"\n\
+         #include \"once.h\"\n\
+         #include \"sym.h\"\n\
+         #include \"hard.h\"\n\
+         #include \"not_guard.h\"\n\
+         #include \"not_guard.h\"\n\
+         #include \"else_guard.h\"\n\
+         #include \"else_guard.h\"\n",
+        &main,
+    );
+    fassert_eq!(
+        pp.issues,
+        vec![Issue::new(
+            Some(Location::new(main.clone(), 1, 1)),
+            IssueType::Warning,
+            IssueDesc::PragmaOnceInMainFile,
+        )]
+    );
+    fassert_eq!(pp.output, "guard\nguard\nonce\nn\nafter\nafter\ne\nelse\n");
+    fassert_eq!(pp.skipped_rereads, 3);
+}
+
+#[test]
+fn linemarkers() {
+    let root = tree(
+        "linemarkers",
+        &[
+            ("user.h", "\nuser\n"),
+            ("s/sys.h", "#include \"nested.h\"\nsys\n"),
+            ("s/nested.h", "nested\n"),
+        ],
+    );
+    let params = Params {
+        include_dirs: vec![IncludeDir::ISystem(dir(&root, "s"))],
+        ..Params::default()
+    };
+    let main = dir(&root, "main.c");
+    let mut pp = Preprocessor::new(&params);
+    pp.run(
+        "#define f(x) x\n\
+         a\n\
+         #include \"user.h\"\n\
+         \n\
+         b\n\
+         #include <sys.h>\n\
+         f(c\n\
+         )\n\
+         d\n\
+         \n\n\n\n\n\n\n\n\n\
+         e\n\
+         #line 100 \"x\\\\\\\"y.c\"\n\
+         g\n",
+        &main,
+    );
+    fassert_eq!(pp.issues, vec![]);
+    let user = dir(&root, "user.h");
+    let sys = dir(&root, "s/sys.h");
+    let nested = dir(&root, "s/nested.h");
+    fassert_eq!(
+        pp.output,
+        format!(
+            "# 1 \"{main}\"\n\
+             \n\
+             a\n\
+             # 1 \"{user}\" 1\n\
+             \n\
+             user\n\
+             # 4 \"{main}\" 2\n\
+             \n\
+             b\n\
+             # 1 \"{sys}\" 1 3 4\n\
+             # 1 \"{nested}\" 1 3 4\n\
+             nested\n\
+             # 2 \"{sys}\" 2 3 4\n\
+             sys\n\
+             # 7 \"{main}\" 2\n\
+             c\n\
+             \n\
+             d\n\
+             # 19 \"{main}\"\n\
+             e\n\
+             # 100 \"x\\\\\\\"y.c\"\n\
+             g\n",
+            main = main,
+            user = user,
+            sys = sys,
+            nested = nested,
+        )
+    );
+}
+
+// '-include' and '-imacros' files aren't looked for in the main file's
+// directory, and only the macros of the '-imacros' ones are kept.
+#[test]
+fn command_line_includes() {
+    let root = tree(
+        "command_line_includes",
+        &[
+            ("main/config.h", "#define WRONG\n"),
+            ("q/config.h", "#define CONFIG 1\nconfig\n"),
+            ("q/macros.h", "#define MACROS 2\nmacros\n"),
+        ],
+    );
+    let params = Params {
+        include_dirs: vec![IncludeDir::IQuote(dir(&root, "q"))],
+        macro_defines: vec![MacroDefine::D("D".to_string())],
+        include: vec!["config.h".to_string(), "missing.h".to_string()],
+        imacros: vec!["macros.h".to_string()],
+        ..Params::default()
+    };
+    let main = dir(&root, "main/main.c");
+    let mut pp = Preprocessor::new(&params);
+    pp.run("CONFIG MACROS WRONG __INCLUDE_LEVEL__\n", &main);
+    fassert_eq!(
+        pp.issues,
+        vec![Issue::new(
+            Some(Location::new("<command-line>".to_string(), 4, 1)),
+            IssueType::Error,
+            IssueDesc::HeaderNotFound("missing.h".to_string()),
+        )]
+    );
+    fassert_eq!(
+        pp.resolutions
+            .iter()
+            .map(|r| &r.includer)
+            .collect::<Vec<_>>(),
+        vec!["<command-line>"; 3]
+    );
+    fassert_eq!(
+        pp.output,
+        format!(
+            "# 1 \"{main}\"\n\
+             # 1 \"{config}\" 1\n\
+             \n\
+             config\n\
+             # 4 \"<command-line>\" 2\n\
+             # 1 \"{main}\"\n\
+             1 2 WRONG 0\n",
+            main = main,
+            config = dir(&root, "q/config.h"),
+        )
+    );
+}
+
+// Warnings, but not errors, are dropped in system headers, and in the rest
+// of a file after '#pragma GCC system_header'.
+#[test]
+fn system_headers() {
+    let root = tree(
+        "system_headers",
+        &[
+            (
+                "user.h",
+                "#import \"sys.h\"\n\
+                 #pragma GCC system_header\n\
+                 #import \"sys.h\"\n\
+                 user\n",
+            ),
+            ("s/sys.h", "#import \"other.h\"\n#include \"missing.h\"\n"),
+            ("s/other.h", ""),
+        ],
+    );
+    let params = Params {
+        include_dirs: vec![IncludeDir::IDirAfter(dir(&root, "s"))],
+        ..Params::default()
+    };
+    let main = dir(&root, "main.c");
+    let mut pp = Preprocessor::new(&params);
+    pp.run("#include \"user.h\"\n#pragma GCC system_header\n", &main);
+    let user = dir(&root, "user.h");
+    let sys = dir(&root, "s/sys.h");
+    fassert_eq!(
+        pp.issues,
+        vec![
+            Issue::new(
+                Some(Location::new(user.clone(), 1, 1)),
+                IssueType::Warning,
+                IssueDesc::DeprecatedGnuExtension("#import".to_string()),
+            ),
+            Issue::new(
+                Some(Location::new(sys.clone(), 2, 1)),
+                IssueType::Error,
+                IssueDesc::HeaderNotFound("missing.h".to_string()),
+            ),
+            Issue::new(
+                Some(Location::new(main.clone(), 2, 1)),
+                IssueType::Warning,
+                IssueDesc::SystemHeaderPragmaInMainFile,
+            ),
+        ]
+    );
+    fassert_eq!(
+        pp.output,
+        format!(
+            "# 1 \"{main}\"\n\
+             # 1 \"{user}\" 1\n\
+             # 1 \"{sys}\" 1 3 4\n\
+             # 1 \"{other}\" 1 3 4\n\
+             # 2 \"{sys}\" 2 3 4\n\
+             # 2 \"{user}\" 2\n\
+             # 3 \"{user}\" 3\n\
+             \n\
+             user\n\
+             # 2 \"{main}\" 2\n",
+            main = main,
+            user = user,
+            sys = sys,
+            other = dir(&root, "s/other.h"),
+        )
+    );
+}
+
+#[test]
+fn dependency() {
+    let root = tree(
+        "dependency",
+        &[("main.c", ""), ("old.h", ""), ("new.h", "")],
+    );
+    let set_modified = |file: &str, secs: u64| {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        fs::File::options()
+            .write(true)
+            .open(root.join(file))
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    };
+    set_modified("old.h", 1);
+    set_modified("main.c", 2);
+    set_modified("new.h", 3);
+    let main = dir(&root, "main.c");
+    let params = Params::default();
+    let mut pp = Preprocessor::new(&params);
+    pp.run(
+        "#pragma GCC dependency \"old.h\"\n\
+         #pragma GCC dependency \"new.h\" rebuild  it\n\
+         #pragma GCC dependency \"missing.h\"\n",
+        &main,
+    );
+    fassert_eq!(
+        pp.issues,
+        vec![
+            Issue::new(
+                Some(Location::new(main.clone(), 2, 1)),
+                IssueType::Warning,
+                IssueDesc::CurrentFileOlderThan(
+                    dir(&root, "new.h"),
+                    "rebuild it".to_string()
+                ),
+            ),
+            Issue::new(
+                Some(Location::new(main.clone(), 3, 1)),
+                IssueType::Warning,
+                IssueDesc::HeaderNotFound("missing.h".to_string()),
+            ),
+        ]
+    );
+}